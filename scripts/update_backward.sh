@@ -3,9 +3,7 @@
 CURRENT=${1:-$(date +'%Y-%m-%d' -d "1 day ago")}
 FINISH=${2:-$(date +'%Y-%m-%d' -d "4 month ago")}
 if [ "$CURRENT" != "$TODAY" ]; then
-    while [ "$CURRENT" != "$FINISH" ]; do
-      echo $CURRENT
-      ZKBINFO_HOST=zkbinfo ~/zkbinfo/bin/fetch_by_date $CURRENT
-      CURRENT=$(date -I -d "$CURRENT - 1 day")
-    done
+    if [ "$CURRENT" != "$FINISH" ]; then
+      ZKBINFO_HOST=zkbinfo ~/zkbinfo/bin/fetch_by_date $CURRENT $(date -I -d "$FINISH + 1 day")
+    fi
 fi
\ No newline at end of file
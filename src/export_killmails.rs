@@ -0,0 +1,40 @@
+use log::info;
+
+use std::env;
+use std::io::{self, BufWriter};
+
+use lib::database;
+use lib::export::{self, ExportRequest};
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let args = env::args().collect::<Vec<String>>();
+    if args.len() < 2 {
+        usage(&args[0]);
+        return Ok(());
+    }
+    // The same parameters as the query of /api/export
+    let request = match serde_urlencoded::from_str::<ExportRequest>(&args[1..].join("&")) {
+        Ok(request) => request,
+        Err(what) => {
+            eprintln!("{what}");
+            usage(&args[0]);
+            return Ok(());
+        }
+    };
+
+    let url = "killmail.db";
+    info!("The Database path: {url}, {request:?}");
+    let pool = database::create_pool(url)?;
+    let conn = pool.get()?;
+    let rows = export::run(&conn, &request, BufWriter::new(io::stdout()))?;
+    info!("Exported {rows} rows");
+    Ok(())
+}
+
+fn usage(app: &str) {
+    println!(
+        "Usage:\n\t{app} format=csv|ndjson|parquet [dataset=killmails|activity|relations] [from=YYYY-MM-DD] [to=YYYY-MM-DD] [subject=character|corporation|alliance] [id=<id>] > <file>"
+    );
+}
@@ -1,12 +1,77 @@
 use chrono::NaiveDate;
-use env_logger;
 use log::{error, info, warn};
 use tokio::time::Duration;
 
-use std::collections::HashMap;
 use std::env;
+use std::io::{self, IsTerminal, Write};
 
-use lib::evetech;
+use lib::api::keys;
+use lib::backfill::{self, Checkpoint, Summary};
+
+const DEFAULT_CHECKPOINT: &str = "fetch_by_date.checkpoint";
+
+/// Saves the missing killmails of the date to zkbinfo.
+async fn fetch_date(
+    client: &reqwest::Client,
+    api: &str,
+    date: &NaiveDate,
+    concurrency: usize,
+) -> anyhow::Result<Summary> {
+    let zkbinfo_get_saved_api = format!("{api}/api/killmail/ids/{}/", date.format("%Y-%m-%d"));
+    info!("zkbinfo API GET_SAVED: {zkbinfo_get_saved_api}");
+    let saved = client
+        .get(&zkbinfo_get_saved_api)
+        .send()
+        .await?
+        .json::<Vec<i32>>()
+        .await?;
+    info!("Received {} killmails from zkbinfo", saved.len());
+
+    let zkbinfo_save_api = format!("{api}/killmail/save");
+    let terminal = io::stderr().is_terminal();
+    let summary = backfill::reconcile(
+        date,
+        &saved,
+        concurrency,
+        |killmail| {
+            let client = client.clone();
+            let api = zkbinfo_save_api.clone();
+            async move {
+                let mut timeout = 10;
+                loop {
+                    match client.post(&api).json(&killmail).send().await {
+                        Ok(response) => {
+                            // A refused killmail is failed, not saved
+                            response.error_for_status()?;
+                            return Ok(true);
+                        }
+                        Err(what) => {
+                            error!("{what}");
+                            warn!("Will wait zkbinfo for {timeout} seconds");
+                            tokio::time::sleep(Duration::from_secs(timeout)).await;
+                            if timeout < 300 {
+                                timeout += 30;
+                            }
+                        }
+                    }
+                }
+            }
+        },
+        |n, total| {
+            if terminal {
+                eprint!("\r{date}: {n} of {total}");
+                let _ = io::stderr().flush();
+            } else if n % 100 == 0 || n == total {
+                info!("{date}: {n} of {total}");
+            }
+        },
+    )
+    .await?;
+    if terminal && summary.total > 0 {
+        eprintln!();
+    }
+    Ok(summary)
+}
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
@@ -15,77 +80,65 @@ async fn main() -> anyhow::Result<()> {
         .unwrap_or_default()
         .parse::<u16>()
         .unwrap_or(8080);
+    let concurrency = env::var("ZKBINFO_ESI_CONCURRENCY")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap_or(backfill::DEFAULT_CONCURRENCY);
+    let checkpoint_path =
+        env::var("ZKBINFO_CHECKPOINT").unwrap_or(String::from(DEFAULT_CHECKPOINT));
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
     let args = env::args().collect::<Vec<String>>();
-    if args.len() != 1 {
-        let client = reqwest::Client::new();
-        let zkbinfo_save_api = format!("http://{host}:{port}/killmail/save");
-        info!("zkbinfo API SAVE url: {zkbinfo_save_api}");
-
-        if let Ok(date) = NaiveDate::parse_from_str(args[1].as_str(), "%Y-%m-%d") {
-            let zkbinfo_get_saved_api = format!(
-                "http://{host}:{port}/api/killmail/ids/{}/",
-                date.format("%Y-%m-%d").to_string()
-            );
-            info!("zkbinfo API GET_SAVED: {zkbinfo_get_saved_api}");
-
-            let zkb_api = format!(
-                "https://zkillboard.com/api/history/{}.json",
-                date.format("%Y%m%d").to_string()
-            );
-            info!("zkillboard.com API: {zkb_api}");
-
-            let mut map = reqwest::get(&zkb_api)
-                .await?
-                .json::<HashMap<i32, String>>()
-                .await?;
-            info!("Received {} killmails from zkillboard.com", map.len());
-
-            let saved = reqwest::get(&zkbinfo_get_saved_api)
-                .await?
-                .json::<Vec<i32>>()
-                .await?;
-            info!("Received {} killmails from zkbinfo", saved.len());
-
-            for id in saved {
-                map.remove(&id);
-            }
-            info!("The rest of killmails to receive {}", map.len());
+    let parse = |arg: &String| NaiveDate::parse_from_str(arg, "%Y-%m-%d").ok();
+    let (first, last) = match (args.get(1).and_then(parse), args.get(2).map(parse)) {
+        (Some(first), None) if args.len() == 2 => (first, first),
+        (Some(first), Some(Some(last))) if args.len() == 3 => (first, last),
+        _ => {
+            usage(&args[0]);
+            return Ok(());
+        }
+    };
 
-            for (id, hash) in map {
-                let evetech_api = format!(
-                    "https://esi.evetech.net/latest/killmails/{id}/{hash}/?datasource=tranquility"
-                );
-                info!("EVETECH API: {evetech_api}");
+    let client = keys::http_client()?;
+    let api = format!("http://{host}:{port}");
+    info!("zkbinfo API url: {api}, {concurrency} ESI fetches at once");
 
-                let mut timeout = 10;
-                loop {
-                    let response = reqwest::get(&evetech_api).await?;
-                    if let Ok(killmail) = response.json::<evetech::Killmail>().await {
-                        while let Err(what) =
-                            client.post(&zkbinfo_save_api).json(&killmail).send().await
-                        {
-                            error!("{what}");
-                            tokio::time::sleep(Duration::from_secs(timeout)).await;
-                            warn!("Will wait zkbinfo for {timeout} seconds");
-                        }
-                        break;
-                    } else {
-                        if timeout < 300 {
-                            timeout += 30;
-                        }
-                        tokio::time::sleep(Duration::from_secs(timeout)).await;
-                        warn!("Will wait evetech for {timeout} seconds");
-                    }
+    let mut checkpoint = Checkpoint::open(checkpoint_path.into(), first, last)?;
+    let mut total = Summary::default();
+    let mut complete = true;
+    for date in backfill::dates(first, last) {
+        if checkpoint.is_done(&date) {
+            info!("{date}: done by a previous run");
+            continue;
+        }
+        match fetch_date(&client, &api, &date, concurrency).await {
+            Ok(summary) => {
+                info!("{date}: {summary}");
+                if summary.failed.is_empty() {
+                    checkpoint.mark(date)?;
+                } else {
+                    complete = false;
                 }
+                total.add(summary);
+            }
+            Err(what) => {
+                error!("{date}: {what}");
+                complete = false;
             }
-            return Ok(());
         }
     }
-    Ok(usage(&args[0]))
+    info!("{first} - {last}: {total}");
+    if !total.failed.is_empty() {
+        warn!("Failed killmails: {:?}", total.failed);
+    }
+    if complete {
+        checkpoint.remove()?;
+    } else {
+        warn!("Run it again to retry the incomplete dates");
+    }
+    Ok(())
 }
 
-fn usage(app: &String) -> () {
-    println!("Usage:\n\t{app} <YYYY-MM-DD>");
+fn usage(app: &str) {
+    println!("Usage:\n\t{app} <YYYY-MM-DD> [<YYYY-MM-DD>]");
 }
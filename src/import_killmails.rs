@@ -0,0 +1,33 @@
+use log::info;
+
+use std::env;
+use std::path::Path;
+
+use lib::database;
+use lib::import;
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let args = env::args().collect::<Vec<String>>();
+    if args.len() < 2 {
+        println!(
+            "Usage:\n\t{} <tar.gz|tar.bz2|ndjson|json|directory>...",
+            args[0]
+        );
+        return Ok(());
+    }
+    let batch = env::var("ZKBINFO_IMPORT_BATCH")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap_or(import::DEFAULT_BATCH);
+
+    let url = "killmail.db";
+    info!("The Database path: {url}, batches of {batch} killmails");
+    let pool = database::create_pool(url)?;
+    let conn = pool.get()?;
+    let paths = args[1..].iter().map(Path::new).collect::<Vec<&Path>>();
+    let stats = import::run(&conn, &paths, batch)?;
+    info!("Import complete: {stats}");
+    Ok(())
+}
@@ -0,0 +1,632 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use super::{keys, Context, Status};
+use crate::archive::{self, RetentionPolicy};
+use crate::backfill::{self, GapMonitor};
+use crate::backup::{self, BackupPolicy};
+use crate::database;
+use crate::killstream::Filter;
+use crate::replication;
+
+pub const DEFAULT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60 * 48);
+const SCHEDULER_TICK: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Cleanup,
+    Vacuum,
+    Analyze,
+    Backfill,
+    Backup,
+    Sync,
+    Refetch,
+}
+impl JobKind {
+    /// Backfill, sync and refetch work on a single date.
+    fn dated(&self) -> bool {
+        matches!(self, JobKind::Backfill | JobKind::Sync | JobKind::Refetch)
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct JobStatus {
+    running: bool,
+    runs: usize,
+    progress: Option<String>,
+    started: Option<String>,
+    finished: Option<String>,
+    last_result: Option<String>,
+}
+
+/// Maintenance jobs state, their schedule, the retention and backup policies.
+pub struct Jobs {
+    status: HashMap<JobKind, JobStatus>,
+    intervals: HashMap<JobKind, Duration>,
+    last_runs: HashMap<JobKind, Instant>,
+    retention: RetentionPolicy,
+    backup: BackupPolicy,
+    peer: Option<String>,
+    gaps: GapMonitor,
+    filter: Filter,
+}
+impl Default for Jobs {
+    fn default() -> Self {
+        Self::new(Some(DEFAULT_CLEANUP_INTERVAL))
+    }
+}
+impl Jobs {
+    /// No interval disables the scheduled cleanup.
+    pub fn new(cleanup_interval: Option<Duration>) -> Self {
+        let mut jobs = Self {
+            status: HashMap::new(),
+            intervals: HashMap::new(),
+            last_runs: HashMap::new(),
+            retention: RetentionPolicy::default(),
+            backup: BackupPolicy::default(),
+            peer: None,
+            gaps: GapMonitor::new(None, None),
+            filter: Filter::default(),
+        };
+        jobs.set_interval(JobKind::Cleanup, cleanup_interval);
+        jobs
+    }
+
+    /// No interval disables the scheduled runs of the job.
+    pub fn set_interval(&mut self, kind: JobKind, interval: Option<Duration>) {
+        match interval {
+            Some(interval) => self.intervals.insert(kind, interval),
+            None => self.intervals.remove(&kind),
+        };
+    }
+
+    fn interval_hours(&self, kind: JobKind) -> Option<u64> {
+        self.intervals.get(&kind).map(|i| i.as_secs() / 3600)
+    }
+
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+    }
+
+    pub fn retention(&self) -> &RetentionPolicy {
+        &self.retention
+    }
+
+    pub fn set_backup(&mut self, backup: BackupPolicy) {
+        self.backup = backup;
+    }
+
+    pub fn backup(&self) -> &BackupPolicy {
+        &self.backup
+    }
+
+    /// Base url of the zkbinfo instance the sync job replicates from.
+    pub fn set_peer(&mut self, peer: Option<String>) {
+        self.peer = peer.map(|peer| peer.trim_end_matches('/').to_string());
+    }
+
+    pub fn set_gap_monitor(&mut self, gaps: GapMonitor) {
+        self.gaps = gaps;
+    }
+
+    /// The killmails a backfill saves, the same the live stream keeps.
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// A live killmail arrived, returns the dates queued for backfill.
+    pub fn arrival(&mut self) -> Vec<NaiveDate> {
+        self.gaps.arrival(Utc::now())
+    }
+
+    pub fn last_arrival(&self) -> Option<DateTime<Utc>> {
+        self.gaps.last_arrival()
+    }
+
+    fn is_running(&self, kind: JobKind) -> bool {
+        self.status.get(&kind).map(|s| s.running).unwrap_or(false)
+    }
+
+    /// The next queued date to backfill, unless a backfill is running.
+    fn next_backfill(&self) -> Option<NaiveDate> {
+        if self.is_running(JobKind::Backfill) {
+            return None;
+        }
+        self.gaps.next(Utc::now().date_naive())
+    }
+
+    fn due(&self) -> Vec<JobKind> {
+        self.intervals
+            .iter()
+            .filter(|(kind, interval)| match self.last_runs.get(kind) {
+                Some(last) => last.elapsed() >= **interval,
+                None => true,
+            })
+            .map(|(kind, _)| *kind)
+            .collect()
+    }
+
+    /// Returns false when the job is already running.
+    fn start(&mut self, kind: JobKind) -> bool {
+        let status = self.status.entry(kind).or_default();
+        if status.running {
+            return false;
+        }
+        status.running = true;
+        status.runs += 1;
+        status.progress = None;
+        status.started = Some(Utc::now().to_rfc3339());
+        self.last_runs.insert(kind, Instant::now());
+        true
+    }
+
+    fn progress(&mut self, kind: JobKind, progress: String) {
+        self.status.entry(kind).or_default().progress = Some(progress);
+    }
+
+    fn finish(&mut self, kind: JobKind, result: anyhow::Result<String>) {
+        let status = self.status.entry(kind).or_default();
+        status.running = false;
+        status.finished = Some(Utc::now().to_rfc3339());
+        status.last_result = Some(match result {
+            Ok(message) => message,
+            Err(what) => format!("Failed: {what}"),
+        });
+    }
+}
+
+fn progress(ctx: &Context, kind: JobKind, message: String) {
+    if let Ok(mut jobs) = ctx.jobs.lock() {
+        jobs.progress(kind, message);
+    }
+}
+
+fn database_job(ctx: &Context, kind: JobKind) -> anyhow::Result<String> {
+    let conn = ctx.get_pool().get()?;
+    match kind {
+        JobKind::Cleanup => {
+            let policy = ctx
+                .jobs
+                .lock()
+                .map(|jobs| jobs.retention.clone())
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            return archive::archive_and_cleanup(&conn, &policy);
+        }
+        JobKind::Backup => {
+            let policy = ctx
+                .jobs
+                .lock()
+                .map(|jobs| jobs.backup.clone())
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            return backup::run(&conn, &policy);
+        }
+        JobKind::Vacuum => database::vacuum(&conn)?,
+        JobKind::Analyze => database::analyze(&conn)?,
+        JobKind::Backfill | JobKind::Sync | JobKind::Refetch => {
+            return Err(anyhow::anyhow!("{kind:?} requires a date"))
+        }
+    }
+    Ok(String::from("Success"))
+}
+
+async fn backfill_job(ctx: &Context, date: NaiveDate) -> anyhow::Result<String> {
+    let filter = ctx
+        .jobs
+        .lock()
+        .map_err(|e| anyhow::anyhow!("{e}"))?
+        .filter
+        .clone();
+    let saved = {
+        let conn = ctx.get_pool().get()?;
+        database::select_ids_by_date(&conn, &date)?
+    };
+    let summary = backfill::reconcile(
+        &date,
+        &saved,
+        backfill::DEFAULT_CONCURRENCY,
+        |killmail| {
+            let keep = filter.matches(&killmail);
+            let db = ctx.clone();
+            async move {
+                if keep {
+                    web::block(move || db.store(killmail))
+                        .await
+                        .map_err(|e| anyhow::anyhow!(e))
+                        .and_then(|result| result)?;
+                }
+                Ok(keep)
+            }
+        },
+        |n, total| progress(ctx, JobKind::Backfill, format!("{date}: {n} of {total}")),
+    )
+    .await?;
+    let message = format!("{date}: {summary}");
+    if !summary.failed.is_empty() {
+        return Err(anyhow::anyhow!(message));
+    }
+    Ok(message)
+}
+
+async fn refetch_job(ctx: &Context, date: NaiveDate) -> anyhow::Result<String> {
+    let (hashes, saved) = {
+        let conn = ctx.get_pool().get()?;
+        (
+            database::select_hashes_by_date(&conn, &date)?,
+            database::select_ids_by_date(&conn, &date)?,
+        )
+    };
+    let hashes = backfill::hashes(&date, hashes, &saved).await?;
+    let mut summary = backfill::refetch(
+        hashes,
+        backfill::DEFAULT_CONCURRENCY,
+        |killmail| {
+            let db = ctx.clone();
+            async move {
+                web::block(move || db.replace(killmail))
+                    .await
+                    .map_err(|e| anyhow::anyhow!(e))
+                    .and_then(|result| result)
+            }
+        },
+        |n, total| progress(ctx, JobKind::Refetch, format!("{date}: {n} of {total}")),
+    )
+    .await;
+    summary.skipped = saved.len().saturating_sub(summary.total);
+    Ok(format!(
+        "{date}: {} of {} killmails refetched, {} failed, {} without a hash",
+        summary.saved,
+        summary.total,
+        summary.failed.len(),
+        summary.skipped
+    ))
+}
+
+async fn sync_job(ctx: &Context, date: NaiveDate) -> anyhow::Result<String> {
+    let peer = ctx
+        .jobs
+        .lock()
+        .map_err(|e| anyhow::anyhow!("{e}"))?
+        .peer
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("No peer configured"))?;
+    let client = keys::http_client()?;
+    let peer_ids = replication::peer_ids(&client, &peer, &date).await?;
+    let saved = {
+        let conn = ctx.get_pool().get()?;
+        database::select_ids_by_date(&conn, &date)?
+    };
+    let missing = replication::missing(peer_ids, &saved);
+    let total = missing.len();
+    let mut failed = 0;
+    for (n, chunk) in missing.chunks(replication::EXPORT_LIMIT).enumerate() {
+        let done = n * replication::EXPORT_LIMIT;
+        progress(ctx, JobKind::Sync, format!("{date}: {done} of {total}"));
+        let mut killmails = replication::export(&client, &peer, chunk).await?;
+        // Only the requested ones, each once
+        let mut wanted = chunk.iter().copied().collect::<HashSet<i32>>();
+        killmails.retain(|killmail| wanted.remove(&killmail.killmail_id));
+        failed += wanted.len();
+        let db = ctx.clone();
+        failed += web::block(move || {
+            let mut failed = 0;
+            for killmail in killmails {
+                let id = killmail.killmail_id;
+                if let Err(what) = db.store(killmail) {
+                    warn!("Sync of killmail {id} failed: {what}");
+                    failed += 1;
+                }
+            }
+            failed
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+    }
+    Ok(format!(
+        "{date}: {} of {total} missing killmails synced from {peer}, {failed} failed",
+        total.saturating_sub(failed)
+    ))
+}
+
+/// Starts the job in the background, returns false if it is already running.
+pub fn spawn(ctx: Context, kind: JobKind, date: Option<NaiveDate>) -> bool {
+    let started = match ctx.jobs.lock() {
+        Ok(mut jobs) => jobs.start(kind),
+        Err(_) => false,
+    };
+    if !started {
+        return false;
+    }
+    info!("{kind:?} job started");
+    actix_rt::spawn(async move {
+        let result = match (kind, date) {
+            (JobKind::Backfill, Some(date)) => backfill_job(&ctx, date).await,
+            (JobKind::Sync, Some(date)) => sync_job(&ctx, date).await,
+            (JobKind::Refetch, Some(date)) => refetch_job(&ctx, date).await,
+            _ => {
+                let db = ctx.clone();
+                web::block(move || database_job(&db, kind))
+                    .await
+                    .map_err(|e| anyhow::anyhow!(e))
+                    .and_then(|result| result)
+            }
+        };
+        match &result {
+            Ok(message) => info!("{kind:?} job finished: {message}"),
+            Err(what) => error!("{kind:?} job failed: {what}"),
+        }
+        if let Ok(mut jobs) = ctx.jobs.lock() {
+            if let (JobKind::Backfill, Some(date), Ok(_)) = (kind, date, &result) {
+                jobs.gaps.backfilled(&date);
+            }
+            jobs.finish(kind, result);
+        }
+    });
+    true
+}
+
+/// Runs the scheduled jobs whenever their interval has elapsed.
+pub async fn scheduler(ctx: Context) {
+    let mut interval = actix_rt::time::interval(SCHEDULER_TICK);
+    loop {
+        interval.tick().await;
+        let due = ctx.jobs.lock().map(|jobs| jobs.due()).unwrap_or_default();
+        for kind in due {
+            spawn(ctx.clone(), kind, None);
+        }
+        let gap = ctx.jobs.lock().ok().and_then(|jobs| jobs.next_backfill());
+        if let Some(date) = gap {
+            info!("Backfill of {date} after a stream outage");
+            spawn(ctx.clone(), JobKind::Backfill, Some(date));
+        }
+    }
+}
+
+/// Zero hours means no interval.
+pub fn hours(hours: u64) -> Option<Duration> {
+    Some(Duration::from_secs(hours * 3600)).filter(|_| hours > 0)
+}
+
+/******************************************************************************/
+#[derive(Debug, Serialize)]
+struct JobsReport {
+    cleanup_interval_hours: Option<u64>,
+    backup_interval_hours: Option<u64>,
+    last_arrival: Option<String>,
+    backfill_queue: Vec<String>,
+    jobs: HashMap<JobKind, JobStatus>,
+}
+
+pub async fn jobs(ctx: Context) -> impl Responder {
+    let json = match ctx.jobs.lock() {
+        Ok(jobs) => serde_json::to_string(&JobsReport {
+            cleanup_interval_hours: jobs.interval_hours(JobKind::Cleanup),
+            backup_interval_hours: jobs.interval_hours(JobKind::Backup),
+            last_arrival: jobs.last_arrival().map(|time| time.to_rfc3339()),
+            backfill_queue: jobs
+                .gaps
+                .pending()
+                .iter()
+                .map(|date| date.to_string())
+                .collect(),
+            jobs: jobs.status.clone(),
+        })
+        .unwrap(),
+        Err(what) => Status::json(format!("{what}")),
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(json)
+}
+
+fn started(ok: bool) -> Status {
+    if ok {
+        Status::from("Started")
+    } else {
+        Status::from("Already running")
+    }
+}
+
+pub async fn run(ctx: Context, kind: web::Path<JobKind>) -> impl Responder {
+    match kind.into_inner() {
+        kind if kind.dated() => Status::from(format!("{kind:?} requires a date")),
+        kind => started(spawn(ctx, kind, None)),
+    }
+}
+
+pub async fn run_dated(ctx: Context, param: web::Path<(JobKind, String)>) -> impl Responder {
+    let (kind, date) = param.into_inner();
+    if !kind.dated() {
+        return Status::from(format!("{kind:?} does not take a date"));
+    }
+    match NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+        Ok(date) => started(spawn(ctx, kind, Some(date))),
+        Err(what) => Status::from(format!("Can't parse date '{date}' due to '{what}'")),
+    }
+}
+
+/// Zero disables the scheduled job, a missing field keeps its schedule
+#[derive(Debug, Deserialize)]
+pub struct Schedule {
+    cleanup_interval_hours: Option<u64>,
+    backup_interval_hours: Option<u64>,
+}
+
+pub async fn schedule(ctx: Context, schedule: web::Json<Schedule>) -> impl Responder {
+    let changes = [
+        (JobKind::Cleanup, schedule.cleanup_interval_hours),
+        (JobKind::Backup, schedule.backup_interval_hours),
+    ];
+    match ctx.jobs.lock() {
+        Ok(mut jobs) => {
+            for (kind, value) in changes {
+                if let Some(value) = value {
+                    jobs.set_interval(kind, hours(value));
+                    info!("{kind:?} interval set to {value} hours");
+                }
+            }
+            Status::from("Success")
+        }
+        Err(what) => Status::from(format!("{what}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{self, keys::KeyRing, limiter::RateLimiter, AppState};
+    use crate::cache::ReportCache;
+    use crate::testing;
+    use actix_web::{App, HttpServer};
+
+    fn context(name: &str) -> Context {
+        web::Data::new(AppState::new(
+            testing::pool(name),
+            ReportCache::default(),
+            RateLimiter::default(),
+            KeyRing::new(false),
+        ))
+    }
+
+    #[test]
+    fn job_runs_once_at_a_time() {
+        let mut jobs = Jobs::default();
+        assert!(jobs.start(JobKind::Vacuum));
+        assert!(!jobs.start(JobKind::Vacuum));
+        assert!(jobs.start(JobKind::Analyze));
+        jobs.finish(JobKind::Vacuum, Ok(String::from("Success")));
+        let status = &jobs.status[&JobKind::Vacuum];
+        assert!(!status.running);
+        assert_eq!(status.last_result, Some(String::from("Success")));
+        assert!(jobs.start(JobKind::Vacuum));
+        assert_eq!(jobs.status[&JobKind::Vacuum].runs, 2);
+    }
+
+    #[test]
+    fn cleanup_schedule() {
+        let mut jobs = Jobs::default();
+        assert_eq!(jobs.due(), vec![JobKind::Cleanup]);
+        assert!(jobs.start(JobKind::Cleanup));
+        assert!(jobs.due().is_empty());
+        jobs.set_interval(JobKind::Cleanup, Some(Duration::ZERO));
+        assert_eq!(jobs.due(), vec![JobKind::Cleanup]);
+        jobs.set_interval(JobKind::Cleanup, None);
+        assert!(jobs.due().is_empty());
+    }
+
+    #[test]
+    fn backup_schedule() {
+        let mut jobs = Jobs::new(None);
+        assert!(jobs.due().is_empty());
+        jobs.set_interval(JobKind::Backup, hours(24));
+        assert_eq!(jobs.interval_hours(JobKind::Backup), Some(24));
+        assert_eq!(jobs.due(), vec![JobKind::Backup]);
+        assert!(jobs.start(JobKind::Backup));
+        assert!(jobs.due().is_empty());
+        jobs.set_interval(JobKind::Backup, hours(0));
+        assert_eq!(jobs.interval_hours(JobKind::Backup), None);
+    }
+
+    #[test]
+    fn failed_job_reports_error() {
+        let mut jobs = Jobs::default();
+        assert!(jobs.start(JobKind::Cleanup));
+        jobs.finish(JobKind::Cleanup, Err(anyhow::anyhow!("locked")));
+        assert_eq!(
+            jobs.status[&JobKind::Cleanup].last_result,
+            Some(String::from("Failed: locked"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn sync_pulls_what_the_peer_has() {
+        let peer = context("sync-peer.db");
+        for id in [1, 2, 3] {
+            peer.store(testing::killmail(id, "2022-07-01T10:00:00Z"))
+                .unwrap();
+        }
+        peer.store(testing::killmail(4, "2022-07-02T10:00:00Z"))
+            .unwrap();
+        let data = peer.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/api/killmail/ids/{date}/", web::get().to(api::saved_ids))
+                .route("/api/killmail/export/", web::post().to(api::export))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let root = format!("http://{}/", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let local = context("sync-local.db");
+        local
+            .store(testing::killmail(2, "2022-07-01T10:00:00Z"))
+            .unwrap();
+        local.jobs.lock().unwrap().set_peer(Some(root));
+        let date = NaiveDate::from_ymd_opt(2022, 7, 1).unwrap();
+        let result = sync_job(&local, date).await.unwrap();
+        assert!(result.starts_with("2022-07-01: 2 of 2 missing"), "{result}");
+        handle.stop(false).await;
+
+        let conn = local.get_pool().get().unwrap();
+        let mut ids = database::select_ids_by_date(&conn, &date).unwrap();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2, 3]);
+        let synced = database::select_by_ids(&conn, &[3]).unwrap();
+        let exported = database::select_by_ids(&peer.get_pool().get().unwrap(), &[3]).unwrap();
+        assert_eq!(
+            serde_json::to_string(&synced).unwrap(),
+            serde_json::to_string(&exported).unwrap()
+        );
+        assert!(database::select_by_ids(&conn, &[4]).unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn sync_keeps_to_the_requested_killmails() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/api/killmail/ids/{date}/",
+                    web::get().to(|| async { HttpResponse::Ok().json([1, 2]) }),
+                )
+                .route(
+                    "/api/killmail/export/",
+                    web::post().to(|| async {
+                        let time = "2022-07-01T10:00:00Z";
+                        let killmails = [1, 1, 2, 9].map(|id| testing::killmail(id, time));
+                        HttpResponse::Ok().json(killmails)
+                    }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let root = format!("http://{}/", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let local = context("sync-extra.db");
+        local.jobs.lock().unwrap().set_peer(Some(root));
+        let date = NaiveDate::from_ymd_opt(2022, 7, 1).unwrap();
+        let result = sync_job(&local, date).await.unwrap();
+        assert!(result.starts_with("2022-07-01: 2 of 2 missing"), "{result}");
+        assert!(result.ends_with(", 0 failed"), "{result}");
+        handle.stop(false).await;
+
+        let conn = local.get_pool().get().unwrap();
+        let mut ids = database::select_ids_by_date(&conn, &date).unwrap();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+    }
+}
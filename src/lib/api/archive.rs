@@ -0,0 +1,56 @@
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, Responder};
+
+use std::path::PathBuf;
+
+use super::{Activity, Context, StatType, Status};
+use crate::archive;
+use crate::database::QuerySubject;
+
+fn archive_dir(ctx: &Context) -> anyhow::Result<PathBuf> {
+    ctx.jobs
+        .lock()
+        .map(|jobs| jobs.retention().dir.clone())
+        .map_err(|e| anyhow::anyhow!("{e}"))
+}
+
+fn json_response(json: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(json)
+}
+
+/// Months available for historical queries.
+pub async fn months(ctx: Context) -> impl Responder {
+    ctx.notify_access(StatType::ArchiveQueryCount);
+
+    let json = match archive_dir(&ctx).and_then(|dir| archive::months(&dir)) {
+        Ok(months) => serde_json::to_string(&months).unwrap(),
+        Err(what) => Status::json(format!("{what}")),
+    };
+    json_response(json)
+}
+
+fn activity_impl(
+    ctx: &Context,
+    month: &str,
+    sbj: QuerySubject,
+    id: i32,
+) -> anyhow::Result<Activity> {
+    let rows = archive::history(&archive_dir(ctx)?, month, id, sbj)?;
+    Ok(Activity::from(id, rows))
+}
+
+pub async fn activity(
+    ctx: Context,
+    param: web::Path<(String, QuerySubject, i32)>,
+) -> impl Responder {
+    ctx.notify_access(StatType::ArchiveQueryCount);
+
+    let (month, sbj, id) = param.into_inner();
+    let json = match activity_impl(&ctx, &month, sbj, id) {
+        Ok(report) => serde_json::to_string(&report).unwrap(),
+        Err(what) => Status::json(format!("{what}")),
+    };
+    json_response(json)
+}
@@ -0,0 +1,338 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse, Responder};
+use log::{error, info};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::env;
+
+use super::{Context, Status};
+use crate::database::{self, ApiKey, Scope, SqlitePool};
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+pub const ANONYMOUS: &str = "anonymous";
+
+const KEY_LENGTH: usize = 40;
+
+/// The API keys known to the server, indexed by the key itself.
+pub struct KeyRing {
+    keys: HashMap<String, ApiKey>,
+    anonymous_write: bool,
+}
+impl KeyRing {
+    pub fn new(anonymous_write: bool) -> Self {
+        Self {
+            keys: HashMap::new(),
+            anonymous_write,
+        }
+    }
+
+    pub fn load(&mut self, pool: &SqlitePool) -> anyhow::Result<usize> {
+        let conn = pool.get()?;
+        self.keys = database::select_api_keys(&conn)?
+            .into_iter()
+            .map(|key| (key.key.clone(), key))
+            .collect();
+        Ok(self.keys.len())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ApiKey> {
+        self.keys.get(key)
+    }
+
+    pub fn allows(&self, consumer: &Consumer, scope: Scope) -> bool {
+        match &consumer.key {
+            Some(key) => key.allows(scope),
+            None => scope == Scope::Read || (scope == Scope::Write && self.anonymous_write),
+        }
+    }
+}
+
+/// Who is behind a request: a named API key or an anonymous address.
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    pub name: String,
+    pub client: String,
+    pub key: Option<ApiKey>,
+}
+impl Consumer {
+    pub fn quota(&self) -> Option<u32> {
+        self.key.as_ref().map(|key| key.quota)
+    }
+}
+
+/// Returns None when the request carries a key the server does not know.
+pub fn identify(keys: &KeyRing, req: &ServiceRequest) -> Option<Consumer> {
+    match req.headers().get(API_KEY_HEADER) {
+        Some(value) => {
+            let key = keys.get(value.to_str().ok()?)?;
+            Some(Consumer {
+                name: key.name.clone(),
+                client: format!("key:{}", key.name),
+                key: Some(key.clone()),
+            })
+        }
+        None => Some(Consumer {
+            name: String::from(ANONYMOUS),
+            client: req
+                .peer_addr()
+                .map(|addr| format!("ip:{}", addr.ip()))
+                .unwrap_or_default(),
+            key: None,
+        }),
+    }
+}
+
+pub fn generate() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(KEY_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// HTTP client for the zkbinfo API that presents ZKBINFO_API_KEY when it is set.
+pub fn http_client() -> anyhow::Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+    if let Ok(key) = env::var("ZKBINFO_API_KEY") {
+        headers.insert(API_KEY_HEADER, HeaderValue::from_str(&key)?);
+    }
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .build()
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/******************************************************************************/
+#[derive(Debug, Deserialize)]
+pub struct NewKey {
+    name: String,
+    #[serde(default)]
+    quota: u32,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyInfo {
+    name: String,
+    quota: u32,
+    scopes: Vec<Scope>,
+}
+impl KeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        Self {
+            name: key.name.clone(),
+            quota: key.quota,
+            scopes: key.scopes.clone(),
+        }
+    }
+}
+
+fn reload(ctx: &Context) -> anyhow::Result<usize> {
+    let mut keys = ctx.keys.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+    keys.load(&ctx.pool)
+}
+
+fn list_impl(ctx: &Context) -> anyhow::Result<Vec<KeyInfo>> {
+    let conn = ctx.get_pool().get()?;
+    let keys = database::select_api_keys(&conn)?;
+    Ok(keys.iter().map(KeyInfo::from).collect())
+}
+
+pub async fn list(ctx: Context) -> impl Responder {
+    let json = match list_impl(&ctx) {
+        Ok(keys) => serde_json::to_string(&keys).unwrap(),
+        Err(what) => Status::json(format!("{what}")),
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(json)
+}
+
+/// Returns None when the name is taken.
+fn create_impl(ctx: &Context, new: NewKey) -> anyhow::Result<Option<ApiKey>> {
+    let key = ApiKey {
+        key: generate(),
+        name: new.name,
+        quota: new.quota,
+        scopes: new.scopes,
+    };
+    let conn = ctx.get_pool().get()?;
+    if !database::insert_api_key(&conn, &key)? {
+        return Ok(None);
+    }
+    reload(ctx)?;
+    Ok(Some(key))
+}
+
+pub async fn create(ctx: Context, new: web::Json<NewKey>) -> impl Responder {
+    let name = new.name.clone();
+    match create_impl(&ctx, new.into_inner()) {
+        Ok(Some(key)) => {
+            info!("API key '{}' created", key.name);
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(serde_json::to_string(&key).unwrap())
+        }
+        Ok(None) => HttpResponse::Conflict()
+            .content_type(ContentType::json())
+            .body(Status::json(format!("API key '{name}' already exists"))),
+        Err(what) => {
+            error!("Failed to create API key: {what}");
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(Status::json(format!("{what}")))
+        }
+    }
+}
+
+fn revoke_impl(ctx: &Context, name: &str) -> anyhow::Result<usize> {
+    let conn = ctx.get_pool().get()?;
+    let count = database::delete_api_key(&conn, name)?;
+    reload(ctx)?;
+    Ok(count)
+}
+
+pub async fn revoke(ctx: Context, name: web::Path<String>) -> impl Responder {
+    match revoke_impl(&ctx, &name) {
+        Ok(0) => Status::from(format!("API key '{name}' not found")),
+        Ok(_) => {
+            info!("API key '{name}' revoked");
+            Status::from("Success")
+        }
+        Err(what) => {
+            error!("Failed to revoke API key: {what}");
+            Status::from(format!("{what}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::limiter::RateLimit;
+    use crate::api::AppState;
+    use crate::cache::ReportCache;
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+
+    fn key(name: &str, quota: u32, scopes: Vec<Scope>) -> ApiKey {
+        ApiKey {
+            key: format!("{name}-key"),
+            name: name.to_string(),
+            quota,
+            scopes,
+        }
+    }
+
+    fn keys() -> KeyRing {
+        let mut keys = KeyRing::new(false);
+        for key in [
+            key("reader", 2, vec![Scope::Read]),
+            key("admin", 0, vec![Scope::Admin]),
+        ] {
+            keys.keys.insert(key.key.clone(), key);
+        }
+        keys
+    }
+
+    #[test]
+    fn identifies_by_key_or_address() {
+        let keys = keys();
+        let req = TestRequest::default()
+            .insert_header((API_KEY_HEADER, "reader-key"))
+            .to_srv_request();
+        let reader = identify(&keys, &req).unwrap();
+        assert_eq!(reader.client, "key:reader");
+        assert_eq!(reader.quota(), Some(2));
+        assert!(keys.allows(&reader, Scope::Read));
+        assert!(!keys.allows(&reader, Scope::Write));
+
+        let req = TestRequest::default()
+            .insert_header((API_KEY_HEADER, "admin-key"))
+            .to_srv_request();
+        let admin = identify(&keys, &req).unwrap();
+        assert!(keys.allows(&admin, Scope::Write));
+        assert!(keys.allows(&admin, Scope::Admin));
+
+        let req = TestRequest::default()
+            .insert_header((API_KEY_HEADER, "stolen"))
+            .to_srv_request();
+        assert!(identify(&keys, &req).is_none());
+
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:4242".parse().unwrap())
+            .to_srv_request();
+        let anonymous = identify(&keys, &req).unwrap();
+        assert_eq!(
+            (anonymous.name.as_str(), anonymous.client.as_str()),
+            (ANONYMOUS, "ip:10.0.0.1")
+        );
+        assert_eq!(anonymous.quota(), None);
+        assert!(keys.allows(&anonymous, Scope::Read));
+        assert!(!keys.allows(&anonymous, Scope::Write));
+        assert!(KeyRing::new(true).allows(&anonymous, Scope::Write));
+    }
+
+    #[actix_web::test]
+    async fn quotas_conflicts_and_revocation() {
+        let pool = testing::pool("keys.db");
+        let admin = key("admin", 0, vec![Scope::Admin]);
+        database::replace_api_key(&pool.get().unwrap(), &admin).unwrap();
+        let mut keys = KeyRing::new(false);
+        keys.load(&pool).unwrap();
+        let state = AppState::new(pool, ReportCache::default(), Default::default(), keys);
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(state)).service(
+                web::scope("")
+                    .wrap(RateLimit)
+                    .route("/admin/keys/", web::post().to(create))
+                    .route("/admin/keys/{name}/", web::delete().to(revoke))
+                    .route("/api/character/1/", web::get().to(HttpResponse::Ok))
+                    .route("/killmail/save/", web::post().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let status = |req: TestRequest, key: &str| {
+            let req = req.insert_header((API_KEY_HEADER, key.to_string()));
+            let app = &app;
+            async move { test::call_service(app, req.to_request()).await.status() }
+        };
+
+        let new = serde_json::json!({"name": "reader", "quota": 2, "scopes": ["read"]});
+        let req = TestRequest::post().uri("/admin/keys/").set_json(&new);
+        let created: ApiKey = test::call_and_read_body_json(
+            &app,
+            req.insert_header((API_KEY_HEADER, "admin-key"))
+                .to_request(),
+        )
+        .await;
+        let req = TestRequest::post().uri("/admin/keys/").set_json(&new);
+        assert_eq!(status(req, "admin-key").await, StatusCode::CONFLICT);
+
+        let lookup = || TestRequest::get().uri("/api/character/1/");
+        assert_eq!(status(lookup(), &created.key).await, StatusCode::OK);
+        assert_eq!(status(lookup(), &created.key).await, StatusCode::OK);
+        assert_eq!(
+            status(lookup(), &created.key).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let save = TestRequest::post().uri("/killmail/save/");
+        assert_eq!(status(save, &created.key).await, StatusCode::FORBIDDEN);
+        let req = TestRequest::post().uri("/admin/keys/").set_json(&new);
+        assert_eq!(status(req, &created.key).await, StatusCode::FORBIDDEN);
+
+        let req = TestRequest::delete().uri("/admin/keys/reader/");
+        assert_eq!(status(req, "admin-key").await, StatusCode::OK);
+        assert_eq!(
+            status(lookup(), &created.key).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{ContentType, RETRY_AFTER};
+use actix_web::{web, Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::keys;
+use super::{AppState, StatType, Status};
+use crate::database::Scope;
+
+pub const DEFAULT_LOOKUP_RATE: u32 = 600;
+pub const DEFAULT_ANALYTICS_RATE: u32 = 60;
+
+/// How often the buckets of idle clients are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum RouteGroup {
+    Lookup,
+    Analytics,
+    Ingest,
+    Admin,
+}
+impl RouteGroup {
+    pub fn of(path: &str) -> Self {
+        if path.starts_with("/killmail/") {
+            RouteGroup::Ingest
+        } else if path.starts_with("/admin/") {
+            RouteGroup::Admin
+        } else if path.contains("/activity/")
+            || path.contains("/friends/")
+            || path.contains("/enemies/")
+            || path.starts_with("/api/export")
+            || path.starts_with("/api/killmail/export/")
+            || path.starts_with("/api/killmail/hashes/")
+        {
+            RouteGroup::Analytics
+        } else {
+            RouteGroup::Lookup
+        }
+    }
+
+    pub fn scope(&self) -> Scope {
+        match self {
+            RouteGroup::Lookup | RouteGroup::Analytics => Scope::Read,
+            RouteGroup::Ingest => Scope::Write,
+            RouteGroup::Admin => Scope::Admin,
+        }
+    }
+
+    fn request_stat(&self) -> StatType {
+        match self {
+            RouteGroup::Lookup => StatType::LookupRequestCount,
+            RouteGroup::Analytics => StatType::AnalyticsRequestCount,
+            RouteGroup::Ingest => StatType::IngestRequestCount,
+            RouteGroup::Admin => StatType::AdminRequestCount,
+        }
+    }
+
+    fn limited_stat(&self) -> StatType {
+        match self {
+            RouteGroup::Lookup => StatType::RateLimitedLookupCount,
+            RouteGroup::Analytics => StatType::RateLimitedAnalyticsCount,
+            RouteGroup::Ingest => StatType::RateLimitedIngestCount,
+            RouteGroup::Admin => StatType::RateLimitedAdminCount,
+        }
+    }
+}
+
+/// Requests per minute with a burst of the same size; zero means unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    per_minute: u32,
+}
+impl Rate {
+    pub fn per_minute(per_minute: u32) -> Self {
+        Self { per_minute }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.per_minute == 0
+    }
+
+    fn capacity(&self) -> f64 {
+        self.per_minute as f64
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+impl TokenBucket {
+    fn new(rate: &Rate, now: Instant) -> Self {
+        Self {
+            rate: *rate,
+            tokens: rate.capacity(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.rate.refill_per_sec()).min(self.rate.capacity());
+        self.updated = now;
+    }
+
+    fn take(&mut self, rate: &Rate, now: Instant) -> Result<(), Duration> {
+        if self.rate.per_minute != rate.per_minute {
+            self.tokens = self.tokens.min(rate.capacity());
+            self.rate = *rate;
+        }
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / self.rate.refill_per_sec();
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+pub struct RateLimiter {
+    rates: HashMap<RouteGroup, Rate>,
+    buckets: HashMap<(String, RouteGroup), TokenBucket>,
+    pruned: Instant,
+}
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(
+            Rate::per_minute(DEFAULT_LOOKUP_RATE),
+            Rate::per_minute(DEFAULT_ANALYTICS_RATE),
+        )
+    }
+}
+impl RateLimiter {
+    pub fn new(lookup: Rate, analytics: Rate) -> Self {
+        Self {
+            rates: HashMap::from([
+                (RouteGroup::Lookup, lookup),
+                (RouteGroup::Analytics, analytics),
+            ]),
+            buckets: HashMap::new(),
+            pruned: Instant::now(),
+        }
+    }
+
+    /// Takes a token for the client or returns how long it has to wait for one.
+    /// The quota of an API key replaces the rate of the route group.
+    pub fn check(
+        &mut self,
+        client: &str,
+        group: RouteGroup,
+        quota: Option<Rate>,
+    ) -> Result<(), Duration> {
+        self.check_at(client, group, quota, Instant::now())
+    }
+
+    fn check_at(
+        &mut self,
+        client: &str,
+        group: RouteGroup,
+        quota: Option<Rate>,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let rate = match quota.or_else(|| self.rates.get(&group).copied()) {
+            Some(rate) if !rate.is_unlimited() => rate,
+            _ => return Ok(()),
+        };
+        if now.saturating_duration_since(self.pruned) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+        self.buckets
+            .entry((client.to_string(), group))
+            .or_insert_with(|| TokenBucket::new(&rate, now))
+            .take(&rate, now)
+    }
+
+    /// Forgets clients whose buckets have been refilled completely.
+    fn prune(&mut self, now: Instant) {
+        self.pruned = now;
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.rate.capacity()
+        });
+    }
+}
+
+fn json_response(mut response: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    response
+        .content_type(ContentType::json())
+        .body(Status::json(message))
+}
+
+/// Identifies the consumer, checks its scope and quota and accounts its usage.
+/// Returns the response to send instead of serving the request, if any.
+fn reject(req: &ServiceRequest) -> Option<HttpResponse> {
+    let ctx = req.app_data::<web::Data<AppState>>()?;
+    let group = RouteGroup::of(req.path());
+    let (consumer, allowed) = match ctx.keys.lock() {
+        Ok(keys) => match keys::identify(&keys, req) {
+            Some(consumer) => {
+                let allowed = keys.allows(&consumer, group.scope());
+                (consumer, allowed)
+            }
+            None => {
+                ctx.notify_access(StatType::UnauthorizedCount);
+                return Some(json_response(
+                    HttpResponse::Unauthorized(),
+                    "Unknown API key",
+                ));
+            }
+        },
+        Err(_) => return None,
+    };
+    if !allowed {
+        ctx.notify_consumer(&consumer.name, StatType::ForbiddenCount);
+        return Some(json_response(HttpResponse::Forbidden(), "Forbidden"));
+    }
+
+    let quota = consumer.quota().map(Rate::per_minute);
+    let verdict = match ctx.limiter.lock() {
+        Ok(mut limiter) => limiter.check(&consumer.client, group, quota),
+        Err(_) => Ok(()),
+    };
+    match verdict {
+        Ok(()) => {
+            ctx.notify_consumer(&consumer.name, group.request_stat());
+            None
+        }
+        Err(wait) => {
+            ctx.notify_access(group.limited_stat());
+            ctx.notify_consumer(&consumer.name, group.limited_stat());
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let mut response = HttpResponse::TooManyRequests();
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+            Some(json_response(response, "Too Many Requests"))
+        }
+    }
+}
+
+/// Access control and token bucket rate limiting keyed by API key or client IP.
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(response) = reject(&req) {
+            return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+        }
+
+        let response = self.service.call(req);
+        Box::pin(async move { response.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_group_of() {
+        assert_eq!(
+            RouteGroup::of("/api/killmail/ids/2022-06-01/"),
+            RouteGroup::Lookup
+        );
+        assert_eq!(RouteGroup::of("/api/statistic"), RouteGroup::Lookup);
+        assert_eq!(
+            RouteGroup::of("/api/character/activity/hourly/1/"),
+            RouteGroup::Analytics
+        );
+        assert_eq!(
+            RouteGroup::of("/api/alliance/enemies/corp/1/"),
+            RouteGroup::Analytics
+        );
+        assert_eq!(RouteGroup::of("/api/export"), RouteGroup::Analytics);
+        assert_eq!(
+            RouteGroup::of("/api/killmail/export/"),
+            RouteGroup::Analytics
+        );
+        assert_eq!(
+            RouteGroup::of("/api/killmail/hashes/2022-06-01/"),
+            RouteGroup::Analytics
+        );
+        assert_eq!(RouteGroup::of("/killmail/save"), RouteGroup::Ingest);
+        assert_eq!(RouteGroup::of("/admin/keys/"), RouteGroup::Admin);
+        assert_eq!(RouteGroup::of("/admin/jobs/vacuum/"), RouteGroup::Admin);
+    }
+
+    #[test]
+    fn rejects_after_burst() {
+        let mut limiter = RateLimiter::new(Rate::per_minute(0), Rate::per_minute(2));
+        let now = Instant::now();
+        assert!(limiter
+            .check_at("a", RouteGroup::Analytics, None, now)
+            .is_ok());
+        assert!(limiter
+            .check_at("a", RouteGroup::Analytics, None, now)
+            .is_ok());
+        let wait = limiter
+            .check_at("a", RouteGroup::Analytics, None, now)
+            .expect_err("Third request must be rejected");
+        assert_eq!(wait.as_secs(), 30);
+        assert!(limiter
+            .check_at("b", RouteGroup::Analytics, None, now)
+            .is_ok());
+        assert!(limiter.check_at("a", RouteGroup::Lookup, None, now).is_ok());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = RateLimiter::new(Rate::per_minute(60), Rate::per_minute(0));
+        let now = Instant::now();
+        for _ in 0..60 {
+            assert!(limiter.check_at("a", RouteGroup::Lookup, None, now).is_ok());
+        }
+        assert!(limiter
+            .check_at("a", RouteGroup::Lookup, None, now)
+            .is_err());
+        let later = now + Duration::from_secs(1);
+        assert!(limiter
+            .check_at("a", RouteGroup::Lookup, None, later)
+            .is_ok());
+        assert!(limiter
+            .check_at("a", RouteGroup::Lookup, None, later)
+            .is_err());
+    }
+
+    #[test]
+    fn quota_replaces_group_rate() {
+        let mut limiter = RateLimiter::new(Rate::per_minute(1), Rate::per_minute(1));
+        let now = Instant::now();
+        let quota = Some(Rate::per_minute(3));
+        for _ in 0..3 {
+            assert!(limiter
+                .check_at("key:a", RouteGroup::Lookup, quota, now)
+                .is_ok());
+        }
+        assert!(limiter
+            .check_at("key:a", RouteGroup::Lookup, quota, now)
+            .is_err());
+        let unlimited = Some(Rate::per_minute(0));
+        for _ in 0..10 {
+            assert!(limiter
+                .check_at("key:b", RouteGroup::Lookup, unlimited, now)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn prune_forgets_full_buckets() {
+        let mut limiter = RateLimiter::new(Rate::per_minute(60), Rate::per_minute(0));
+        let now = Instant::now();
+        assert!(limiter.check_at("a", RouteGroup::Lookup, None, now).is_ok());
+        limiter.prune(now);
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.prune(now + Duration::from_secs(2));
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn prunes_once_per_interval() {
+        let mut limiter = RateLimiter::new(Rate::per_minute(60), Rate::per_minute(0));
+        let now = limiter.pruned;
+        assert!(limiter.check_at("a", RouteGroup::Lookup, None, now).is_ok());
+        let soon = now + Duration::from_secs(2);
+        assert!(limiter
+            .check_at("b", RouteGroup::Lookup, None, soon)
+            .is_ok());
+        assert_eq!(limiter.buckets.len(), 2);
+        let later = now + PRUNE_INTERVAL + Duration::from_secs(1);
+        assert!(limiter
+            .check_at("c", RouteGroup::Lookup, None, later)
+            .is_ok());
+        assert_eq!(limiter.buckets.len(), 1);
+        assert_eq!(limiter.pruned, later);
+    }
+}
@@ -0,0 +1,113 @@
+use actix_web::web;
+use log::{error, info};
+
+use super::{Context, StatType};
+use crate::killstream::{Filter, KillmailSource};
+
+/// Saves what the source hands out straight into the database, never returns.
+pub async fn listen<S: KillmailSource>(ctx: Context, mut source: S, filter: Filter) {
+    let stats = source.stats();
+    source
+        .run(|killmail| {
+            let keep = filter.matches(&killmail);
+            if !keep {
+                if let Ok(mut stats) = stats.lock() {
+                    stats.filtered += 1;
+                }
+            }
+            let ctx = ctx.clone();
+            async move {
+                if !keep {
+                    return;
+                }
+                ctx.notify_access(StatType::SavedKillmailsCount);
+                let id = killmail.killmail_id;
+                let db = ctx.clone();
+                let result = web::block(move || db.store(killmail))
+                    .await
+                    .map_err(|e| anyhow::anyhow!(e))
+                    .and_then(|result| result);
+                match result {
+                    Ok(outcome) => {
+                        ctx.arrival();
+                        info!("killmail {id}: {outcome:?}");
+                    }
+                    Err(what) => error!("killmail {id} is lost: {what}"),
+                }
+            }
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{keys::KeyRing, limiter::RateLimiter, AppState};
+    use crate::cache::ReportCache;
+    use crate::database;
+    use crate::evetech::Killmail;
+    use crate::killstream::{Recorder, StreamStats};
+    use crate::testing;
+
+    use std::future::Future;
+    use std::sync::{Arc, Mutex};
+
+    /// Hands out its killmails once and returns.
+    struct Fake {
+        killmails: Vec<Killmail>,
+        stats: Arc<Mutex<StreamStats>>,
+    }
+    impl KillmailSource for Fake {
+        async fn run<F, Fut>(&mut self, mut handle: F)
+        where
+            F: FnMut(Killmail) -> Fut,
+            Fut: Future<Output = ()>,
+        {
+            for killmail in self.killmails.drain(..) {
+                handle(killmail).await;
+            }
+        }
+
+        fn stats(&self) -> Arc<Mutex<StreamStats>> {
+            self.stats.clone()
+        }
+
+        fn record(&mut self, _recorder: Recorder) {}
+    }
+
+    #[actix_web::test]
+    async fn saves_what_the_source_hands_out() {
+        let ctx = web::Data::new(AppState::new(
+            testing::pool("listener.db"),
+            ReportCache::default(),
+            RateLimiter::default(),
+            KeyRing::new(false),
+        ));
+        let mut stranger = testing::killmail(3, "2022-07-01T12:00:00Z");
+        stranger.attackers[0].character_id = Some(5);
+        stranger.victim.character_id = Some(6);
+        let stats = Arc::new(Mutex::new(StreamStats::default()));
+        let source = Fake {
+            killmails: vec![
+                testing::killmail(1, "2022-07-01T10:00:00Z"),
+                testing::killmail(2, "2022-07-01T11:00:00Z"),
+                stranger,
+            ],
+            stats: stats.clone(),
+        };
+        assert!(ctx.jobs.lock().unwrap().last_arrival().is_none());
+
+        listen(ctx.clone(), source, Filter::parse("character:3").unwrap()).await;
+
+        let conn = ctx.get_pool().get().unwrap();
+        let mut ids = database::select_ids_by_date(
+            &conn,
+            &chrono::NaiveDate::from_ymd_opt(2022, 7, 1).unwrap(),
+        )
+        .unwrap();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(stats.lock().unwrap().filtered, 1);
+        assert!(ctx.jobs.lock().unwrap().last_arrival().is_some());
+    }
+}
@@ -1,30 +1,56 @@
-use actix_web::{http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header::{self, ContentType, EntityTag, Header, HttpDate};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
 use chrono::NaiveDate;
 use log::{error, info, warn};
 
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
+use std::time::SystemTime;
 
+use crate::cache;
 use crate::database;
 use crate::evetech;
+use crate::replication;
+use database::InsertOutcome;
 use database::QuerySubject;
 use database::RawHistory;
 use database::RelationType;
 use database::SqlitePool;
 
+use cache::{CacheEntry, CacheKey, Report, ReportCache};
+
+pub mod admin;
+pub mod archive;
+pub mod keys;
+pub mod limiter;
+pub mod listener;
+pub mod snapshot;
+pub mod stream;
+use admin::Jobs;
+use keys::KeyRing;
+use limiter::RateLimiter;
+
 type Context = web::Data<AppState>;
 
 pub struct AppState {
     pub stat: Mutex<Stat>,
     pub pool: SqlitePool,
+    pub cache: Mutex<ReportCache>,
+    pub limiter: Mutex<RateLimiter>,
+    pub keys: Mutex<KeyRing>,
+    pub jobs: Mutex<Jobs>,
 }
 impl AppState {
-    pub fn new(pool: SqlitePool) -> Self {
+    pub fn new(pool: SqlitePool, cache: ReportCache, limiter: RateLimiter, keys: KeyRing) -> Self {
         Self {
             stat: Mutex::new(Stat::default()),
-            pool: pool,
+            pool,
+            cache: Mutex::new(cache),
+            limiter: Mutex::new(limiter),
+            keys: Mutex::new(keys),
+            jobs: Mutex::new(Jobs::default()),
         }
     }
 
@@ -32,12 +58,73 @@ impl AppState {
         self.pool.clone()
     }
 
+    /// Saves the killmail, counts the outcome and drops the cached reports it affects.
+    pub fn store(&self, killmail: evetech::Killmail) -> anyhow::Result<InsertOutcome> {
+        let touched = cache::touched(&killmail);
+        let conn = self.pool.get()?;
+        let outcome = database::insert(&conn, killmail)?;
+        self.notify_access(match outcome {
+            InsertOutcome::Inserted => StatType::InsertedKillmailsCount,
+            InsertOutcome::Duplicate => StatType::DuplicateKillmailsCount,
+            InsertOutcome::Updated => StatType::UpdatedKillmailsCount,
+        });
+        if outcome != InsertOutcome::Duplicate {
+            self.invalidate(&touched);
+        }
+        Ok(outcome)
+    }
+
+    /// Saves the killmail over the stored one.
+    pub fn replace(&self, killmail: evetech::Killmail) -> anyhow::Result<()> {
+        let touched = cache::touched(&killmail);
+        let conn = self.pool.get()?;
+        database::replace(&conn, killmail)?;
+        self.invalidate(&touched);
+        Ok(())
+    }
+
+    /// A killmail came from the live stream, queues the dates of an outage.
+    pub fn arrival(&self) {
+        let queued = match self.jobs.lock() {
+            Ok(mut jobs) => jobs.arrival(),
+            Err(_) => return,
+        };
+        if !queued.is_empty() {
+            warn!("Stream outage detected, backfill queued for {queued:?}");
+        }
+    }
+
     pub fn notify_access(&self, id: StatType) {
         if let Ok(mut stat) = self.stat.try_lock() {
             *stat.access_count.entry(id).or_insert(0) += 1;
         }
     }
 
+    pub fn invalidate(&self, touched: &HashSet<(QuerySubject, i32)>) {
+        if let Ok(mut cache) = self.cache.lock() {
+            let count = cache.invalidate(touched);
+            if count > 0 {
+                if let Ok(mut stat) = self.stat.try_lock() {
+                    *stat
+                        .access_count
+                        .entry(StatType::CacheInvalidatedCount)
+                        .or_insert(0) += count;
+                }
+            }
+        }
+    }
+
+    pub fn notify_consumer(&self, name: &str, st: StatType) {
+        if let Ok(mut stat) = self.stat.try_lock() {
+            *stat
+                .consumers
+                .entry(name.to_string())
+                .or_default()
+                .entry(st)
+                .or_insert(0) += 1;
+        }
+    }
+
     pub fn notify(&self, subj: QuerySubject, st: StatType) {
         if let Ok(mut stat) = self.stat.try_lock() {
             match subj {
@@ -52,8 +139,31 @@ impl AppState {
 #[derive(Serialize, Clone, Eq, PartialEq, Hash)]
 pub enum StatType {
     SavedKillmailsCount,
+    InsertedKillmailsCount,
+    DuplicateKillmailsCount,
+    UpdatedKillmailsCount,
     StatisticAccessedCount,
     SelectKillmailsByDateCount,
+    ExportedKillmailsCount,
+    StreamedExportCount,
+    ArchiveQueryCount,
+    SnapshotAccessedCount,
+
+    CacheHitCount,
+    CacheMissCount,
+    CacheInvalidatedCount,
+
+    RateLimitedLookupCount,
+    RateLimitedAnalyticsCount,
+    RateLimitedIngestCount,
+    RateLimitedAdminCount,
+
+    LookupRequestCount,
+    AnalyticsRequestCount,
+    IngestRequestCount,
+    AdminRequestCount,
+    UnauthorizedCount,
+    ForbiddenCount,
 
     ActivityCount,
     ActivityHourlyCount,
@@ -78,6 +188,9 @@ pub struct Stat {
     character: HashMap<StatType, usize>,
     corporation: HashMap<StatType, usize>,
     alliance: HashMap<StatType, usize>,
+    consumers: HashMap<String, HashMap<StatType, usize>>,
+    /// Taken when the statistic is asked for
+    esi_cache: evetech::CacheStats,
 }
 impl Responder for Stat {
     type Body = actix_web::body::BoxBody;
@@ -118,16 +231,20 @@ pub mod character {
     use super::*;
     const SUBJECT: QuerySubject = QuerySubject::Character;
 
-    pub async fn activity(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn activity(ctx: Context, req: HttpRequest, id: web::Path<i32>) -> impl Responder {
         ctx.notify(SUBJECT, StatType::ActivityCount);
 
-        activity_wrapper(ctx, id.into_inner(), SUBJECT)
+        activity_wrapper(ctx, &req, id.into_inner(), SUBJECT)
     }
 
-    pub async fn activity_hourly(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn activity_hourly(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::ActivityHourlyCount);
 
-        activity_hourly_wrapper(ctx, id.into_inner(), SUBJECT)
+        activity_hourly_wrapper(ctx, &req, id.into_inner(), SUBJECT)
     }
 
     pub async fn lost_ship(ctx: Context, param: web::Path<(i32, i32)>) -> impl Responder {
@@ -137,61 +254,96 @@ pub mod character {
         lost_ship_wrapper(ctx, id, ship_id, SUBJECT)
     }
 
-    pub async fn friends_char(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_char(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsCharacterCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::FriendsChar)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::FriendsChar,
+        )
     }
 
-    pub async fn enemies_char(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_char(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesCharacterCount);
 
         relations_wrapper(
             ctx,
+            &req,
             id.into_inner(),
             QuerySubject::Character,
             RelationType::EnemiesChar,
         )
     }
 
-    pub async fn friends_corp(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_corp(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsCorporationCount);
 
         relations_wrapper(
             ctx,
+            &req,
             id.into_inner(),
             QuerySubject::Character,
             RelationType::FriendsCorp,
         )
     }
 
-    pub async fn enemies_corp(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_corp(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesCorporationCount);
 
         relations_wrapper(
             ctx,
+            &req,
             id.into_inner(),
             QuerySubject::Character,
             RelationType::EnemiesCorp,
         )
     }
 
-    pub async fn friends_alli(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_alli(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsAllianceCount);
 
         relations_wrapper(
             ctx,
+            &req,
             id.into_inner(),
             QuerySubject::Character,
             RelationType::FriendsAlli,
         )
     }
 
-    pub async fn enemies_alli(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_alli(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesAllianceCount);
 
         relations_wrapper(
             ctx,
+            &req,
             id.into_inner(),
             QuerySubject::Character,
             RelationType::EnemiesAlli,
@@ -203,59 +355,123 @@ pub mod corporation {
     use super::*;
     const SUBJECT: QuerySubject = QuerySubject::Corporation;
 
-    pub async fn activity(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn activity(ctx: Context, req: HttpRequest, id: web::Path<i32>) -> impl Responder {
         ctx.notify(SUBJECT, StatType::CorporationActivityCount);
 
-        activity_wrapper(ctx, id.into_inner(), SUBJECT)
+        activity_wrapper(ctx, &req, id.into_inner(), SUBJECT)
     }
 
-    pub async fn activity_hourly(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn activity_hourly(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::ActivityHourlyCount);
 
-        activity_hourly_wrapper(ctx, id.into_inner(), SUBJECT)
+        activity_hourly_wrapper(ctx, &req, id.into_inner(), SUBJECT)
     }
 
-    pub async fn lost_ship(ctx: Context, param: web::Path<(i32,i32)>) -> impl Responder {
+    pub async fn lost_ship(ctx: Context, param: web::Path<(i32, i32)>) -> impl Responder {
         ctx.notify(SUBJECT, StatType::LostShipsCount);
 
         let (id, ship_id) = param.into_inner();
         lost_ship_wrapper(ctx, id, ship_id, SUBJECT)
     }
 
-    pub async fn friends_char(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_char(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsCharacterCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::FriendsChar)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::FriendsChar,
+        )
     }
 
-    pub async fn enemies_char(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_char(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesCharacterCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::EnemiesChar)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::EnemiesChar,
+        )
     }
 
-    pub async fn friends_corp(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_corp(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsCorporationCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::FriendsCorp)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::FriendsCorp,
+        )
     }
 
-    pub async fn enemies_corp(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_corp(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesCorporationCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::EnemiesCorp)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::EnemiesCorp,
+        )
     }
 
-    pub async fn friends_alli(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_alli(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsAllianceCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::FriendsAlli)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::FriendsAlli,
+        )
     }
 
-    pub async fn enemies_alli(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_alli(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesAllianceCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::EnemiesAlli)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::EnemiesAlli,
+        )
     }
 }
 
@@ -263,16 +479,20 @@ pub mod alliance {
     use super::*;
     const SUBJECT: QuerySubject = QuerySubject::Alliance;
 
-    pub async fn activity(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn activity(ctx: Context, req: HttpRequest, id: web::Path<i32>) -> impl Responder {
         ctx.notify(SUBJECT, StatType::AllianceActivityCount);
 
-        activity_wrapper(ctx, id.into_inner(), SUBJECT)
+        activity_wrapper(ctx, &req, id.into_inner(), SUBJECT)
     }
 
-    pub async fn activity_hourly(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn activity_hourly(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::ActivityHourlyCount);
 
-        activity_hourly_wrapper(ctx, id.into_inner(), SUBJECT)
+        activity_hourly_wrapper(ctx, &req, id.into_inner(), SUBJECT)
     }
 
     pub async fn lost_ship(ctx: Context, param: web::Path<(i32, i32)>) -> impl Responder {
@@ -282,40 +502,100 @@ pub mod alliance {
         lost_ship_wrapper(ctx, id, ship_id, SUBJECT)
     }
 
-    pub async fn friends_char(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_char(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsCharacterCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::FriendsChar)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::FriendsChar,
+        )
     }
 
-    pub async fn enemies_char(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_char(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesCharacterCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::EnemiesChar)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::EnemiesChar,
+        )
     }
 
-    pub async fn friends_corp(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_corp(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsCorporationCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::FriendsCorp)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::FriendsCorp,
+        )
     }
 
-    pub async fn enemies_corp(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_corp(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesCorporationCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::EnemiesCorp)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::EnemiesCorp,
+        )
     }
 
-    pub async fn friends_alli(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn friends_alli(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::FriendsAllianceCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::FriendsAlli)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::FriendsAlli,
+        )
     }
 
-    pub async fn enemies_alli(ctx: Context, id: web::Path<i32>) -> impl Responder {
+    pub async fn enemies_alli(
+        ctx: Context,
+        req: HttpRequest,
+        id: web::Path<i32>,
+    ) -> impl Responder {
         ctx.notify(SUBJECT, StatType::EnemiesAllianceCount);
 
-        relations_wrapper(ctx, id.into_inner(), SUBJECT, RelationType::EnemiesAlli)
+        relations_wrapper(
+            ctx,
+            &req,
+            id.into_inner(),
+            SUBJECT,
+            RelationType::EnemiesAlli,
+        )
     }
 }
 
@@ -323,11 +603,13 @@ pub mod alliance {
 pub async fn statistic(ctx: Context) -> impl Responder {
     ctx.notify_access(StatType::StatisticAccessedCount);
 
-    if let Ok(stat) = ctx.stat.try_lock() {
-        return stat.clone();
+    let mut stat = if let Ok(stat) = ctx.stat.try_lock() {
+        stat.clone()
     } else {
-        return Stat::default();
-    }
+        Stat::default()
+    };
+    stat.esi_cache = evetech::esi().cache_stats();
+    stat
 }
 /******************************************************************************/
 #[derive(Serialize, Clone, Default)]
@@ -360,30 +642,111 @@ pub async fn saved_ids(ctx: Context, date: web::Path<String>) -> impl Responder
         .body(json)
 }
 
-/******************************************************************************/
+/// Zkillboard hashes of the date, in the shape of the zkillboard history.
+pub async fn saved_hashes(ctx: Context, date: web::Path<String>) -> impl Responder {
+    ctx.notify_access(StatType::SelectKillmailsByDateCount);
 
-fn save_impl(ctx: Context, json: String) -> anyhow::Result<i32> {
-    let killmail = serde_json::from_str::<evetech::Killmail>(&json)?;
-    let id = killmail.killmail_id;
+    let json = match NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+        Ok(date) => {
+            let pool = ctx.get_pool();
+            let conn = pool.get().unwrap();
+            match database::select_hashes_by_date(&conn, &date) {
+                Ok(hashes) => serde_json::to_string(&hashes).unwrap(),
+                Err(what) => {
+                    error!("Failed to select hashes from DB: {what}");
+                    Status::json(format!("{what}"))
+                }
+            }
+        }
+        Err(what) => {
+            warn!("Can't parse date '{date}' due to '{what}'");
+            Status::json(format!("Can't parse date '{date}' due to '{what}'"))
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(json)
+}
+
+/******************************************************************************/
+fn export_impl(ctx: Context, ids: &[i32]) -> anyhow::Result<Vec<evetech::Killmail>> {
+    if ids.len() > replication::EXPORT_LIMIT {
+        return Err(anyhow::anyhow!(
+            "At most {} killmails per request",
+            replication::EXPORT_LIMIT
+        ));
+    }
     let pool = ctx.get_pool();
     let conn = pool.get()?;
-    let _ = database::insert(&conn, killmail)?;
-    Ok(id)
+    database::select_by_ids(&conn, ids)
 }
 
-pub async fn save(ctx: Context, json: String) -> impl Responder {
-    ctx.notify_access(StatType::SavedKillmailsCount);
+/// Full killmails for the peers replicating from this instance.
+pub async fn export(ctx: Context, ids: web::Json<Vec<i32>>) -> impl Responder {
+    ctx.notify_access(StatType::ExportedKillmailsCount);
 
-    match save_impl(ctx, json) {
-        Ok(id) => {
-            info!("killmail {} saved in the database", id);
-            Status::from(format!("Success"))
-        }
+    let json = match export_impl(ctx, &ids) {
+        Ok(killmails) => serde_json::to_string(&killmails).unwrap(),
         Err(what) => {
-            error!("Failed to select ids from DB: {what}");
-            Status::from(format!("{what}"))
+            error!("Failed to export killmails: {what}");
+            Status::json(format!("{what}"))
         }
+    };
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(json)
+}
+
+/******************************************************************************/
+
+#[derive(Debug, Deserialize)]
+pub struct SaveQuery {
+    /// Set by the live stream, the outage detection counts only these
+    #[serde(default)]
+    live: bool,
+}
+
+fn save_impl(
+    ctx: Context,
+    killmail: evetech::Killmail,
+    live: bool,
+) -> anyhow::Result<(i32, InsertOutcome)> {
+    let id = killmail.killmail_id;
+    let outcome = ctx.store(killmail)?;
+    if live {
+        ctx.arrival();
     }
+    Ok((id, outcome))
+}
+
+pub async fn save(ctx: Context, query: web::Query<SaveQuery>, json: String) -> impl Responder {
+    ctx.notify_access(StatType::SavedKillmailsCount);
+
+    let result = serde_json::from_str::<evetech::Killmail>(&json)
+        .map(|killmail| save_impl(ctx, killmail, query.live));
+    let (mut response, message) = match result {
+        Err(what) => {
+            warn!("Can't parse killmail: {what}");
+            (HttpResponse::BadRequest(), format!("{what}"))
+        }
+        Ok(Ok((id, outcome))) => {
+            info!("killmail {id}: {outcome:?}");
+            let response = match outcome {
+                InsertOutcome::Inserted => HttpResponse::Created(),
+                InsertOutcome::Updated | InsertOutcome::Duplicate => HttpResponse::Ok(),
+            };
+            (response, format!("{outcome:?}"))
+        }
+        Ok(Err(what)) => {
+            error!("Failed to save killmail: {what}");
+            (HttpResponse::InternalServerError(), format!("{what}"))
+        }
+    };
+    response
+        .content_type(ContentType::json())
+        .body(Status::json(message))
 }
 
 /******************************************************************************/
@@ -413,8 +776,10 @@ pub struct Activity {
 }
 impl Activity {
     pub fn from(id: i32, rows: Vec<RawHistory>) -> Self {
-        let mut report = Activity::default();
-        report.id = id;
+        let mut report = Activity {
+            id,
+            ..Default::default()
+        };
         // report.wins.killmails.reserve(rows.len());
         // report.losses.killmails.reserve(rows.len());
         for row in rows {
@@ -444,10 +809,67 @@ impl Activity {
                 }
             }
         }
-        return report;
+        report
     }
 }
 
+/******************************************************************************/
+fn not_modified(req: &HttpRequest, entry: &CacheEntry) -> bool {
+    let etag = EntityTag::new_strong(entry.etag.clone());
+    match header::IfNoneMatch::parse(req) {
+        Ok(header::IfNoneMatch::Any) => return true,
+        Ok(header::IfNoneMatch::Items(tags)) if !tags.is_empty() => {
+            return tags.iter().any(|tag| tag.weak_eq(&etag));
+        }
+        _ => {}
+    }
+    if let Ok(header::IfModifiedSince(since)) = header::IfModifiedSince::parse(req) {
+        return entry.last_modified <= SystemTime::from(since);
+    }
+    false
+}
+
+fn cached_wrapper<F>(ctx: Context, req: &HttpRequest, key: CacheKey, report: F) -> HttpResponse
+where
+    F: FnOnce() -> anyhow::Result<String>,
+{
+    let hit = ctx.cache.lock().ok().and_then(|mut cache| cache.get(&key));
+    let entry = match hit {
+        Some(entry) => {
+            ctx.notify_access(StatType::CacheHitCount);
+            entry
+        }
+        None => {
+            ctx.notify_access(StatType::CacheMissCount);
+            match report() {
+                Ok(json) => match ctx.cache.lock() {
+                    Ok(mut cache) => cache.put(key, json),
+                    Err(_) => CacheEntry::from(json),
+                },
+                Err(what) => {
+                    return HttpResponse::Ok()
+                        .content_type(ContentType::json())
+                        .body(Status::json(format!("{what}")));
+                }
+            }
+        }
+    };
+
+    let etag = header::ETag(EntityTag::new_strong(entry.etag.clone()));
+    let last_modified = header::LastModified(HttpDate::from(entry.last_modified));
+    if not_modified(req, &entry) {
+        return HttpResponse::NotModified()
+            .insert_header(etag)
+            .insert_header(last_modified)
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header(etag)
+        .insert_header(last_modified)
+        .body(entry.body)
+}
+
 /******************************************************************************/
 fn activity_impl(ctx: Context, id: i32, sbj: QuerySubject) -> anyhow::Result<Activity> {
     let pool = ctx.get_pool();
@@ -457,15 +879,11 @@ fn activity_impl(ctx: Context, id: i32, sbj: QuerySubject) -> anyhow::Result<Act
     Ok(Activity::from(id, rows))
 }
 
-fn activity_wrapper(ctx: Context, id: i32, sbj: QuerySubject) -> impl Responder {
-    let json = match activity_impl(ctx, id, sbj) {
-        Ok(report) => serde_json::to_string(&report).unwrap(),
-        Err(what) => Status::json(format!("{what}")),
-    };
-
-    HttpResponse::Ok()
-        .content_type(ContentType::json())
-        .body(json)
+fn activity_wrapper(ctx: Context, req: &HttpRequest, id: i32, sbj: QuerySubject) -> impl Responder {
+    let key = CacheKey::new(sbj, id, Report::Activity, database::HISTORY_DEPTH);
+    cached_wrapper(ctx.clone(), req, key, || {
+        activity_impl(ctx, id, sbj).map(|report| serde_json::to_string(&report).unwrap())
+    })
 }
 
 /******************************************************************************/
@@ -485,17 +903,15 @@ fn relation_impl(
 
 fn relations_wrapper(
     ctx: Context,
+    req: &HttpRequest,
     id: i32,
     sbj: QuerySubject,
     rel: RelationType,
 ) -> impl Responder {
-    let json = match relation_impl(ctx, id, sbj, rel) {
-        Ok(report) => serde_json::to_string(&report).unwrap(),
-        Err(what) => Status::json(format!("{what}")),
-    };
-    HttpResponse::Ok()
-        .content_type(ContentType::json())
-        .body(json)
+    let key = CacheKey::new(sbj, id, Report::Relations(rel), database::HISTORY_DEPTH);
+    cached_wrapper(ctx.clone(), req, key, || {
+        relation_impl(ctx, id, sbj, rel).map(|report| serde_json::to_string(&report).unwrap())
+    })
 }
 
 /******************************************************************************/
@@ -512,22 +928,21 @@ fn activity_hourly_impl(
         .collect::<HashMap<i32, usize>>();
 
     for hour in 0..24 {
-        if !map.contains_key(&hour) {
-            map.insert(hour, 0);
-        }
+        map.entry(hour).or_insert(0);
     }
     Ok(map)
 }
 
-fn activity_hourly_wrapper(ctx: Context, id: i32, sbj: QuerySubject) -> impl Responder {
-    let json = match activity_hourly_impl(ctx, id, sbj) {
-        Ok(report) => serde_json::to_string(&report).unwrap(),
-        Err(what) => Status::json(format!("{what}")),
-    };
-
-    HttpResponse::Ok()
-        .content_type(ContentType::json())
-        .body(json)
+fn activity_hourly_wrapper(
+    ctx: Context,
+    req: &HttpRequest,
+    id: i32,
+    sbj: QuerySubject,
+) -> impl Responder {
+    let key = CacheKey::new(sbj, id, Report::ActivityHourly, database::HISTORY_DEPTH);
+    cached_wrapper(ctx.clone(), req, key, || {
+        activity_hourly_impl(ctx, id, sbj).map(|report| serde_json::to_string(&report).unwrap())
+    })
 }
 /******************************************************************************/
 fn lost_ship_impl(
@@ -553,3 +968,36 @@ fn lost_ship_wrapper(ctx: Context, id: i32, ship_id: i32, sbj: QuerySubject) ->
 }
 
 /******************************************************************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn save_answers_by_outcome() {
+        let state = AppState::new(
+            testing::pool("save.db"),
+            ReportCache::default(),
+            RateLimiter::default(),
+            KeyRing::new(false),
+        );
+        let ctx = web::Data::new(state);
+        let req = TestRequest::default().to_http_request();
+        let killmail = testing::killmail(1, "2022-07-01T10:00:00Z");
+        let json = serde_json::to_string(&killmail).unwrap();
+        let status = |json: String| {
+            let ctx = ctx.clone();
+            let req = req.clone();
+            async move {
+                let query = web::Query(SaveQuery { live: false });
+                save(ctx, query, json).await.respond_to(&req).status()
+            }
+        };
+        assert_eq!(status(json.clone()).await, StatusCode::CREATED);
+        assert_eq!(status(json).await, StatusCode::OK);
+        assert_eq!(status(String::from("{")).await, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,51 @@
+use actix_files::NamedFile;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+
+use std::path::PathBuf;
+
+use super::{Context, StatType, Status};
+use crate::backup;
+
+fn published_dir(ctx: &Context) -> anyhow::Result<PathBuf> {
+    ctx.jobs
+        .lock()
+        .map(|jobs| jobs.backup().published_dir())
+        .map_err(|e| anyhow::anyhow!("{e}"))
+}
+
+/// Published snapshots, oldest first.
+pub async fn list(ctx: Context) -> impl Responder {
+    ctx.notify_access(StatType::SnapshotAccessedCount);
+
+    let json = match published_dir(&ctx).and_then(|dir| backup::list(&dir)) {
+        Ok(names) => serde_json::to_string(&names).unwrap(),
+        Err(what) => Status::json(format!("{what}")),
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(json)
+}
+
+fn download_impl(ctx: &Context, name: &str) -> anyhow::Result<NamedFile> {
+    let dir = published_dir(ctx)?;
+    // Only the listed names, so the path never leaves the directory
+    if !backup::list(&dir)?
+        .iter()
+        .any(|published| published == name)
+    {
+        return Err(anyhow::anyhow!("Snapshot '{name}' not found"));
+    }
+    Ok(NamedFile::open(dir.join(name))?)
+}
+
+pub async fn download(ctx: Context, req: HttpRequest, name: web::Path<String>) -> HttpResponse {
+    ctx.notify_access(StatType::SnapshotAccessedCount);
+
+    match download_impl(&ctx, &name) {
+        Ok(file) => file.into_response(&req),
+        Err(what) => HttpResponse::NotFound()
+            .content_type(ContentType::json())
+            .body(Status::json(format!("{what}"))),
+    }
+}
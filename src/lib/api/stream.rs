@@ -0,0 +1,136 @@
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use log::{error, info};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+use std::io::{self, Write};
+
+use super::{Context, StatType};
+use crate::export::{self, ExportRequest};
+
+/// Bytes gathered before a chunk goes out
+const CHUNK: usize = 65536;
+/// A client that takes no chunk for so long loses the export and its database connection
+const SEND_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Hands what the export writes to the response in chunks, waits while the client is behind.
+struct ChunkWriter {
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    buf: Vec<u8>,
+    runtime: Handle,
+    deadline: Duration,
+}
+impl ChunkWriter {
+    fn new(tx: mpsc::Sender<Result<Bytes, io::Error>>, runtime: Handle) -> Self {
+        Self {
+            tx,
+            buf: Vec::with_capacity(CHUNK),
+            runtime,
+            deadline: SEND_DEADLINE,
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        let sent = self
+            .runtime
+            .block_on(timeout(self.deadline, self.tx.send(Ok(chunk))));
+        match sent {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Client went away",
+            )),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Client takes no data",
+            )),
+        }
+    }
+}
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        let _ = self.send();
+    }
+}
+
+/// Killmails with their participants or the aggregates, as csv, ndjson or parquet.
+pub async fn export(ctx: Context, request: web::Query<ExportRequest>) -> HttpResponse {
+    ctx.notify_access(StatType::StreamedExportCount);
+
+    let request = request.into_inner();
+    let content_type = request.format.content_type();
+    let filename = format!("zkbinfo-export.{}", request.format.extension());
+    let (tx, rx) = mpsc::channel(4);
+    let pool = ctx.get_pool();
+    actix_rt::spawn(async move {
+        let failed = tx.clone();
+        let runtime = Handle::current();
+        let result = web::block(move || {
+            let conn = pool.get()?;
+            export::run(&conn, &request, ChunkWriter::new(tx, runtime))
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+        .and_then(|result| result);
+        match result {
+            Ok(rows) => info!("Exported {rows} rows"),
+            Err(what) => {
+                error!("Failed to export: {what}");
+                // Breaks the response, so the client does not take it as complete
+                let _ = failed.send(Err(io::Error::other(what.to_string()))).await;
+            }
+        }
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{filename}\""),
+        ))
+        .streaming(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stalled_client_is_given_up() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut out = ChunkWriter::new(tx, Handle::current());
+        out.deadline = Duration::from_millis(50);
+        let written = tokio::task::spawn_blocking(move || {
+            out.write_all(b"first")?;
+            out.flush()?;
+            out.write_all(b"second")?;
+            out.flush()
+        })
+        .await
+        .unwrap();
+        assert_eq!(written.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(rx.recv().await.unwrap().unwrap(), Bytes::from("first"));
+    }
+}
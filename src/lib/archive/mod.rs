@@ -0,0 +1,274 @@
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::database::{self, QuerySubject, RawHistory};
+use crate::evetech;
+
+pub const DEFAULT_RETENTION_DAYS: u32 = 360;
+pub const DEFAULT_ARCHIVE_DIR: &str = "archive";
+
+const PREFIX: &str = "killmails-";
+
+/// Where expired killmails go before they are deleted.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ArchiveFormat {
+    Off,
+    Sqlite,
+    Ndjson,
+}
+impl ArchiveFormat {
+    pub fn from(name: &str) -> Option<Self> {
+        match name {
+            "off" | "" => Some(ArchiveFormat::Off),
+            "sqlite" => Some(ArchiveFormat::Sqlite),
+            "ndjson" => Some(ArchiveFormat::Ndjson),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Off => "",
+            ArchiveFormat::Sqlite => "db",
+            ArchiveFormat::Ndjson => "ndjson.gz",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub days: u32,
+    pub format: ArchiveFormat,
+    pub dir: PathBuf,
+}
+impl RetentionPolicy {
+    /// ZKBINFO_RETENTION_DAYS, ZKBINFO_ARCHIVE and ZKBINFO_ARCHIVE_DIR.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            days: env::var("ZKBINFO_RETENTION_DAYS")
+                .unwrap_or_default()
+                .parse::<u32>()
+                .unwrap_or(DEFAULT_RETENTION_DAYS),
+            format: ArchiveFormat::from(&env::var("ZKBINFO_ARCHIVE").unwrap_or_default())
+                .ok_or_else(|| anyhow!("ZKBINFO_ARCHIVE must be one of off, sqlite, ndjson"))?,
+            dir: env::var("ZKBINFO_ARCHIVE_DIR")
+                .unwrap_or(String::from(DEFAULT_ARCHIVE_DIR))
+                .into(),
+        })
+    }
+}
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            days: DEFAULT_RETENTION_DAYS,
+            format: ArchiveFormat::Off,
+            dir: PathBuf::from(DEFAULT_ARCHIVE_DIR),
+        }
+    }
+}
+
+/// Month in the YYYY-MM form, anything else is refused.
+fn checked(month: &str) -> anyhow::Result<&str> {
+    NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d")
+        .map(|_| month)
+        .map_err(|_| anyhow!("Can't parse month '{month}'"))
+}
+
+pub fn path(dir: &Path, month: &str, format: ArchiveFormat) -> PathBuf {
+    dir.join(format!("{PREFIX}{month}.{}", format.extension()))
+}
+
+/// Ids of the killmails in the archive, none when there is no archive yet.
+fn archived_ids(path: &Path) -> anyhow::Result<HashSet<i32>> {
+    #[derive(Deserialize)]
+    struct Archived {
+        killmail_id: i32,
+    }
+    let mut ids = HashSet::new();
+    if !path.exists() {
+        return Ok(ids);
+    }
+    let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+    for line in reader.lines() {
+        ids.insert(serde_json::from_str::<Archived>(&line?)?.killmail_id);
+    }
+    Ok(ids)
+}
+
+/// Appends the killmails not archived yet, a retry after a failed cleanup adds nothing.
+/// The appended copy is renamed over the archive, a failed run leaves the archive as it was.
+fn append_ndjson(path: &Path, killmails: &[evetech::Killmail]) -> anyhow::Result<()> {
+    let archived = archived_ids(path)?;
+    let fresh = killmails
+        .iter()
+        .filter(|killmail| !archived.contains(&killmail.killmail_id))
+        .collect::<Vec<_>>();
+    if fresh.is_empty() {
+        return Ok(());
+    }
+    let temp = path.with_extension("gz.tmp");
+    if path.exists() {
+        fs::copy(path, &temp)?;
+    } else {
+        File::create(&temp)?;
+    }
+    let file = OpenOptions::new().append(true).open(&temp)?;
+    // Every run appends a gzip member, readers see one continuous stream
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    for killmail in fresh {
+        serde_json::to_writer(&mut encoder, killmail)?;
+        encoder.write_all(b"\n")?;
+    }
+    encoder.finish()?.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Archives the expired months as the policy says, then deletes them.
+pub fn archive_and_cleanup(conn: &Connection, policy: &RetentionPolicy) -> anyhow::Result<String> {
+    let months = match policy.format {
+        ArchiveFormat::Off => Vec::new(),
+        format => {
+            fs::create_dir_all(&policy.dir)?;
+            let months = database::expired_months(conn, policy.days)?;
+            for month in &months {
+                let path = path(&policy.dir, month, format);
+                if format == ArchiveFormat::Sqlite {
+                    let path = path.to_str().ok_or_else(|| anyhow!("Bad path {path:?}"))?;
+                    database::archive_month(conn, path, month, policy.days)?;
+                } else {
+                    let killmails = database::select_expired(conn, month, policy.days)?;
+                    append_ndjson(&path, &killmails)?;
+                }
+                info!("Killmails of {month} archived to {path:?}");
+            }
+            months
+        }
+    };
+    database::cleanup(conn, policy.days)?;
+    Ok(format!("{} months archived", months.len()))
+}
+
+/// Months having a SQLite archive, the ones that can be queried.
+pub fn months(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let suffix = format!(".{}", ArchiveFormat::Sqlite.extension());
+    let mut months = Vec::new();
+    if !dir.exists() {
+        return Ok(months);
+    }
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let month = name
+            .strip_prefix(PREFIX)
+            .and_then(|name| name.strip_suffix(&suffix));
+        if let Some(month) = month.filter(|month| checked(month).is_ok()) {
+            months.push(month.to_string());
+        }
+    }
+    months.sort();
+    Ok(months)
+}
+
+/// Whole history of the subject from the archive of the month, attached read-only.
+pub fn history(
+    dir: &Path,
+    month: &str,
+    id: i32,
+    sbj: QuerySubject,
+) -> anyhow::Result<Vec<RawHistory>> {
+    let path = path(dir, checked(month)?, ArchiveFormat::Sqlite);
+    if !path.exists() {
+        return Err(anyhow!("No archive for {month}"));
+    }
+    let path = path.to_str().ok_or_else(|| anyhow!("Bad path {path:?}"))?;
+    let conn = Connection::open_in_memory()?;
+    database::attach_read_only(&conn, path, "archive")?;
+    database::archived_history(&conn, "archive", id, sbj)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, killmail};
+    use std::io::Read;
+
+    fn policy(name: &str, format: ArchiveFormat) -> (Connection, RetentionPolicy) {
+        let conn = testing::database();
+        database::insert(&conn, killmail(1, "2020-01-05T10:00:00Z")).unwrap();
+        database::insert(&conn, killmail(2, "2020-02-05T10:00:00Z")).unwrap();
+        let today = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        database::insert(&conn, killmail(3, &today)).unwrap();
+        let policy = RetentionPolicy {
+            days: 30,
+            format,
+            dir: testing::temp_path(name),
+        };
+        (conn, policy)
+    }
+
+    #[test]
+    fn month_is_checked() {
+        assert!(checked("2020-01").is_ok());
+        assert!(checked("2020-13").is_err());
+        assert!(checked("../db").is_err());
+    }
+
+    #[test]
+    fn sqlite_archive_is_queryable() {
+        let (conn, policy) = policy("sqlite", ArchiveFormat::Sqlite);
+        let result = archive_and_cleanup(&conn, &policy).unwrap();
+        assert_eq!(result, "2 months archived");
+        assert_eq!(months(&policy.dir).unwrap(), vec!["2020-01", "2020-02"]);
+        let rows = history(&policy.dir, "2020-01", 1, QuerySubject::Character).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].is_victim);
+        assert!(database::expired_months(&conn, policy.days)
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(&policy.dir).unwrap();
+    }
+
+    #[test]
+    fn ndjson_archive_round_trip() {
+        let (conn, policy) = policy("ndjson", ArchiveFormat::Ndjson);
+        archive_and_cleanup(&conn, &policy).unwrap();
+        let file = fs::File::open(path(&policy.dir, "2020-02", ArchiveFormat::Ndjson)).unwrap();
+        let mut text = String::new();
+        MultiGzDecoder::new(file).read_to_string(&mut text).unwrap();
+        let killmail = serde_json::from_str::<evetech::Killmail>(text.trim()).unwrap();
+        assert_eq!(killmail, testing::killmail(2, "2020-02-05T10:00:00Z"));
+        assert!(months(&policy.dir).unwrap().is_empty());
+        fs::remove_dir_all(&policy.dir).unwrap();
+    }
+
+    #[test]
+    fn ndjson_retry_appends_nothing_twice() {
+        let dir = testing::temp_dir("ndjson-retry");
+        let path = path(&dir, "2020-01", ArchiveFormat::Ndjson);
+        let first = [killmail(1, "2020-01-05T10:00:00Z")];
+        append_ndjson(&path, &first).unwrap();
+        append_ndjson(&path, &first).unwrap();
+        let both = [first[0].clone(), killmail(2, "2020-01-06T10:00:00Z")];
+        append_ndjson(&path, &both).unwrap();
+        let mut text = String::new();
+        MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert_eq!(archived_ids(&path).unwrap(), HashSet::from([1, 2]));
+        assert!(!path.with_extension("gz.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Duration as Age, NaiveDate, Utc};
+use futures::stream::{self, StreamExt};
+use futures::FutureExt;
+use log::{info, warn};
+use serde::Serialize;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::evetech;
+
+pub const ZKB_HISTORY_ROOT: &str = "https://zkillboard.com/api/history";
+pub const DEFAULT_GAP_MINUTES: i64 = 10;
+/// Longer outages are left to a manual backfill
+pub const MAX_GAP_DAYS: i64 = 7;
+/// ESI killmails fetched at once
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Killmail ids and hashes zkillboard knows for the date.
+pub async fn history(date: &NaiveDate) -> anyhow::Result<HashMap<i32, String>> {
+    let url = format!("{ZKB_HISTORY_ROOT}/{}.json", date.format("%Y%m%d"));
+    info!("{url}");
+    reqwest::get(&url)
+        .await?
+        .json::<HashMap<i32, String>>()
+        .await
+        .map_err(|e| anyhow!(e))
+}
+
+/// Hashes of the wanted killmails, the ones saved without a hash are taken from the history.
+pub async fn hashes(
+    date: &NaiveDate,
+    mut saved: HashMap<i32, String>,
+    wanted: &[i32],
+) -> anyhow::Result<HashMap<i32, String>> {
+    let wanted = wanted.iter().collect::<HashSet<&i32>>();
+    saved.retain(|id, _| wanted.contains(id));
+    if saved.len() < wanted.len() {
+        for (id, hash) in history(date).await? {
+            if wanted.contains(&id) {
+                saved.entry(id).or_insert(hash);
+            }
+        }
+    }
+    Ok(saved)
+}
+
+/// Drops the killmails that are already saved.
+pub fn missing(mut history: HashMap<i32, String>, saved: &[i32]) -> HashMap<i32, String> {
+    for id in saved {
+        history.remove(id);
+    }
+    history
+}
+
+/// The shared ESI client retries what is worth retrying.
+async fn fetch(id: i32, hash: &str) -> anyhow::Result<evetech::Killmail> {
+    let mut killmail = evetech::Killmail::from(id, hash).await?;
+    killmail.zkb = Some(evetech::Zkb {
+        hash: hash.to_string(),
+    });
+    Ok(killmail)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    /// Missing killmails
+    pub total: usize,
+    /// Killmails saved before
+    pub skipped: usize,
+    pub saved: usize,
+    /// Fetched and left out by the store
+    pub filtered: usize,
+    pub failed: Vec<i32>,
+}
+impl Summary {
+    pub fn add(&mut self, other: Summary) {
+        self.total += other.total;
+        self.skipped += other.skipped;
+        self.saved += other.saved;
+        self.filtered += other.filtered;
+        self.failed.extend(other.failed);
+    }
+}
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} missing killmails saved, {} failed, {} saved before",
+            self.saved,
+            self.total,
+            self.failed.len(),
+            self.skipped
+        )?;
+        if self.filtered > 0 {
+            write!(f, ", {} filtered out", self.filtered)?;
+        }
+        Ok(())
+    }
+}
+
+/// Saves the killmails zkillboard knows for the date and the store has not,
+/// fetching up to `concurrency` of them from ESI at once.
+/// The store returns false for a killmail it leaves out.
+pub async fn reconcile<S, Fut, P>(
+    date: &NaiveDate,
+    saved: &[i32],
+    concurrency: usize,
+    store: S,
+    progress: P,
+) -> anyhow::Result<Summary>
+where
+    S: FnMut(evetech::Killmail) -> Fut,
+    Fut: Future<Output = anyhow::Result<bool>>,
+    P: FnMut(usize, usize),
+{
+    let history = history(date).await?;
+    info!("Received {} killmails from zkillboard.com", history.len());
+    let known = history.len();
+    let missing = missing(history, saved);
+    let summary = Summary {
+        total: missing.len(),
+        skipped: known - missing.len(),
+        ..Default::default()
+    };
+    Ok(save_all(missing, concurrency, summary, store, progress).await)
+}
+
+/// Fetches the killmails of the stored hashes from ESI again and saves them over.
+pub async fn refetch<S, Fut, P>(
+    hashes: HashMap<i32, String>,
+    concurrency: usize,
+    store: S,
+    progress: P,
+) -> Summary
+where
+    S: FnMut(evetech::Killmail) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+    P: FnMut(usize, usize),
+{
+    let summary = Summary {
+        total: hashes.len(),
+        ..Default::default()
+    };
+    let mut store = store;
+    let store = move |killmail| store(killmail).map(|result| result.map(|()| true));
+    save_all(hashes, concurrency, summary, store, progress).await
+}
+
+async fn save_all<S, Fut, P>(
+    hashes: HashMap<i32, String>,
+    concurrency: usize,
+    mut summary: Summary,
+    mut store: S,
+    mut progress: P,
+) -> Summary
+where
+    S: FnMut(evetech::Killmail) -> Fut,
+    Fut: Future<Output = anyhow::Result<bool>>,
+    P: FnMut(usize, usize),
+{
+    let mut fetches = stream::iter(hashes)
+        .map(|(id, hash)| async move { (id, fetch(id, &hash).await) })
+        .buffer_unordered(concurrency.max(1));
+    let mut done = 0;
+    while let Some((id, fetched)) = fetches.next().await {
+        let result = match fetched {
+            Ok(killmail) => store(killmail).await,
+            Err(what) => Err(what),
+        };
+        match result {
+            Ok(true) => summary.saved += 1,
+            Ok(false) => summary.filtered += 1,
+            Err(what) => {
+                warn!("Backfill of killmail {id} failed: {what}");
+                summary.failed.push(id);
+            }
+        }
+        done += 1;
+        progress(done, summary.total);
+    }
+    summary
+}
+
+/// Dates from `first` to `last` inclusive, backwards when `last` is earlier.
+pub fn dates(first: NaiveDate, last: NaiveDate) -> Vec<NaiveDate> {
+    let step = if last < first { -1 } else { 1 };
+    let mut dates = vec![first];
+    let mut date = first;
+    while date != last {
+        date += Age::days(step);
+        dates.push(date);
+    }
+    dates
+}
+
+/// The dates a multi-day backfill completed, so a rerun of the range resumes after them.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    range: String,
+    done: BTreeSet<NaiveDate>,
+}
+impl Checkpoint {
+    /// The file starts with its range, the one left by another range is started over.
+    pub fn open(path: PathBuf, first: NaiveDate, last: NaiveDate) -> anyhow::Result<Self> {
+        let range = format!("{first} {last}");
+        let mut done = BTreeSet::new();
+        if path.exists() {
+            let text = fs::read_to_string(&path)?;
+            let mut lines = text.lines();
+            if lines.next().map(str::trim) == Some(range.as_str()) {
+                for line in lines {
+                    if let Ok(date) = NaiveDate::parse_from_str(line.trim(), "%Y-%m-%d") {
+                        done.insert(date);
+                    }
+                }
+            } else {
+                warn!("{} is left by another range, starting over", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(Self { path, range, done })
+    }
+
+    pub fn is_done(&self, date: &NaiveDate) -> bool {
+        self.done.contains(date)
+    }
+
+    pub fn mark(&mut self, date: NaiveDate) -> anyhow::Result<()> {
+        if self.done.insert(date) {
+            let fresh = !self.path.exists();
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            if fresh {
+                writeln!(file, "{}", self.range)?;
+            }
+            writeln!(file, "{}", date.format("%Y-%m-%d"))?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the run once it is complete.
+    pub fn remove(self) -> anyhow::Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Notices the pauses of the live stream and the dates they touch.
+#[derive(Debug)]
+pub struct GapMonitor {
+    threshold: Option<Age>,
+    last_arrival: Option<DateTime<Utc>>,
+    pending: BTreeSet<NaiveDate>,
+}
+impl GapMonitor {
+    /// No threshold disables the detection.
+    pub fn new(threshold: Option<Age>, last_arrival: Option<DateTime<Utc>>) -> Self {
+        Self {
+            threshold,
+            last_arrival,
+            pending: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the dates queued due to the pause before this arrival.
+    pub fn arrival(&mut self, now: DateTime<Utc>) -> Vec<NaiveDate> {
+        let mut queued = Vec::new();
+        if let (Some(threshold), Some(last)) = (self.threshold, self.last_arrival) {
+            if now - last > threshold {
+                let oldest = now.date_naive() - Age::days(MAX_GAP_DAYS);
+                let mut date = last.date_naive().max(oldest);
+                while date <= now.date_naive() {
+                    if self.pending.insert(date) {
+                        queued.push(date);
+                    }
+                    date = date.succ_opt().expect("Correct date expected");
+                }
+            }
+        }
+        self.last_arrival = Some(now);
+        queued
+    }
+
+    /// The oldest pending date that is over, zkillboard history is complete only then.
+    /// It stays pending until its backfill succeeds.
+    pub fn next(&self, today: NaiveDate) -> Option<NaiveDate> {
+        self.pending
+            .iter()
+            .next()
+            .filter(|date| **date < today)
+            .copied()
+    }
+
+    pub fn backfilled(&mut self, date: &NaiveDate) {
+        self.pending.remove(date);
+    }
+
+    pub fn pending(&self) -> Vec<NaiveDate> {
+        self.pending.iter().cloned().collect()
+    }
+
+    pub fn last_arrival(&self) -> Option<DateTime<Utc>> {
+        self.last_arrival
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 7, day, hour, 0, 0).unwrap()
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2022, 7, day).unwrap()
+    }
+
+    #[test]
+    fn pause_queues_the_touched_dates() {
+        let mut monitor = GapMonitor::new(Some(Age::minutes(10)), None);
+        assert!(monitor.arrival(at(1, 10)).is_empty());
+        assert!(monitor.arrival(at(1, 10) + Age::minutes(5)).is_empty());
+        assert_eq!(monitor.arrival(at(3, 1)), vec![date(1), date(2), date(3)]);
+        assert!(monitor.arrival(at(3, 2)).is_empty());
+        assert_eq!(monitor.pending(), vec![date(1), date(2), date(3)]);
+    }
+
+    #[test]
+    fn only_finished_dates_are_backfilled() {
+        let mut monitor = GapMonitor::new(Some(Age::minutes(10)), Some(at(1, 23)));
+        monitor.arrival(at(2, 1));
+        assert_eq!(monitor.next(date(2)), Some(date(1)));
+        // Failed, so it is tried again
+        assert_eq!(monitor.next(date(2)), Some(date(1)));
+        monitor.backfilled(&date(1));
+        assert_eq!(monitor.next(date(2)), None);
+        assert_eq!(monitor.next(date(3)), Some(date(2)));
+        monitor.backfilled(&date(2));
+        assert_eq!(monitor.next(date(3)), None);
+    }
+
+    #[test]
+    fn long_outage_is_capped() {
+        let mut monitor = GapMonitor::new(Some(Age::minutes(10)), Some(at(1, 1)));
+        assert_eq!(monitor.arrival(at(30, 1)).len(), MAX_GAP_DAYS as usize + 1);
+    }
+
+    #[test]
+    fn disabled_without_threshold() {
+        let mut monitor = GapMonitor::new(None, Some(at(1, 1)));
+        assert!(monitor.arrival(at(5, 1)).is_empty());
+    }
+
+    #[test]
+    fn dates_both_ways() {
+        assert_eq!(dates(date(1), date(3)), vec![date(1), date(2), date(3)]);
+        assert_eq!(dates(date(3), date(1)), vec![date(3), date(2), date(1)]);
+        assert_eq!(dates(date(2), date(2)), vec![date(2)]);
+    }
+
+    #[test]
+    fn checkpoint_survives_restart() {
+        let path = testing::temp_path("checkpoint");
+        let mut checkpoint = Checkpoint::open(path.clone(), date(1), date(3)).unwrap();
+        checkpoint.mark(date(1)).unwrap();
+        checkpoint.mark(date(2)).unwrap();
+        checkpoint.mark(date(1)).unwrap();
+
+        let checkpoint = Checkpoint::open(path.clone(), date(1), date(3)).unwrap();
+        assert!(checkpoint.is_done(&date(1)));
+        assert!(checkpoint.is_done(&date(2)));
+        assert!(!checkpoint.is_done(&date(3)));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        let checkpoint = Checkpoint::open(path.clone(), date(2), date(4)).unwrap();
+        assert!(!checkpoint.is_done(&date(2)));
+        assert!(!path.exists());
+        let checkpoint = Checkpoint::open(path.clone(), date(1), date(3)).unwrap();
+        assert!(!checkpoint.is_done(&date(1)));
+        checkpoint.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn summaries_add_up() {
+        let mut total = Summary::default();
+        total.add(Summary {
+            total: 3,
+            skipped: 10,
+            saved: 2,
+            filtered: 4,
+            failed: vec![7],
+        });
+        total.add(Summary {
+            total: 1,
+            skipped: 5,
+            saved: 1,
+            filtered: 0,
+            failed: Vec::new(),
+        });
+        assert_eq!(
+            total.to_string(),
+            "3 of 4 missing killmails saved, 1 failed, 15 saved before, 4 filtered out"
+        );
+    }
+
+    #[test]
+    fn missing_drops_saved() {
+        let history = HashMap::from([(1, String::from("a")), (2, String::from("b"))]);
+        let missing = missing(history, &[2, 3]);
+        assert_eq!(missing.len(), 1);
+        assert!(missing.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn hashes_of_the_wanted_killmails() {
+        let saved = HashMap::from([
+            (1, String::from("a")),
+            (2, String::from("b")),
+            (3, String::from("c")),
+        ]);
+        let hashes = hashes(&date(1), saved, &[1, 3]).await.unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes[&3], "c");
+    }
+}
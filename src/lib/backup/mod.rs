@@ -0,0 +1,201 @@
+use anyhow::anyhow;
+use chrono::{Duration as Age, NaiveDate, NaiveDateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use rusqlite::backup::Backup;
+use rusqlite::Connection;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_BACKUP_DIR: &str = "backup";
+pub const DEFAULT_KEEP_DAYS: u32 = 7;
+pub const PUBLISHED_DIR: &str = "published";
+
+const PREFIX: &str = "killmail-";
+const SNAPSHOT_FORMAT: &str = "%Y%m%d-%H%M%S";
+const PUBLISHED_FORMAT: &str = "%Y-%m-%d";
+/// Pages copied at once, writers wait only for one step
+const PAGES_PER_STEP: i32 = 1000;
+const STEP_PAUSE: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct BackupPolicy {
+    pub dir: PathBuf,
+    pub keep_days: u32,
+    pub publish: bool,
+}
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from(DEFAULT_BACKUP_DIR),
+            keep_days: DEFAULT_KEEP_DAYS,
+            publish: false,
+        }
+    }
+}
+impl BackupPolicy {
+    pub fn published_dir(&self) -> PathBuf {
+        self.dir.join(PUBLISHED_DIR)
+    }
+}
+
+fn timestamp(name: &str) -> Option<NaiveDateTime> {
+    let stem = name.strip_prefix(PREFIX)?;
+    if let Some(stamp) = stem.strip_suffix(".db") {
+        NaiveDateTime::parse_from_str(stamp, SNAPSHOT_FORMAT).ok()
+    } else {
+        let stamp = stem.strip_suffix(".db.gz")?;
+        NaiveDate::parse_from_str(stamp, PUBLISHED_FORMAT)
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+    }
+}
+
+/// Consistent copy of the live database, visible only once complete.
+pub fn snapshot(conn: &Connection, dir: &Path) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let name = format!("{PREFIX}{}.db", Utc::now().format(SNAPSHOT_FORMAT));
+    let path = dir.join(name);
+    let partial = path.with_extension("partial");
+    {
+        let mut dst = Connection::open(&partial)?;
+        let backup = Backup::new(conn, &mut dst)?;
+        backup.run_to_completion(PAGES_PER_STEP, STEP_PAUSE, None)?;
+    }
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+/// Compresses the snapshot as the published one of the day, unless it is there already.
+pub fn publish(snapshot: &Path, dir: &Path) -> anyhow::Result<Option<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let name = format!("{PREFIX}{}.db.gz", Utc::now().format(PUBLISHED_FORMAT));
+    let path = dir.join(name);
+    if path.exists() {
+        return Ok(None);
+    }
+    let partial = path.with_extension("partial");
+    let mut encoder = GzEncoder::new(File::create(&partial)?, Compression::default());
+    io::copy(&mut File::open(snapshot)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::rename(&partial, &path)?;
+    Ok(Some(path))
+}
+
+/// Snapshots and published files of the directory, oldest first.
+pub fn list(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    if !dir.exists() {
+        return Ok(names);
+    }
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if timestamp(&name).is_some() {
+            names.push(name);
+        }
+    }
+    names.sort_by_key(|name| timestamp(name));
+    Ok(names)
+}
+
+/// Removes the files older than keep_days, returns how many.
+pub fn rotate(dir: &Path, keep_days: u32) -> anyhow::Result<usize> {
+    let oldest = Utc::now().naive_utc() - Age::days(keep_days as i64);
+    let mut removed = 0;
+    for name in list(dir)? {
+        if timestamp(&name).filter(|stamp| *stamp < oldest).is_some() {
+            fs::remove_file(dir.join(&name))?;
+            info!("Backup {name} removed");
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Snapshot, publishing and rotation, the way the policy says.
+pub fn run(conn: &Connection, policy: &BackupPolicy) -> anyhow::Result<String> {
+    let path = snapshot(conn, &policy.dir)?;
+    info!("Snapshot {path:?} created");
+    if policy.publish {
+        if let Some(published) = publish(&path, &policy.published_dir())? {
+            info!("Snapshot {published:?} published");
+        }
+    }
+    let removed =
+        rotate(&policy.dir, policy.keep_days)? + rotate(&policy.published_dir(), policy.keep_days)?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Bad path {path:?}"))?
+        .to_string_lossy();
+    Ok(format!("{name} created, {removed} old backups removed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use flate2::read::GzDecoder;
+
+    fn policy(name: &str) -> BackupPolicy {
+        BackupPolicy {
+            dir: testing::temp_path(name),
+            keep_days: 7,
+            publish: true,
+        }
+    }
+
+    fn source() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t(x INTEGER); INSERT INTO t VALUES (42);")
+            .unwrap();
+        conn
+    }
+
+    #[test]
+    fn names_carry_timestamps() {
+        assert!(timestamp("killmail-20261019-063000.db").is_some());
+        assert!(timestamp("killmail-2026-10-19.db.gz").is_some());
+        assert!(timestamp("killmail-20261019-063000.partial").is_none());
+        assert!(timestamp("killmail.db").is_none());
+    }
+
+    #[test]
+    fn snapshot_is_published_once_a_day() {
+        let policy = policy("backup");
+        run(&source(), &policy).unwrap();
+        let snapshots = list(&policy.dir).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        let copy = Connection::open(policy.dir.join(&snapshots[0])).unwrap();
+        let x: i32 = copy
+            .query_row("SELECT x FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(x, 42);
+
+        let published = list(&policy.published_dir()).unwrap();
+        assert_eq!(published.len(), 1);
+        let gz = File::open(policy.published_dir().join(&published[0])).unwrap();
+        let mut bytes = Vec::new();
+        io::Read::read_to_end(&mut GzDecoder::new(gz), &mut bytes).unwrap();
+        assert_eq!(bytes, fs::read(policy.dir.join(&snapshots[0])).unwrap());
+        let path = policy.dir.join(&snapshots[0]);
+        assert!(publish(&path, &policy.published_dir()).unwrap().is_none());
+        fs::remove_dir_all(&policy.dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_by_age() {
+        let policy = policy("rotate");
+        fs::create_dir_all(&policy.dir).unwrap();
+        fs::write(policy.dir.join("killmail-20200101-000000.db"), "").unwrap();
+        fs::write(policy.dir.join("notes.txt"), "").unwrap();
+        snapshot(&source(), &policy.dir).unwrap();
+        assert_eq!(rotate(&policy.dir, policy.keep_days).unwrap(), 1);
+        assert_eq!(list(&policy.dir).unwrap().len(), 1);
+        assert!(policy.dir.join("notes.txt").exists());
+        fs::remove_dir_all(&policy.dir).unwrap();
+    }
+}
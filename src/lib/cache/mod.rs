@@ -0,0 +1,251 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::database::{QuerySubject, RelationType};
+use crate::evetech;
+
+pub const DEFAULT_CAPACITY: usize = 4096;
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Report {
+    Activity,
+    ActivityHourly,
+    Relations(RelationType),
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct CacheKey {
+    pub subject: QuerySubject,
+    pub id: i32,
+    pub report: Report,
+    pub window: &'static str,
+}
+impl CacheKey {
+    pub fn new(subject: QuerySubject, id: i32, report: Report, window: &'static str) -> Self {
+        Self {
+            subject,
+            id,
+            report,
+            window,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub body: String,
+    pub etag: String,
+    pub last_modified: SystemTime,
+    created: Instant,
+    tick: u64,
+}
+impl CacheEntry {
+    pub fn from(body: String) -> Self {
+        Self {
+            etag: etag(&body),
+            body,
+            last_modified: whole_seconds(SystemTime::now()),
+            created: Instant::now(),
+            tick: 0,
+        }
+    }
+}
+
+/// Bounded LRU cache of serialized report bodies with a time-to-live.
+pub struct ReportCache {
+    capacity: usize,
+    ttl: Duration,
+    tick: u64,
+    entries: HashMap<CacheKey, CacheEntry>,
+    order: BTreeMap<u64, CacheKey>,
+}
+impl Default for ReportCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, DEFAULT_TTL)
+    }
+}
+impl ReportCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<CacheEntry> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        if entry.created.elapsed() > self.ttl {
+            let old = entry.tick;
+            self.entries.remove(key);
+            self.order.remove(&old);
+            return None;
+        }
+        self.order.remove(&entry.tick);
+        entry.tick = tick;
+        self.order.insert(tick, key.clone());
+        Some(entry.clone())
+    }
+
+    pub fn put(&mut self, key: CacheKey, body: String) -> CacheEntry {
+        let mut entry = CacheEntry::from(body);
+        if self.capacity == 0 {
+            return entry;
+        }
+        if let Some(old) = self.entries.remove(&key) {
+            self.order.remove(&old.tick);
+        }
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_first() {
+                Some((_, lru)) => self.entries.remove(&lru),
+                None => break,
+            };
+        }
+        entry.tick = self.next_tick();
+        self.order.insert(entry.tick, key.clone());
+        self.entries.insert(key, entry.clone());
+        entry
+    }
+
+    /// Drops every report of the given entities regardless of report kind or window.
+    pub fn invalidate(&mut self, touched: &HashSet<(QuerySubject, i32)>) -> usize {
+        let before = self.entries.len();
+        let order = &mut self.order;
+        self.entries.retain(|key, entry| {
+            let keep = !touched.contains(&(key.subject, key.id));
+            if !keep {
+                order.remove(&entry.tick);
+            }
+            keep
+        });
+        before - self.entries.len()
+    }
+}
+
+/// HTTP dates carry no fractions of a second, so neither should Last-Modified.
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(since.as_secs())
+}
+
+pub fn etag(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Every (subject, id) whose reports may change once the killmail is stored.
+pub fn touched(killmail: &evetech::Killmail) -> HashSet<(QuerySubject, i32)> {
+    let mut ids = HashSet::new();
+    let mut add = |character: Option<i32>, corporation: Option<i32>, alliance: Option<i32>| {
+        if let Some(id) = character {
+            ids.insert((QuerySubject::Character, id));
+        }
+        if let Some(id) = corporation {
+            ids.insert((QuerySubject::Corporation, id));
+        }
+        if let Some(id) = alliance {
+            ids.insert((QuerySubject::Alliance, id));
+        }
+    };
+    let victim = &killmail.victim;
+    add(
+        victim.character_id,
+        victim.corporation_id,
+        victim.alliance_id,
+    );
+    for attacker in &killmail.attackers {
+        add(
+            attacker.character_id,
+            attacker.corporation_id,
+            attacker.alliance_id,
+        );
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: i32) -> CacheKey {
+        CacheKey::new(QuerySubject::Character, id, Report::Activity, "'-30 days'")
+    }
+
+    #[test]
+    fn put_and_get() {
+        let mut cache = ReportCache::new(4, DEFAULT_TTL);
+        let put = cache.put(key(1), String::from("{}"));
+        let got = cache.get(&key(1)).expect("Entry expected");
+        assert_eq!(got.body, "{}");
+        assert_eq!(got.etag, put.etag);
+        assert!(cache.get(&key(2)).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ReportCache::new(2, DEFAULT_TTL);
+        cache.put(key(1), String::from("1"));
+        cache.put(key(2), String::from("2"));
+        assert!(cache.get(&key(1)).is_some());
+        cache.put(key(3), String::from("3"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key(1)).is_some());
+        assert!(cache.get(&key(2)).is_none());
+        assert!(cache.get(&key(3)).is_some());
+    }
+
+    #[test]
+    fn expires_by_ttl() {
+        let mut cache = ReportCache::new(2, Duration::ZERO);
+        cache.put(key(1), String::from("1"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn invalidate_by_entity() {
+        let mut cache = ReportCache::new(8, DEFAULT_TTL);
+        cache.put(key(1), String::from("1"));
+        cache.put(
+            CacheKey::new(
+                QuerySubject::Character,
+                1,
+                Report::Relations(RelationType::FriendsChar),
+                "'-30 days'",
+            ),
+            String::from("1"),
+        );
+        cache.put(key(2), String::from("2"));
+        let touched = HashSet::from([(QuerySubject::Character, 1)]);
+        assert_eq!(cache.invalidate(&touched), 2);
+        assert!(cache.get(&key(1)).is_none());
+        assert!(cache.get(&key(2)).is_some());
+    }
+
+    #[test]
+    fn etag_depends_on_body() {
+        assert_eq!(etag("{}"), etag("{}"));
+        assert_ne!(etag("{}"), etag("[]"));
+    }
+}
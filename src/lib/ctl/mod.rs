@@ -0,0 +1,782 @@
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use log::info;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use tokio::time::{sleep, Duration};
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use crate::api::{keys, Activity};
+use crate::archive::{self, RetentionPolicy};
+use crate::backfill;
+use crate::database::{self, QuerySubject, RelationType, SqlitePool};
+use crate::export::{self, ExportRequest};
+use crate::import;
+use crate::integrity::{self, IntegrityReport, Repair};
+
+pub const DEFAULT_DB: &str = "killmail.db";
+/// How often a remote job is asked whether it is finished
+const JOB_POLL: Duration = Duration::from_secs(2);
+
+pub const USAGE: &str = "[--db <path> | --api <url>] [--json] <command>
+
+Commands:
+    backfill <YYYY-MM-DD> [<YYYY-MM-DD>]    saves the killmails zkillboard has and the database misses
+    import <archive|file|directory>...      imports killmail archives (local only)
+    export format=csv|ndjson|parquet [dataset=killmails|activity|relations]
+           [from=YYYY-MM-DD] [to=YYYY-MM-DD] [subject=<subject>] [id=<id>]   writes to stdout
+    cleanup                                 archives and deletes the expired killmails
+    vacuum                                  rebuilds the database file
+    migrate                                 brings the schema up to date (local only)
+    stats                                   database or API statistic
+    integrity-check [--coverage] [<YYYY-MM-DD> [<YYYY-MM-DD>]]
+           reports the broken killmails by date, --coverage compares with zkillboard (local only)
+    repair refetch|remove [<YYYY-MM-DD> [<YYYY-MM-DD>]]
+           refetches the broken killmails from ESI or removes them (local only)
+    query <character|corporation|alliance> <id> <report>
+           report: activity, hourly, friends-char|corp|alli, enemies-char|corp|alli
+
+The local database is killmail.db by default, ZKBINFO_API_KEY is presented to a remote API.";
+
+/// The database file or the API of a running zkbinfo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Local(PathBuf),
+    Remote(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    Activity,
+    Hourly,
+    Relations(RelationType),
+}
+impl Report {
+    fn from(name: &str) -> Option<Self> {
+        let relation = match name {
+            "activity" => return Some(Report::Activity),
+            "hourly" => return Some(Report::Hourly),
+            "friends-char" => RelationType::FriendsChar,
+            "enemies-char" => RelationType::EnemiesChar,
+            "friends-corp" => RelationType::FriendsCorp,
+            "enemies-corp" => RelationType::EnemiesCorp,
+            "friends-alli" => RelationType::FriendsAlli,
+            "enemies-alli" => RelationType::EnemiesAlli,
+            _ => return None,
+        };
+        Some(Report::Relations(relation))
+    }
+
+    /// The API path of the report, relative to /api/<subject>/.
+    fn path(&self, id: i32) -> String {
+        match self {
+            Report::Activity => format!("activity/{id}/"),
+            Report::Hourly => format!("activity/hourly/{id}/"),
+            Report::Relations(relation) => {
+                let kind = match relation {
+                    RelationType::FriendsChar
+                    | RelationType::FriendsCorp
+                    | RelationType::FriendsAlli => "friends",
+                    _ => "enemies",
+                };
+                let of = match relation {
+                    RelationType::FriendsChar | RelationType::EnemiesChar => "char",
+                    RelationType::FriendsCorp | RelationType::EnemiesCorp => "corp",
+                    _ => "alli",
+                };
+                format!("{kind}/{of}/{id}/")
+            }
+        }
+    }
+}
+
+fn subject(name: &str) -> Option<QuerySubject> {
+    match name {
+        "character" => Some(QuerySubject::Character),
+        "corporation" => Some(QuerySubject::Corporation),
+        "alliance" => Some(QuerySubject::Alliance),
+        _ => None,
+    }
+}
+
+fn subject_name(subject: QuerySubject) -> &'static str {
+    match subject {
+        QuerySubject::Character => "character",
+        QuerySubject::Corporation => "corporation",
+        QuerySubject::Alliance => "alliance",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Backfill(NaiveDate, NaiveDate),
+    Import(Vec<PathBuf>),
+    /// The query string of /api/export
+    Export(String),
+    Cleanup,
+    Vacuum,
+    Migrate,
+    Stats,
+    /// Dates, all of the database without them, and whether to compare with zkillboard
+    IntegrityCheck(Option<(NaiveDate, NaiveDate)>, bool),
+    Repair(Repair, Option<(NaiveDate, NaiveDate)>),
+    Query(QuerySubject, i32, Report),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub target: Target,
+    pub json: bool,
+    pub command: Command,
+}
+impl Options {
+    /// Parses the arguments without the program name.
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut target = Target::Local(PathBuf::from(DEFAULT_DB));
+        let mut json = false;
+        let mut args = args.iter().map(String::as_str).peekable();
+        while let Some(flag) = args.peek().filter(|arg| arg.starts_with("--")) {
+            match *flag {
+                "--db" => {
+                    args.next();
+                    let path = args.next().ok_or_else(|| anyhow!("--db needs a path"))?;
+                    target = Target::Local(PathBuf::from(path));
+                }
+                "--api" => {
+                    args.next();
+                    let url = args.next().ok_or_else(|| anyhow!("--api needs an url"))?;
+                    target = Target::Remote(url.trim_end_matches('/').to_string());
+                }
+                "--json" => {
+                    args.next();
+                    json = true;
+                }
+                flag => return Err(anyhow!("Unknown option {flag}")),
+            }
+        }
+        let name = args.next().ok_or_else(|| anyhow!("No command"))?;
+        let rest = args.collect::<Vec<&str>>();
+        let date = |arg: &str| {
+            NaiveDate::parse_from_str(arg, "%Y-%m-%d")
+                .map_err(|what| anyhow!("Can't parse date '{arg}' due to '{what}'"))
+        };
+        let range = |args: &[&str]| match args {
+            [] => Ok(None),
+            [first] => Ok(Some((date(first)?, date(first)?))),
+            [first, last] => Ok(Some((date(first)?, date(last)?))),
+            _ => Err(anyhow!("Too many dates")),
+        };
+        let command = match (name, rest.as_slice()) {
+            ("backfill", [first]) => Command::Backfill(date(first)?, date(first)?),
+            ("backfill", [first, last]) => Command::Backfill(date(first)?, date(last)?),
+            ("import", paths) if !paths.is_empty() => {
+                Command::Import(paths.iter().map(PathBuf::from).collect())
+            }
+            ("export", params) if !params.is_empty() => {
+                let query = params.join("&");
+                serde_urlencoded::from_str::<ExportRequest>(&query)?;
+                Command::Export(query)
+            }
+            ("cleanup", []) => Command::Cleanup,
+            ("vacuum", []) => Command::Vacuum,
+            ("migrate", []) => Command::Migrate,
+            ("stats", []) => Command::Stats,
+            ("integrity-check", ["--coverage", dates @ ..]) => {
+                Command::IntegrityCheck(range(dates)?, true)
+            }
+            ("integrity-check", dates) => Command::IntegrityCheck(range(dates)?, false),
+            ("repair", ["refetch", dates @ ..]) => Command::Repair(Repair::Refetch, range(dates)?),
+            ("repair", ["remove", dates @ ..]) => Command::Repair(Repair::Remove, range(dates)?),
+            ("query", [sbj, id, report]) => Command::Query(
+                subject(sbj).ok_or_else(|| anyhow!("Unknown subject '{sbj}'"))?,
+                id.parse::<i32>()?,
+                Report::from(report).ok_or_else(|| anyhow!("Unknown report '{report}'"))?,
+            ),
+            (name, _) => return Err(anyhow!("Wrong arguments of '{name}'")),
+        };
+        Ok(Self {
+            target,
+            json,
+            command,
+        })
+    }
+}
+
+/******************************************************************************/
+/// Columns aligned for a terminal.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+impl Table {
+    /// An array of objects is a row each, an object is a row per (nested) field.
+    pub fn from_json(value: &Value) -> Self {
+        match value {
+            Value::Array(items) if items.iter().all(Value::is_object) && !items.is_empty() => {
+                let mut headers = Vec::new();
+                for item in items.iter().filter_map(Value::as_object) {
+                    for key in item.keys() {
+                        if !headers.contains(key) {
+                            headers.push(key.clone());
+                        }
+                    }
+                }
+                let rows = items
+                    .iter()
+                    .map(|item| headers.iter().map(|key| cell(&item[key])).collect())
+                    .collect();
+                Self { headers, rows }
+            }
+            Value::Object(_) => {
+                let mut rows = Vec::new();
+                flatten("", value, &mut rows);
+                rows.sort_by(|a, b| by_key(&a[0], &b[0]));
+                Self {
+                    headers: vec![String::from("field"), String::from("value")],
+                    rows,
+                }
+            }
+            value => Self {
+                headers: vec![String::from("value")],
+                rows: vec![vec![cell(value)]],
+            },
+        }
+    }
+
+    /// An object of id to count, the largest count first.
+    pub fn ranked(value: &Value, header: &str) -> Self {
+        let mut rows = value
+            .as_object()
+            .map(|map| {
+                map.iter()
+                    .map(|(id, count)| (id.clone(), count.as_u64().unwrap_or_default()))
+                    .collect::<Vec<(String, u64)>>()
+            })
+            .unwrap_or_default();
+        rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| by_key(&a.0, &b.0)));
+        Self {
+            headers: vec![String::from("id"), String::from(header)],
+            rows: rows
+                .into_iter()
+                .map(|(id, count)| vec![id, count.to_string()])
+                .collect(),
+        }
+    }
+}
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut widths = self.headers.iter().map(|h| h.len()).collect::<Vec<usize>>();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let line = |cells: &[String]| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+        writeln!(f, "{}", line(&self.headers))?;
+        let rule = widths
+            .iter()
+            .map(|width| "-".repeat(*width))
+            .collect::<Vec<String>>();
+        writeln!(f, "{}", rule.join("  "))?;
+        for row in &self.rows {
+            writeln!(f, "{}", line(row))?;
+        }
+        Ok(())
+    }
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, rows: &mut Vec<Vec<String>>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(&key, value, rows);
+            }
+        }
+        value => rows.push(vec![prefix.to_string(), cell(value)]),
+    }
+}
+
+/// Dotted keys, numbers in numeric order.
+fn by_key(a: &str, b: &str) -> Ordering {
+    for (a, b) in a.split('.').zip(b.split('.')) {
+        let order = match (a.parse::<i64>(), b.parse::<i64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    a.split('.').count().cmp(&b.split('.').count())
+}
+
+/// What a command prints, as JSON or as a table and the notes below it.
+pub struct Output {
+    pub json: Value,
+    pub table: Table,
+    pub notes: Vec<String>,
+}
+impl Output {
+    fn from(json: Value) -> Self {
+        let table = Table::from_json(&json);
+        Self {
+            json,
+            table,
+            notes: Vec::new(),
+        }
+    }
+
+    /// The dates with anomalies, what else is wrong goes to the notes.
+    fn integrity(report: &IntegrityReport) -> anyhow::Result<Self> {
+        let count = |ids: &Vec<i32>| ids.len().to_string();
+        let optional = |n: Option<usize>| n.map(|n| n.to_string()).unwrap_or_default();
+        let table = Table {
+            headers: [
+                "date",
+                "killmails",
+                "without_victim",
+                "duplicate_victims",
+                "without_attackers",
+                "zkillboard",
+                "missing",
+            ]
+            .iter()
+            .map(|header| header.to_string())
+            .collect(),
+            rows: report
+                .days
+                .iter()
+                .filter(|day| !day.is_clean())
+                .map(|day| {
+                    vec![
+                        day.date.to_string(),
+                        day.killmails.to_string(),
+                        count(&day.without_victim),
+                        count(&day.duplicate_victims),
+                        count(&day.without_attackers),
+                        optional(day.zkillboard),
+                        optional(day.missing),
+                    ]
+                })
+                .collect(),
+        };
+        let mut notes = report
+            .file
+            .iter()
+            .map(|problem| format!("SQLite: {problem}"))
+            .collect::<Vec<String>>();
+        if !report.orphaned.is_empty() {
+            notes.push(format!(
+                "Participants without a killmail: {:?}",
+                report.orphaned
+            ));
+        }
+        let broken = report.affected();
+        notes.push(if report.is_clean() {
+            format!("{} dates checked, no anomalies", report.days.len())
+        } else {
+            format!(
+                "{} dates checked, {broken} broken killmails, fix them with 'repair refetch' or 'repair remove'",
+                report.days.len()
+            )
+        });
+        Ok(Self {
+            json: serde_json::to_value(report)?,
+            table,
+            notes,
+        })
+    }
+
+    fn message(message: String) -> Self {
+        Self::from(json!({ "result": message }))
+    }
+}
+
+/******************************************************************************/
+fn concurrency() -> usize {
+    env::var("ZKBINFO_ESI_CONCURRENCY")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap_or(backfill::DEFAULT_CONCURRENCY)
+}
+
+/// Dates of the first and the last saved killmail.
+fn saved_range(conn: &Connection) -> anyhow::Result<Option<(NaiveDate, NaiveDate)>> {
+    let stats = database::stats(conn)?;
+    let date = |time: Option<String>| {
+        time.and_then(|time| NaiveDate::parse_from_str(time.get(..10)?, "%Y-%m-%d").ok())
+    };
+    Ok(date(stats.first_killmail).zip(date(stats.last_killmail)))
+}
+
+fn local_only(command: &str) -> anyhow::Error {
+    anyhow!("{command} works on a local database only, use --db")
+}
+
+/// Opens the database, only the commands that fill it may create it.
+fn open(path: &Path, create: bool) -> anyhow::Result<SqlitePool> {
+    if !create && !path.exists() {
+        return Err(anyhow!("{path:?} not found"));
+    }
+    info!("The Database path: {path:?}");
+    database::create_pool(&path.to_string_lossy())
+}
+
+/// Runs the command, the export goes straight to `out`.
+pub async fn run(
+    options: &Options,
+    out: impl Write + Send + 'static,
+) -> anyhow::Result<Option<Output>> {
+    match &options.target {
+        Target::Local(path) => local(path, &options.command, out).await,
+        Target::Remote(api) => {
+            let remote = Remote {
+                api: api.clone(),
+                client: keys::http_client()?,
+            };
+            remote.run(&options.command, out).await
+        }
+    }
+}
+
+async fn local(
+    path: &Path,
+    command: &Command,
+    out: impl Write + Send + 'static,
+) -> anyhow::Result<Option<Output>> {
+    let create = matches!(
+        command,
+        Command::Backfill(..) | Command::Import(_) | Command::Migrate
+    );
+    let pool = open(path, create)?;
+    let conn = pool.get()?;
+    let output = match command {
+        Command::Backfill(first, last) => {
+            let concurrency = concurrency();
+            let terminal = io::stderr().is_terminal();
+            let mut rows = Vec::new();
+            for date in backfill::dates(*first, *last) {
+                let saved = database::select_ids_by_date(&conn, &date)?;
+                let summary = backfill::reconcile(
+                    &date,
+                    &saved,
+                    concurrency,
+                    |killmail| {
+                        let pool = pool.clone();
+                        async move {
+                            let conn = pool.get()?;
+                            database::insert(&conn, killmail).map(|_| true)
+                        }
+                    },
+                    |n, total| {
+                        if terminal {
+                            eprint!("\r{date}: {n} of {total}");
+                        }
+                    },
+                )
+                .await?;
+                if terminal && summary.total > 0 {
+                    eprintln!();
+                }
+                info!("{date}: {summary}");
+                let mut row = serde_json::to_value(&summary)?;
+                row["date"] = json!(date.to_string());
+                rows.push(row);
+            }
+            Output::from(Value::Array(rows))
+        }
+        Command::Import(paths) => {
+            let batch = env::var("ZKBINFO_IMPORT_BATCH")
+                .unwrap_or_default()
+                .parse::<usize>()
+                .unwrap_or(import::DEFAULT_BATCH);
+            let paths = paths.iter().map(PathBuf::as_path).collect::<Vec<&Path>>();
+            Output::from(serde_json::to_value(import::run(&conn, &paths, batch)?)?)
+        }
+        Command::Export(query) => {
+            let request = serde_urlencoded::from_str::<ExportRequest>(query)?;
+            let rows = export::run(&conn, &request, BufWriter::new(out))?;
+            info!("Exported {rows} rows");
+            return Ok(None);
+        }
+        Command::Cleanup => Output::message(archive::archive_and_cleanup(
+            &conn,
+            &RetentionPolicy::from_env()?,
+        )?),
+        Command::Vacuum => {
+            database::vacuum(&conn)?;
+            Output::message(String::from("Success"))
+        }
+        Command::Migrate => Output::from(serde_json::to_value(database::table_rows(&conn)?)?),
+        Command::Stats => Output::from(serde_json::to_value(database::stats(&conn)?)?),
+        Command::IntegrityCheck(dates, with_coverage) => {
+            let mut report = match dates.or(saved_range(&conn)?) {
+                Some((first, last)) => integrity::check(&conn, first, last)?,
+                None => IntegrityReport {
+                    file: database::integrity_check(&conn)?,
+                    orphaned: database::orphaned_participants(&conn)?,
+                    days: Vec::new(),
+                },
+            };
+            if *with_coverage {
+                integrity::coverage(&conn, &mut report).await?;
+            }
+            Output::integrity(&report)?
+        }
+        Command::Repair(mode, dates) => {
+            let (first, last) = dates
+                .or(saved_range(&conn)?)
+                .ok_or_else(|| anyhow!("No killmails to repair"))?;
+            let report = integrity::check(&conn, first, last)?;
+            let summary = integrity::repair(&conn, &report, *mode, concurrency()).await?;
+            let mut output = Output::from(serde_json::to_value(&summary)?);
+            let report = integrity::check(&conn, first, last)?;
+            output.notes.push(format!(
+                "{} broken killmails left, {} orphaned participants",
+                report.affected(),
+                report.orphaned.len()
+            ));
+            output
+        }
+        Command::Query(sbj, id, report) => {
+            let json = match report {
+                Report::Activity => {
+                    serde_json::to_value(Activity::from(*id, database::history(&conn, *id, *sbj)?))?
+                }
+                Report::Hourly => {
+                    let mut hours = database::activity(&conn, *id, *sbj)?
+                        .into_iter()
+                        .collect::<HashMap<i32, usize>>();
+                    for hour in 0..24 {
+                        hours.entry(hour).or_insert(0);
+                    }
+                    serde_json::to_value(hours)?
+                }
+                Report::Relations(rel) => serde_json::to_value(
+                    database::relations(&conn, *id, *sbj, *rel)?
+                        .into_iter()
+                        .collect::<HashMap<i32, usize>>(),
+                )?,
+            };
+            query_output(*report, json)
+        }
+    };
+    Ok(Some(output))
+}
+
+fn query_output(report: Report, json: Value) -> Output {
+    match report {
+        Report::Relations(_) => {
+            let table = Table::ranked(&json, "killmails");
+            Output {
+                json,
+                table,
+                notes: Vec::new(),
+            }
+        }
+        _ => Output::from(json),
+    }
+}
+
+/// A running zkbinfo, the jobs run there and the command waits for them.
+struct Remote {
+    api: String,
+    client: reqwest::Client,
+}
+impl Remote {
+    async fn get(&self, path: &str) -> anyhow::Result<Value> {
+        let url = format!("{}{path}", self.api);
+        let response = self.client.get(&url).send().await?.error_for_status()?;
+        Ok(response.json::<Value>().await?)
+    }
+
+    /// Starts the job and waits until it is finished, returns its result.
+    /// A run of the job started by someone else is waited for before.
+    async fn job(&self, kind: &str, date: Option<NaiveDate>) -> anyhow::Result<Value> {
+        let path = match date {
+            Some(date) => format!("/admin/jobs/{kind}/{date}/"),
+            None => format!("/admin/jobs/{kind}/"),
+        };
+        let url = format!("{}{path}", self.api);
+        loop {
+            let status = self
+                .client
+                .post(&url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Value>()
+                .await?;
+            match status["message"].as_str().unwrap_or_default() {
+                "Started" => break,
+                "Already running" => {
+                    info!("{kind} job is already running, waiting for it to finish");
+                    self.wait(kind).await?;
+                }
+                message => return Err(anyhow!("{kind}: {message}")),
+            }
+        }
+        info!("{kind} job: Started");
+        let job = self.wait(kind).await?;
+        let mut result = json!({ "job": kind, "result": job["last_result"] });
+        if let Some(date) = date {
+            result["date"] = json!(date.to_string());
+        }
+        Ok(result)
+    }
+
+    /// Polls the job until it is not running, returns its status.
+    async fn wait(&self, kind: &str) -> anyhow::Result<Value> {
+        loop {
+            sleep(JOB_POLL).await;
+            let mut jobs = self.get("/admin/jobs/").await?;
+            let job = jobs["jobs"][kind].take();
+            if !job["running"].as_bool().unwrap_or(false) {
+                return Ok(job);
+            }
+            if let Some(progress) = job["progress"].as_str() {
+                info!("{kind}: {progress}");
+            }
+        }
+    }
+
+    async fn run(&self, command: &Command, mut out: impl Write) -> anyhow::Result<Option<Output>> {
+        let output = match command {
+            Command::Backfill(first, last) => {
+                let mut rows = Vec::new();
+                for date in backfill::dates(*first, *last) {
+                    rows.push(self.job("backfill", Some(date)).await?);
+                }
+                Output::from(Value::Array(rows))
+            }
+            Command::Export(query) => {
+                let url = format!("{}/api/export?{query}", self.api);
+                let mut response = self.client.get(&url).send().await?.error_for_status()?;
+                while let Some(chunk) = response.chunk().await? {
+                    out.write_all(&chunk)?;
+                }
+                out.flush()?;
+                return Ok(None);
+            }
+            Command::Cleanup => Output::from(self.job("cleanup", None).await?),
+            Command::Vacuum => Output::from(self.job("vacuum", None).await?),
+            Command::Stats => Output::from(self.get("/api/statistic").await?),
+            Command::Query(sbj, id, report) => {
+                let path = format!("/api/{}/{}", subject_name(*sbj), report.path(*id));
+                query_output(*report, self.get(&path).await?)
+            }
+            Command::Import(_) => return Err(local_only("import")),
+            Command::Migrate => return Err(local_only("migrate")),
+            Command::IntegrityCheck(..) => return Err(local_only("integrity-check")),
+            Command::Repair(..) => return Err(local_only("repair")),
+        };
+        Ok(Some(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_targets_and_commands() {
+        let options = Options::parse(&args("query corporation 98095669 enemies-alli")).unwrap();
+        assert_eq!(options.target, Target::Local(PathBuf::from(DEFAULT_DB)));
+        assert!(!options.json);
+        assert_eq!(
+            options.command,
+            Command::Query(
+                QuerySubject::Corporation,
+                98095669,
+                Report::Relations(RelationType::EnemiesAlli)
+            )
+        );
+        assert_eq!(
+            Report::Relations(RelationType::EnemiesAlli).path(1),
+            "enemies/alli/1/"
+        );
+
+        let options = Options::parse(&args(
+            "--api http://zkbinfo:8080/ --json backfill 2022-07-01",
+        ))
+        .unwrap();
+        assert_eq!(
+            options.target,
+            Target::Remote(String::from("http://zkbinfo:8080"))
+        );
+        assert!(options.json);
+        let date = NaiveDate::from_ymd_opt(2022, 7, 1).unwrap();
+        assert_eq!(options.command, Command::Backfill(date, date));
+
+        assert!(Options::parse(&args("export format=xml")).is_err());
+        assert!(Options::parse(&args("query ship 1 activity")).is_err());
+        assert!(Options::parse(&args("vacuum now")).is_err());
+        assert_eq!(
+            Options::parse(&args("integrity-check --coverage 2022-07-01"))
+                .unwrap()
+                .command,
+            Command::IntegrityCheck(Some((date, date)), true)
+        );
+        assert_eq!(
+            Options::parse(&args("repair remove")).unwrap().command,
+            Command::Repair(Repair::Remove, None)
+        );
+        assert!(Options::parse(&args("repair 2022-07-01")).is_err());
+        assert!(Options::parse(&args("--db")).is_err());
+    }
+
+    #[test]
+    fn tables_of_json() {
+        let table = Table::from_json(&json!({"wins": {"ships": {"10": 1, "9": 2}}, "id": 1}));
+        assert_eq!(
+            table.to_string(),
+            "field          value\n\
+             -------------  -----\n\
+             id             1\n\
+             wins.ships.9   2\n\
+             wins.ships.10  1\n"
+        );
+        let table =
+            Table::from_json(&json!([{"date": "2022-07-01", "saved": 3}, {"date": "2022-07-02"}]));
+        assert_eq!(table.headers, vec!["date", "saved"]);
+        assert_eq!(table.rows[1], vec!["2022-07-02", ""]);
+        let table = Table::ranked(&json!({"1": 2, "5": 7, "3": 2}), "killmails");
+        assert_eq!(
+            table.rows,
+            vec![vec!["5", "7"], vec!["1", "2"], vec!["3", "2"]]
+        );
+    }
+}
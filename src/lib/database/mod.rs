@@ -1,6 +1,8 @@
 use anyhow::anyhow;
-use chrono::NaiveDate;
-use serde::Serialize;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
 
 use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
@@ -8,10 +10,10 @@ use rusqlite::{named_params, Connection};
 
 use crate::evetech;
 
-const HISTORY_DEPTH: &str = "'-30 days'";
+pub const HISTORY_DEPTH: &str = "'-30 days'";
 
 pub type SqlitePool = r2d2::Pool<SqliteConnectionManager>;
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum RelationType {
     FriendsChar,
     EnemiesChar,
@@ -43,14 +45,15 @@ impl RelationType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum QuerySubject {
     Character,
     Corporation,
     Alliance,
 }
 impl QuerySubject {
-    fn get_field(relation: &Self) -> &'static str {
+    pub(crate) fn get_field(relation: &Self) -> &'static str {
         match relation {
             QuerySubject::Character => "character_id",
             QuerySubject::Corporation => "corporation_id",
@@ -61,21 +64,18 @@ impl QuerySubject {
 
 pub type RawRelation = (i32, usize);
 
-pub fn create_pool(url: &str) -> anyhow::Result<SqlitePool> {
-    let manager = SqliteConnectionManager::file(url);
-    let pool = r2d2::Pool::new(manager).unwrap();
-    let conn = pool.get().unwrap();
-    conn.execute_batch("
-        PRAGMA foreign_keys = ON;
-
-        CREATE TABLE IF NOT EXISTS killmails(
+/// Creates the killmail tables in the main or an attached database.
+pub fn create_tables(conn: &Connection, schema: &str) -> anyhow::Result<()> {
+    conn.execute_batch(&format!("
+        CREATE TABLE IF NOT EXISTS {schema}.killmails(
             killmail_id INTEGER NOT NULL PRIMARY KEY,
             killmail_time TEXT NOT NULL,
-            solar_system_id INTEGER NOT NULL
+            solar_system_id INTEGER NOT NULL,
+            hash TEXT
         );
-        CREATE INDEX IF NOT EXISTS killmail_time_idx ON killmails(killmail_time);
+        CREATE INDEX IF NOT EXISTS {schema}.killmail_time_idx ON killmails(killmail_time);
 
-        CREATE TABLE IF NOT EXISTS participants(
+        CREATE TABLE IF NOT EXISTS {schema}.participants(
             killmail_id INTEGER NOT NULL,
             character_id INTEGER,
             corporation_id INTEGER,
@@ -86,35 +86,360 @@ pub fn create_pool(url: &str) -> anyhow::Result<SqlitePool> {
             UNIQUE(killmail_id, character_id, is_victim),
             FOREIGN KEY(killmail_id) REFERENCES killmails(killmail_id)
         );
-        CREATE INDEX IF NOT EXISTS participant_idx ON participants(character_id, corporation_id, alliance_id);
+        CREATE INDEX IF NOT EXISTS {schema}.participant_idx ON participants(character_id, corporation_id, alliance_id);
+    "))?;
+    // Databases created before the zkillboard hash was kept
+    let has_hash: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('killmails', ?1) WHERE name = 'hash'",
+        [schema],
+        |row| row.get(0),
+    )?;
+    if !has_hash {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {schema}.killmails ADD COLUMN hash TEXT;"
+        ))?;
+    }
+    Ok(())
+}
+
+pub fn create_pool(url: &str) -> anyhow::Result<SqlitePool> {
+    let manager = SqliteConnectionManager::file(url);
+    let pool = r2d2::Pool::new(manager).unwrap();
+    let conn = pool.get().unwrap();
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    create_tables(&conn, "main")?;
+    conn.execute_batch("
+        CREATE TABLE IF NOT EXISTS api_keys(
+            key TEXT NOT NULL PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            quota INTEGER NOT NULL,
+            scopes TEXT NOT NULL
+        );
     ").map_err(|e| anyhow!(e))?;
 
-    return Ok(pool);
+    Ok(pool)
 }
 
-pub fn cleanup(conn: &Connection) -> anyhow::Result<()> {
-    conn.execute_batch(
+/// Deletes killmails older than the retention period.
+pub fn cleanup(conn: &Connection, days: u32) -> anyhow::Result<()> {
+    conn.execute_batch(&format!(
         "
         DELETE FROM participants
         WHERE killmail_id IN (
 	        SELECT killmail_id
 	        FROM killmails
-	        WHERE killmail_time < date('now', '-360 days')
+	        WHERE killmail_time < date('now', '-{days} days')
         );
 
         DELETE FROM killmails
-	    WHERE killmail_time < date('now', '-360 days');
-    ",
-    )
+	    WHERE killmail_time < date('now', '-{days} days');
+    "
+    ))
     .map_err(|e| anyhow!(e))?;
     Ok(())
 }
 
-pub fn insert(conn: &Connection, killmail: evetech::Killmail) -> anyhow::Result<()> {
+/// Months (YYYY-MM) having killmails older than the retention period.
+pub fn expired_months(conn: &Connection, days: u32) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT DISTINCT strftime('%Y-%m', killmail_time) FROM killmails
+         WHERE killmail_time < date('now', '-{days} days')
+         ORDER BY 1;"
+    ))?;
+    let mut months = Vec::new();
+    for month in stmt.query_map([], |row| row.get(0))? {
+        months.push(month?);
+    }
+    Ok(months)
+}
+
+/// Copies the expired killmails of the month into the SQLite file.
+pub fn archive_month(conn: &Connection, path: &str, month: &str, days: u32) -> anyhow::Result<()> {
+    conn.execute("ATTACH DATABASE ?1 AS archive", [path])?;
+    let result = create_tables(conn, "archive").and_then(|_| {
+        conn.execute_batch(&format!(
+            "
+            INSERT OR IGNORE INTO archive.killmails(killmail_id, killmail_time, solar_system_id, hash)
+            SELECT killmail_id, killmail_time, solar_system_id, hash FROM main.killmails
+            WHERE strftime('%Y-%m', killmail_time) = '{month}'
+              AND killmail_time < date('now', '-{days} days');
+
+            INSERT OR IGNORE INTO archive.participants
+            SELECT P.* FROM main.participants P JOIN main.killmails K ON K.killmail_id = P.killmail_id
+            WHERE strftime('%Y-%m', killmail_time) = '{month}'
+              AND killmail_time < date('now', '-{days} days');
+        "
+        ))
+        .map_err(|e| anyhow!(e))
+    });
+    conn.execute_batch("DETACH DATABASE archive;")?;
+    result
+}
+
+/// The expired killmails of the month in the ESI shape.
+pub fn select_expired(
+    conn: &Connection,
+    month: &str,
+    days: u32,
+) -> anyhow::Result<Vec<evetech::Killmail>> {
+    select_killmails(
+        conn,
+        &format!(
+            "strftime('%Y-%m', killmail_time) = '{month}'
+             AND killmail_time < date('now', '-{days} days')"
+        ),
+    )
+}
+
+/// The killmails in the ESI shape, the ones not saved are skipped.
+pub fn select_by_ids(conn: &Connection, ids: &[i32]) -> anyhow::Result<Vec<evetech::Killmail>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let ids = ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    select_killmails(conn, &format!("K.killmail_id IN ({ids})"))
+}
+
+fn select_killmails(conn: &Connection, filter: &str) -> anyhow::Result<Vec<evetech::Killmail>> {
+    let sql = format!(
+        "SELECT K.killmail_id, killmail_time, solar_system_id,
+                character_id, corporation_id, alliance_id, ship_type_id, damage, is_victim, hash
+         FROM participants P JOIN killmails K ON K.killmail_id = P.killmail_id
+         WHERE {filter}
+         ORDER BY K.killmail_id, is_victim DESC;"
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query([])?;
+    let mut killmails: Vec<evetech::Killmail> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let killmail_id: i32 = row.get(0)?;
+        if killmails.last().map(|km| km.killmail_id) != Some(killmail_id) {
+            killmails.push(evetech::Killmail {
+                killmail_id,
+                killmail_time: row.get(1)?,
+                solar_system_id: row.get(2)?,
+                victim: evetech::Victim {
+                    alliance_id: None,
+                    character_id: None,
+                    corporation_id: None,
+                    damage_taken: 0,
+                    ship_type_id: None,
+                },
+                attackers: Vec::new(),
+                zkb: row
+                    .get::<_, Option<String>>(9)?
+                    .map(|hash| evetech::Zkb { hash }),
+            });
+        }
+        let killmail = killmails.last_mut().expect("Killmail pushed above");
+        let is_victim: bool = row.get(8)?;
+        if is_victim {
+            killmail.victim = evetech::Victim {
+                character_id: row.get(3)?,
+                corporation_id: row.get(4)?,
+                alliance_id: row.get(5)?,
+                ship_type_id: row.get(6)?,
+                damage_taken: row.get(7)?,
+            };
+        } else {
+            killmail.attackers.push(evetech::Attackers {
+                character_id: row.get(3)?,
+                corporation_id: row.get(4)?,
+                alliance_id: row.get(5)?,
+                ship_type_id: row.get(6)?,
+                damage_done: row.get(7)?,
+                weapon_type_id: None,
+            });
+        }
+    }
+    Ok(killmails)
+}
+
+/// Attaches an archive file read-only under the schema name.
+pub fn attach_read_only(conn: &Connection, path: &str, schema: &str) -> anyhow::Result<()> {
+    let uri = format!("file:{path}?mode=ro");
+    conn.execute(&format!("ATTACH DATABASE ?1 AS {schema}"), [uri])?;
+    Ok(())
+}
+
+pub fn vacuum(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute_batch("VACUUM;").map_err(|e| anyhow!(e))
+}
+
+pub fn analyze(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute_batch("ANALYZE;").map_err(|e| anyhow!(e))
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct DatabaseStats {
+    pub killmails: usize,
+    pub participants: usize,
+    pub without_hash: usize,
+    pub first_killmail: Option<String>,
+    pub last_killmail: Option<String>,
+    pub size_bytes: usize,
+}
+
+pub fn stats(conn: &Connection) -> anyhow::Result<DatabaseStats> {
+    let mut stats = conn.query_row(
+        "SELECT COUNT(*), SUM(hash IS NULL), MIN(killmail_time), MAX(killmail_time) FROM killmails;",
+        [],
+        |row| {
+            Ok(DatabaseStats {
+                killmails: row.get(0)?,
+                without_hash: row.get::<_, Option<usize>>(1)?.unwrap_or(0),
+                first_killmail: row.get(2)?,
+                last_killmail: row.get(3)?,
+                ..Default::default()
+            })
+        },
+    )?;
+    stats.participants =
+        conn.query_row("SELECT COUNT(*) FROM participants;", [], |row| row.get(0))?;
+    stats.size_bytes = conn.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size();",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(stats)
+}
+
+/// Rows of every table of the main database.
+pub fn table_rows(conn: &Connection) -> anyhow::Result<HashMap<String, usize>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%';",
+    )?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    let mut rows = HashMap::new();
+    for name in names {
+        let sql = format!("SELECT COUNT(*) FROM \"{name}\";");
+        let count = conn.query_row(&sql, [], |row| row.get(0))?;
+        rows.insert(name, count);
+    }
+    Ok(rows)
+}
+
+/// SQLite's own check of the file, empty when the file is sound.
+pub fn integrity_check(conn: &Connection) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check;")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    let mut problems = rows.collect::<Result<Vec<String>, _>>()?;
+    problems.retain(|row| row != "ok");
+    Ok(problems)
+}
+
+/// Killmails of the dates with a victim count other than one or no attackers:
+/// id, date, victims, attackers.
+pub fn participant_anomalies(
+    conn: &Connection,
+    first: &NaiveDate,
+    last: &NaiveDate,
+) -> anyhow::Result<Vec<(i32, String, usize, usize)>> {
+    let mut stmt = conn.prepare(
+        "SELECT K.killmail_id, date(killmail_time),
+                COALESCE(SUM(is_victim = 1), 0) AS victims,
+                COALESCE(SUM(is_victim = 0), 0) AS attackers
+         FROM killmails K LEFT JOIN participants P ON P.killmail_id = K.killmail_id
+         WHERE killmail_time >= ?1 AND killmail_time < date(?2, '+1 day')
+         GROUP BY K.killmail_id
+         HAVING victims <> 1 OR attackers = 0
+         ORDER BY K.killmail_id;",
+    )?;
+    let rows = stmt.query_map([first.to_string(), last.to_string()], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Saved killmails per date.
+pub fn count_by_date(
+    conn: &Connection,
+    first: &NaiveDate,
+    last: &NaiveDate,
+) -> anyhow::Result<HashMap<String, usize>> {
+    let mut stmt = conn.prepare(
+        "SELECT date(killmail_time), COUNT(*) FROM killmails
+         WHERE killmail_time >= ?1 AND killmail_time < date(?2, '+1 day')
+         GROUP BY 1;",
+    )?;
+    let rows = stmt.query_map([first.to_string(), last.to_string()], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    Ok(rows.collect::<Result<HashMap<_, _>, _>>()?)
+}
+
+/// Killmail ids of the participants without a killmail row.
+pub fn orphaned_participants(conn: &Connection) -> anyhow::Result<Vec<i32>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT killmail_id FROM participants
+         WHERE killmail_id NOT IN (SELECT killmail_id FROM killmails)
+         ORDER BY 1;",
+    )?;
+    let ids = stmt.query_map([], |row| row.get(0))?;
+    Ok(ids.collect::<Result<Vec<i32>, _>>()?)
+}
+
+pub fn delete_orphaned_participants(conn: &Connection) -> anyhow::Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM participants WHERE killmail_id NOT IN (SELECT killmail_id FROM killmails);",
+        [],
+    )?)
+}
+
+/// Deletes the killmails with their participants, returns how many killmails.
+pub fn delete_killmails(conn: &Connection, ids: &[i32]) -> anyhow::Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut deleted = 0;
+    for id in ids {
+        tx.execute("DELETE FROM participants WHERE killmail_id = ?1", [id])?;
+        deleted += tx.execute("DELETE FROM killmails WHERE killmail_id = ?1", [id])?;
+    }
+    tx.commit()?;
+    Ok(deleted)
+}
+
+/// What insert did with the killmail.
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum InsertOutcome {
+    Inserted,
+    /// Saved before, nothing new in it
+    Duplicate,
+    /// Saved before, now with its hash or participants it missed
+    Updated,
+}
+
+/// Saves the killmail and its participants all at once or not at all,
+/// also inside the transaction of the caller.
+pub fn insert(conn: &Connection, killmail: evetech::Killmail) -> anyhow::Result<InsertOutcome> {
+    conn.execute_batch("SAVEPOINT insert_killmail;")?;
+    match insert_rows(conn, killmail) {
+        Ok(outcome) => {
+            conn.execute_batch("RELEASE insert_killmail;")?;
+            Ok(outcome)
+        }
+        Err(what) => {
+            conn.execute_batch("ROLLBACK TO insert_killmail; RELEASE insert_killmail;")?;
+            Err(what)
+        }
+    }
+}
+
+fn insert_rows(conn: &Connection, killmail: evetech::Killmail) -> anyhow::Result<InsertOutcome> {
     const INSERT_KILLMAIL: &str = r"INSERT OR IGNORE INTO killmails VALUES (
         :killmail_id,
         :killmail_time,
-        :solar_system_id)";
+        :solar_system_id,
+        :hash)";
+
+    // The same killmail may come again, now with its hash
+    const UPDATE_HASH: &str = r"UPDATE killmails SET hash = :hash
+        WHERE killmail_id = :killmail_id AND hash IS NULL";
 
     const INSERT_PARTICIPANT: &str = r"INSERT OR IGNORE INTO participants VALUES (
         :killmail_id,
@@ -128,57 +453,181 @@ pub fn insert(conn: &Connection, killmail: evetech::Killmail) -> anyhow::Result<
     let mut insert_killmail_stmt = conn.prepare(INSERT_KILLMAIL)?;
     let mut insert_participant_stmt = conn.prepare(INSERT_PARTICIPANT)?;
 
-    insert_killmail_stmt.execute(named_params! {
+    let hash = killmail.zkb.map(|zkb| zkb.hash);
+    let inserted = insert_killmail_stmt.execute(named_params! {
         ":killmail_id": killmail.killmail_id,
         ":killmail_time": killmail.killmail_time,
-        ":solar_system_id": killmail.solar_system_id
+        ":solar_system_id": killmail.solar_system_id,
+        ":hash": hash
     })?;
+    let mut updated = 0;
+    if inserted == 0 && hash.is_some() {
+        updated += conn.execute(
+            UPDATE_HASH,
+            named_params! {
+                ":killmail_id": killmail.killmail_id,
+                ":hash": hash
+            },
+        )?;
+    }
 
     let victim = killmail.victim;
-    insert_participant_stmt.execute(named_params! {
-        ":killmail_id": killmail.killmail_id,
-        ":character_id": victim.character_id,
-        ":corporation_id": victim.corporation_id,
-        ":alliance_id": victim.alliance_id,
-        ":ship_type_id": victim.ship_type_id,
-        ":damage": victim.damage_taken,
-        ":is_victim": 1
-    })?;
+    let mut participants = vec![(
+        victim.character_id,
+        victim.corporation_id,
+        victim.alliance_id,
+        victim.ship_type_id,
+        victim.damage_taken,
+        true,
+    )];
+    participants.extend(killmail.attackers.into_iter().map(|attacker| {
+        (
+            attacker.character_id,
+            attacker.corporation_id,
+            attacker.alliance_id,
+            attacker.ship_type_id,
+            attacker.damage_done,
+            false,
+        )
+    }));
 
-    for attacker in killmail.attackers {
-        insert_participant_stmt.execute(named_params! {
+    // UNIQUE does not hold for NPCs without character, so the ones saved before are skipped here
+    let mut stored = HashMap::new();
+    if inserted == 0 {
+        for participant in stored_participants(conn, killmail.killmail_id)? {
+            *stored.entry(participant).or_insert(0) += 1;
+        }
+    }
+    for participant in participants {
+        let (character_id, corporation_id, alliance_id, ship_type_id, damage, is_victim) =
+            participant;
+        if let Some(count) = stored.get_mut(&participant).filter(|count| **count > 0) {
+            *count -= 1;
+            continue;
+        }
+        updated += insert_participant_stmt.execute(named_params! {
             ":killmail_id": killmail.killmail_id,
-            ":character_id": attacker.character_id,
-            ":corporation_id": attacker.corporation_id,
-            ":alliance_id": attacker.alliance_id,
-            ":ship_type_id": attacker.ship_type_id,
-            ":damage": attacker.damage_done,
-            ":is_victim": 0
+            ":character_id": character_id,
+            ":corporation_id": corporation_id,
+            ":alliance_id": alliance_id,
+            ":ship_type_id": ship_type_id,
+            ":damage": damage,
+            ":is_victim": is_victim
         })?;
     }
 
+    Ok(if inserted > 0 {
+        InsertOutcome::Inserted
+    } else if updated > 0 {
+        InsertOutcome::Updated
+    } else {
+        InsertOutcome::Duplicate
+    })
+}
+
+type Participant = (Option<i32>, Option<i32>, Option<i32>, Option<i32>, i32, bool);
+
+fn stored_participants(conn: &Connection, killmail_id: i32) -> anyhow::Result<Vec<Participant>> {
+    let mut stmt = conn.prepare(
+        "SELECT character_id, corporation_id, alliance_id, ship_type_id, damage, is_victim
+         FROM participants WHERE killmail_id = ?1",
+    )?;
+    let rows = stmt.query_map([killmail_id], |row| {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+        ))
+    })?;
+    Ok(rows.collect::<Result<Vec<Participant>, _>>()?)
+}
+
+/// The ids of the list that are saved already.
+pub fn existing_ids(conn: &Connection, ids: &[i32]) -> anyhow::Result<HashSet<i32>> {
+    let mut existing = HashSet::new();
+    if ids.is_empty() {
+        return Ok(existing);
+    }
+    let ids = ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT killmail_id FROM killmails WHERE killmail_id IN ({ids});"
+    ))?;
+    for id in stmt.query_map([], |row| row.get(0))? {
+        existing.insert(id?);
+    }
+    Ok(existing)
+}
+
+/// Saves the killmail over the stored one, e.g. after ESI was asked again.
+pub fn replace(conn: &Connection, killmail: evetech::Killmail) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "DELETE FROM participants WHERE killmail_id = ?1",
+        [killmail.killmail_id],
+    )?;
+    tx.execute(
+        "DELETE FROM killmails WHERE killmail_id = ?1",
+        [killmail.killmail_id],
+    )?;
+    insert(&tx, killmail)?;
+    tx.commit()?;
     Ok(())
 }
 
-pub fn select_ids_by_date(conn: &Connection, date: &NaiveDate) -> anyhow::Result<Vec<i32>> {
-    let left = date.format("%Y-%m-%d").to_string();
-    let right = date
-        .succ_opt()
-        .expect("Correct date expected")
-        .format("%Y-%m-%d")
-        .to_string();
-    let sql = format!(
-        "SELECT killmail_id FROM killmails WHERE killmail_time BETWEEN '{left}' AND '{right}';"
-    );
+/// The date and the next one, the killmail times of the date are between them.
+fn day_bounds(date: &NaiveDate) -> [String; 2] {
+    let next = date.succ_opt().expect("Correct date expected");
+    [
+        date.format("%Y-%m-%d").to_string(),
+        next.format("%Y-%m-%d").to_string(),
+    ]
+}
 
-    let mut stmt = conn.prepare(&sql)?;
+/// Zkillboard hashes of the killmails saved for the date, the ones without a hash are skipped.
+pub fn select_hashes_by_date(
+    conn: &Connection,
+    date: &NaiveDate,
+) -> anyhow::Result<HashMap<i32, String>> {
+    let mut stmt = conn.prepare(
+        "SELECT killmail_id, hash FROM killmails
+         WHERE killmail_time BETWEEN ?1 AND ?2 AND hash IS NOT NULL;",
+    )?;
+    let mut hashes = HashMap::new();
+    for row in stmt.query_map(day_bounds(date), |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let (id, hash) = row?;
+        hashes.insert(id, hash);
+    }
+    Ok(hashes)
+}
+
+pub fn select_ids_by_date(conn: &Connection, date: &NaiveDate) -> anyhow::Result<Vec<i32>> {
+    let mut stmt =
+        conn.prepare("SELECT killmail_id FROM killmails WHERE killmail_time BETWEEN ?1 AND ?2;")?;
     let mut ids = Vec::new();
-    for id in stmt.query_map([], |row| row.get(0))? {
+    for id in stmt.query_map(day_bounds(date), |row| row.get(0))? {
         ids.push(id?);
     }
     Ok(ids)
 }
 
+/// Time of the latest saved killmail.
+pub fn last_killmail_time(conn: &Connection) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let time: Option<String> =
+        conn.query_row("SELECT MAX(killmail_time) FROM killmails;", [], |row| {
+            row.get(0)
+        })?;
+    Ok(time
+        .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+        .map(|time| time.with_timezone(&Utc)))
+}
+
 #[derive(Debug)]
 pub struct RawHistory {
     pub killmail_id: i32,
@@ -217,6 +666,41 @@ pub fn history(conn: &Connection, id: i32, sbj: QuerySubject) -> anyhow::Result<
     Ok(iter.map(|res| res.unwrap()).collect())
 }
 
+/// Whole history of the subject stored in an attached archive.
+pub fn archived_history(
+    conn: &Connection,
+    schema: &str,
+    id: i32,
+    sbj: QuerySubject,
+) -> anyhow::Result<Vec<RawHistory>> {
+    let sbj_field = QuerySubject::get_field(&sbj);
+    let sql = format!(
+            "SELECT K.killmail_id, character_id, corporation_id, alliance_id, ship_type_id, damage, is_victim, solar_system_id
+             FROM {schema}.participants P JOIN {schema}.killmails K ON K.killmail_id = P.killmail_id
+             WHERE {sbj_field} = {id}
+             ORDER BY killmail_time;"
+        );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let iter = stmt.query_map([], |row| {
+        Ok(RawHistory {
+            killmail_id: row.get(0)?,
+            character_id: row.get(1)?,
+            corporation_id: row.get(2)?,
+            alliance_id: row.get(3)?,
+            ship_type_id: row.get(4)?,
+            damage: row.get(5)?,
+            is_victim: row.get(6)?,
+            solar_system_id: row.get(7)?,
+        })
+    })?;
+    let mut rows = Vec::new();
+    for row in iter {
+        rows.push(row?);
+    }
+    Ok(rows)
+}
+
 pub fn relations(
     conn: &Connection,
     id: i32,
@@ -269,20 +753,19 @@ pub struct Killmail {
     solar_system_id: i32,
     killmail_time: String,
 }
+type RawKillmail = (
+    i32,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+    i32,
+    i32,
+    i32,
+    String,
+);
 impl Killmail {
-    pub fn from(
-        row: (
-            i32,
-            Option<i32>,
-            Option<i32>,
-            Option<i32>,
-            Option<i32>,
-            i32,
-            i32,
-            i32,
-            String,
-        ),
-    ) -> Self {
+    pub fn from(row: RawKillmail) -> Self {
         let (
             killmail_id,
             character_id,
@@ -339,3 +822,217 @@ pub fn lost_ships(
     Ok(iter.map(|res| res.unwrap()).collect())
     // Err(anyhow!("NotImpl"))
 }
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+impl Scope {
+    pub fn name(scope: &Scope) -> &'static str {
+        match scope {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+    pub fn from(scope: &str) -> Option<Scope> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub key: String,
+    pub name: String,
+    /// Requests per minute, zero means unlimited
+    pub quota: u32,
+    pub scopes: Vec<Scope>,
+}
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+fn save_api_key(conn: &Connection, sql: &str, key: &ApiKey) -> rusqlite::Result<usize> {
+    let scopes = key
+        .scopes
+        .iter()
+        .map(Scope::name)
+        .collect::<Vec<_>>()
+        .join(",");
+    conn.execute(
+        sql,
+        named_params! {
+            ":key": key.key,
+            ":name": key.name,
+            ":quota": key.quota,
+            ":scopes": scopes,
+        },
+    )
+}
+
+/// Returns false when the name or the key is taken already.
+pub fn insert_api_key(conn: &Connection, key: &ApiKey) -> anyhow::Result<bool> {
+    const INSERT: &str = "INSERT INTO api_keys VALUES (:key, :name, :quota, :scopes)";
+    match save_api_key(conn, INSERT, key) {
+        Ok(_) => Ok(true),
+        Err(rusqlite::Error::SqliteFailure(failure, _))
+            if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Ok(false)
+        }
+        Err(what) => Err(anyhow!(what)),
+    }
+}
+
+/// Saves the key under its name, replacing the one saved before under that name.
+pub fn replace_api_key(conn: &Connection, key: &ApiKey) -> anyhow::Result<()> {
+    const UPSERT: &str = r"INSERT INTO api_keys VALUES (:key, :name, :quota, :scopes)
+        ON CONFLICT(name) DO UPDATE SET
+            key = excluded.key,
+            quota = excluded.quota,
+            scopes = excluded.scopes";
+    save_api_key(conn, UPSERT, key)?;
+    Ok(())
+}
+
+pub fn delete_api_key(conn: &Connection, name: &str) -> anyhow::Result<usize> {
+    let count = conn.execute(
+        "DELETE FROM api_keys WHERE name = :name",
+        named_params! {":name": name},
+    )?;
+    Ok(count)
+}
+
+pub fn select_api_keys(conn: &Connection) -> anyhow::Result<Vec<ApiKey>> {
+    let mut stmt = conn.prepare("SELECT key, name, quota, scopes FROM api_keys ORDER BY name")?;
+    let iter = stmt.query_map([], |row| {
+        let scopes: String = row.get(3)?;
+        Ok(ApiKey {
+            key: row.get(0)?,
+            name: row.get(1)?,
+            quota: row.get(2)?,
+            scopes: scopes.split(',').filter_map(Scope::from).collect(),
+        })
+    })?;
+    let mut keys = Vec::new();
+    for key in iter {
+        keys.push(key?);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn killmail(hash: Option<&str>) -> evetech::Killmail {
+        let killmail = testing::killmail(1, "2022-07-01T10:00:00Z");
+        match hash {
+            Some(hash) => testing::hashed(killmail, hash),
+            None => killmail,
+        }
+    }
+
+    #[test]
+    fn hash_is_kept_and_old_tables_migrated() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE killmails(
+                killmail_id INTEGER NOT NULL PRIMARY KEY,
+                killmail_time TEXT NOT NULL,
+                solar_system_id INTEGER NOT NULL
+            );",
+        )
+        .unwrap();
+        create_tables(&conn, "main").unwrap();
+        create_tables(&conn, "main").unwrap();
+
+        insert(&conn, killmail(None)).unwrap();
+        insert(&conn, killmail(Some("abc"))).unwrap();
+        insert(&conn, killmail(Some("other"))).unwrap();
+        let date = NaiveDate::from_ymd_opt(2022, 7, 1).unwrap();
+        let hashes = select_hashes_by_date(&conn, &date).unwrap();
+        assert_eq!(hashes, HashMap::from([(1, String::from("abc"))]));
+
+        let mut refetched = killmail(Some("abc"));
+        refetched.victim.damage_taken = 20;
+        replace(&conn, refetched.clone()).unwrap();
+        assert_eq!(select_by_ids(&conn, &[1]).unwrap(), vec![refetched]);
+    }
+
+    #[test]
+    fn insert_outcomes_and_rollback() {
+        let conn = testing::database();
+        assert_eq!(insert(&conn, killmail(None)).unwrap(), InsertOutcome::Inserted);
+        assert_eq!(insert(&conn, killmail(None)).unwrap(), InsertOutcome::Duplicate);
+        assert_eq!(
+            insert(&conn, killmail(Some("abc"))).unwrap(),
+            InsertOutcome::Updated
+        );
+
+        let complete = testing::killmail(2, "2022-07-01T10:00:00Z");
+        assert_eq!(insert(&conn, complete).unwrap(), InsertOutcome::Inserted);
+        let mut broken = testing::killmail(3, "2022-07-01T10:00:00Z");
+        broken.attackers[0].character_id = Some(666);
+        conn.execute_batch(
+            "CREATE TRIGGER fail BEFORE INSERT ON participants WHEN NEW.character_id = 666
+             BEGIN SELECT RAISE(ABORT, 'failed'); END;",
+        )
+        .unwrap();
+        assert!(insert(&conn, broken).is_err());
+        assert_eq!(existing_ids(&conn, &[1, 2, 3]).unwrap(), HashSet::from([1, 2]));
+        let participants: usize = conn
+            .query_row("SELECT COUNT(*) FROM participants WHERE killmail_id = 3", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(participants, 0);
+    }
+
+    #[test]
+    fn api_key_names_are_unique() {
+        let pool = testing::pool("api-keys.db");
+        let conn = pool.get().unwrap();
+        let mut key = ApiKey {
+            key: String::from("first"),
+            name: String::from("admin"),
+            quota: 0,
+            scopes: vec![Scope::Admin],
+        };
+        assert!(insert_api_key(&conn, &key).unwrap());
+        key.key = String::from("second");
+        assert!(!insert_api_key(&conn, &key).unwrap());
+        assert_eq!(select_api_keys(&conn).unwrap()[0].key, "first");
+        replace_api_key(&conn, &key).unwrap();
+        assert_eq!(select_api_keys(&conn).unwrap(), vec![key]);
+    }
+
+    #[test]
+    fn attackers_without_character_are_saved_once() {
+        let conn = testing::database();
+        let mut killmail = testing::killmail(1, "2022-07-01T10:00:00Z");
+        let mut npc = killmail.attackers[0].clone();
+        npc.character_id = None;
+        killmail.attackers.push(npc.clone());
+        killmail.attackers.push(npc);
+        assert_eq!(insert(&conn, killmail.clone()).unwrap(), InsertOutcome::Inserted);
+        assert_eq!(insert(&conn, killmail.clone()).unwrap(), InsertOutcome::Duplicate);
+        let hashed = testing::hashed(killmail, "abc");
+        assert_eq!(insert(&conn, hashed).unwrap(), InsertOutcome::Updated);
+        let participants: usize = conn
+            .query_row("SELECT COUNT(*) FROM participants", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(participants, 4);
+    }
+}
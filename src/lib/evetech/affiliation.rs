@@ -0,0 +1,58 @@
+use super::*;
+use futures::future::try_join_all;
+
+/// Characters ESI answers for in one call
+pub const AFFILIATION_CHUNK: usize = 1000;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct Affiliation {
+    pub character_id: i32,
+    pub corporation_id: i32,
+    pub alliance_id: Option<i32>,
+    pub faction_id: Option<i32>,
+}
+impl Affiliation {
+    /// Corporations and alliances of all the characters, in as few calls as ESI allows.
+    pub async fn from(ids: &[i32]) -> anyhow::Result<Vec<Self>> {
+        Self::from_esi(esi(), ids).await
+    }
+
+    pub async fn from_esi(esi: &Esi, ids: &[i32]) -> anyhow::Result<Vec<Self>> {
+        let url = esi.url("/characters/affiliation/");
+        let mut unique = ids.to_vec();
+        unique.sort();
+        unique.dedup();
+        info!("{url} for {} characters", unique.len());
+        let chunks = unique
+            .chunks(AFFILIATION_CHUNK)
+            .map(|chunk| esi.post::<_, Vec<Self>>(&url, chunk));
+        Ok(try_join_all(chunks).await?.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn from() -> Result<(), String> {
+        let affiliations = Affiliation::from(&[2114350216, 2114350216])
+            .await
+            .map_err(|e| format!("{e}"))?;
+
+        assert_eq!(
+            affiliations,
+            vec![Affiliation {
+                character_id: 2114350216,
+                corporation_id: 98573194,
+                alliance_id: None,
+                faction_id: None,
+            }]
+        );
+        assert!(Affiliation::from(&[])
+            .await
+            .map_err(|e| format!("{e}"))?
+            .is_empty());
+        Ok(())
+    }
+}
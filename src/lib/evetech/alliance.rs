@@ -12,13 +12,13 @@ pub struct Alliance {
 }
 impl Alliance {
     pub async fn from(id: i32) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/alliances/{id}/?{EVE_TECH_SERVER}");
+        Self::from_esi(esi(), id).await
+    }
+
+    pub async fn from_esi(esi: &Esi, id: i32) -> anyhow::Result<Self> {
+        let url = esi.url(&format!("/alliances/{id}/"));
         info!("{url}");
-        reqwest::get(&url)
-            .await?
-            .json::<Self>()
-            .await
-            .map_err(|e| anyhow!(e))
+        esi.get::<Self>(&url).await
     }
 }
 
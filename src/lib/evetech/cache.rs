@@ -0,0 +1,307 @@
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Expired answers with an ETag are kept in the file so long for a revalidation
+const REVALIDATE_WINDOW: Duration = Duration::days(1);
+/// Stores between the prunes of the file
+const PRUNE_EVERY: usize = 1000;
+
+/// An ESI answer with what is needed to serve or revalidate it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedResponse {
+    pub etag: Option<String>,
+    pub expires: DateTime<Utc>,
+    pub body: String,
+}
+impl CachedResponse {
+    pub fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        now < self.expires
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct CacheStats {
+    /// Served without a call
+    pub hits: usize,
+    /// Confirmed by ESI with a 304
+    pub revalidated: usize,
+    /// Fetched whole
+    pub misses: usize,
+    pub entries: usize,
+    /// Of the answers, the ones that needed no body from ESI
+    pub hit_rate: f64,
+}
+
+/// The answers in memory, also ordered by when they expire.
+#[derive(Default)]
+struct Entries {
+    by_url: HashMap<String, CachedResponse>,
+    by_expiry: BTreeSet<(DateTime<Utc>, String)>,
+}
+impl Entries {
+    fn insert(&mut self, url: &str, entry: CachedResponse, capacity: usize) {
+        if let Some(old) = self.by_url.remove(url) {
+            self.by_expiry.remove(&(old.expires, url.to_string()));
+        } else if self.by_url.len() >= capacity {
+            // What expires first is the least likely to be served again
+            if let Some((_, first)) = self.by_expiry.pop_first() {
+                self.by_url.remove(&first);
+            }
+        }
+        self.by_expiry.insert((entry.expires, url.to_string()));
+        self.by_url.insert(url.to_string(), entry);
+    }
+}
+
+/// ESI answers by url, in memory and optionally in a SQLite file that outlives the process.
+pub struct ResponseCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    file: Option<Arc<Mutex<Connection>>>,
+    stores: AtomicUsize,
+    hits: AtomicUsize,
+    revalidated: AtomicUsize,
+    misses: AtomicUsize,
+}
+impl ResponseCache {
+    /// No entries are kept with zero capacity.
+    pub fn new(capacity: usize, file: Option<&Path>) -> Self {
+        let file = file.filter(|_| capacity > 0).and_then(|path| {
+            open(path)
+                .map_err(|what| warn!("ESI cache {} not used: {what}", path.display()))
+                .ok()
+                .map(|conn| Arc::new(Mutex::new(conn)))
+        });
+        Self {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+            file,
+            stores: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            revalidated: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn lookup(&self, url: &str) -> Option<CachedResponse> {
+        if self.capacity == 0 {
+            return None;
+        }
+        if let Some(entry) = self.entries.lock().ok()?.by_url.get(url) {
+            return Some(entry.clone());
+        }
+        let file = self.file.clone()?;
+        let key = url.to_string();
+        let entry = tokio::task::spawn_blocking(move || {
+            let conn = file.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+            select(&conn, &key)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+        .and_then(|result| result)
+        .map_err(|what| warn!("ESI cache lookup of {url} failed: {what}"))
+        .ok()??;
+        self.remember(url, entry.clone());
+        Some(entry)
+    }
+
+    pub async fn store(&self, url: &str, entry: CachedResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(file) = self.file.clone() {
+            let prune =
+                self.stores.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1;
+            let (key, stored) = (url.to_string(), entry.clone());
+            let saved = tokio::task::spawn_blocking(move || {
+                let conn = file.lock().map_err(|e| anyhow::anyhow!("{e}"))?;
+                upsert(&conn, &key, &stored)?;
+                if prune {
+                    self::prune(&conn)?;
+                }
+                Ok(())
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))
+            .and_then(|result: anyhow::Result<()>| result);
+            if let Err(what) = saved {
+                warn!("ESI cache store of {url} failed: {what}");
+            }
+        }
+        self.remember(url, entry);
+    }
+
+    fn remember(&self, url: &str, entry: CachedResponse) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(url, entry, self.capacity);
+        }
+    }
+
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn revalidated(&self) {
+        self.revalidated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let revalidated = self.revalidated.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let total = hits + revalidated + misses;
+        CacheStats {
+            hits,
+            revalidated,
+            misses,
+            entries: self
+                .entries
+                .lock()
+                .map(|entries| entries.by_url.len())
+                .unwrap_or(0),
+            hit_rate: if total > 0 {
+                (hits + revalidated) as f64 / total as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS esi_cache(
+            url TEXT NOT NULL PRIMARY KEY,
+            etag TEXT,
+            expires TEXT NOT NULL,
+            body TEXT NOT NULL
+        );",
+    )?;
+    prune(&conn)?;
+    Ok(conn)
+}
+
+/// Drops the answers that can not be served or revalidated any more.
+fn prune(conn: &Connection) -> anyhow::Result<usize> {
+    let now = Utc::now();
+    Ok(conn.execute(
+        "DELETE FROM esi_cache WHERE expires < ?1 AND (etag IS NULL OR expires < ?2)",
+        params![now.to_rfc3339(), (now - REVALIDATE_WINDOW).to_rfc3339()],
+    )?)
+}
+
+fn select(conn: &Connection, url: &str) -> anyhow::Result<Option<CachedResponse>> {
+    let row = conn
+        .query_row(
+            "SELECT etag, expires, body FROM esi_cache WHERE url = ?",
+            [url],
+            |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+    let Some((etag, expires, body)) = row else {
+        return Ok(None);
+    };
+    Ok(Some(CachedResponse {
+        etag,
+        expires: DateTime::parse_from_rfc3339(&expires)?.with_timezone(&Utc),
+        body,
+    }))
+}
+
+fn upsert(conn: &Connection, url: &str, entry: &CachedResponse) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO esi_cache(url, etag, expires, body) VALUES (?, ?, ?, ?)",
+        params![url, entry.etag, entry.expires.to_rfc3339(), entry.body],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::Duration;
+
+    fn entry(body: &str, expires_in: i64) -> CachedResponse {
+        CachedResponse {
+            etag: None,
+            expires: Utc::now() + Duration::seconds(expires_in),
+            body: body.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_what_expires_first_when_full() {
+        let cache = ResponseCache::new(2, None);
+        cache.store("a", entry("a", 60)).await;
+        cache.store("b", entry("b", 10)).await;
+        cache.store("a", entry("a", 90)).await;
+        cache.store("c", entry("c", 30)).await;
+        assert!(cache.lookup("b").await.is_none());
+        assert_eq!(cache.lookup("a").await.unwrap().body, "a");
+        assert!(cache.lookup("c").await.unwrap().is_fresh(Utc::now()));
+        assert_eq!(cache.stats().entries, 2);
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.by_expiry.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn outlives_the_process_in_a_file() {
+        let path = testing::temp_path("esi-cache.db");
+        let stored = CachedResponse {
+            etag: Some(String::from("\"abc\"")),
+            ..entry("{}", -10)
+        };
+        let cache = ResponseCache::new(10, Some(&path));
+        cache.store("url", stored.clone()).await;
+        cache.store("gone", entry("{}", -10)).await;
+        let long_gone = CachedResponse {
+            etag: Some(String::from("\"old\"")),
+            ..entry("{}", -2 * 24 * 3600)
+        };
+        cache.store("long gone", long_gone).await;
+
+        let cache = ResponseCache::new(10, Some(&path));
+        let found = cache.lookup("url").await.unwrap();
+        assert_eq!(found.etag, stored.etag);
+        assert_eq!(found.body, stored.body);
+        assert!(!found.is_fresh(Utc::now()));
+        assert!(cache.lookup("gone").await.is_none());
+        assert!(cache.lookup("long gone").await.is_none());
+        assert!(ResponseCache::new(0, Some(&path))
+            .lookup("url")
+            .await
+            .is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hit_rate_counts_revalidated_answers() {
+        let cache = ResponseCache::new(10, None);
+        cache.hit();
+        cache.revalidated();
+        cache.miss();
+        cache.miss();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.revalidated, stats.misses), (1, 1, 2));
+        assert_eq!(stats.hit_rate, 0.5);
+    }
+}
@@ -16,13 +16,13 @@ pub struct Character {
 }
 impl Character {
     pub async fn from(id: i32) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/characters/{id}/?{EVE_TECH_SERVER}");
+        Self::from_esi(esi(), id).await
+    }
+
+    pub async fn from_esi(esi: &Esi, id: i32) -> anyhow::Result<Self> {
+        let url = esi.url(&format!("/characters/{id}/"));
         info!("{url}");
-        reqwest::get(&url)
-            .await?
-            .json::<Self>()
-            .await
-            .map_err(|e| anyhow!(e))
+        esi.get::<Self>(&url).await
     }
 }
 
@@ -0,0 +1,513 @@
+use super::cache::{CacheStats, CachedResponse, ResponseCache};
+use super::*;
+use chrono::{DateTime, Utc};
+use log::warn;
+use reqwest::header::{HeaderMap, ETAG, EXPIRES, IF_NONE_MATCH};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, Duration, Instant};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/// ESI calls in flight at once, over the whole process
+pub const DEFAULT_PARALLEL: usize = 16;
+pub const DEFAULT_ATTEMPTS: u32 = 4;
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(2);
+/// A call with its answer takes no longer, or it is retried
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// ESI answers kept in memory
+pub const DEFAULT_CACHE_ENTRIES: usize = 10000;
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Calls pause once fewer errors than this are left in the ESI error window
+const ERROR_LIMIT_RESERVE: i64 = 10;
+/// The error window when ESI does not tell its reset
+const DEFAULT_ERROR_RESET: Duration = Duration::from_secs(60);
+/// Not a standard status, ESI answers it when the error limit is exceeded
+const ERROR_LIMITED: u16 = 420;
+
+#[derive(Debug, Clone)]
+pub struct EsiConfig {
+    /// ESI root, the urls of the calls start with it
+    pub root: String,
+    /// `tranquility`, or `singularity` for the test server
+    pub datasource: String,
+    pub user_agent: String,
+    pub parallel: usize,
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub timeout: Duration,
+    /// Zero disables the cache
+    pub cache_entries: usize,
+    /// SQLite file keeping the cached answers over restarts
+    pub cache_file: Option<PathBuf>,
+}
+impl EsiConfig {
+    /// ZKBINFO_ESI_ROOT, ZKBINFO_ESI_DATASOURCE, ZKBINFO_USER_AGENT, ZKBINFO_ESI_PARALLEL,
+    /// ZKBINFO_ESI_ATTEMPTS, ZKBINFO_ESI_TIMEOUT (seconds), ZKBINFO_ESI_CACHE_ENTRIES
+    /// and ZKBINFO_ESI_CACHE.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            root: env::var("ZKBINFO_ESI_ROOT").unwrap_or(default.root),
+            datasource: env::var("ZKBINFO_ESI_DATASOURCE").unwrap_or(default.datasource),
+            user_agent: env::var("ZKBINFO_USER_AGENT").unwrap_or(default.user_agent),
+            parallel: env::var("ZKBINFO_ESI_PARALLEL")
+                .unwrap_or_default()
+                .parse::<usize>()
+                .unwrap_or(default.parallel),
+            attempts: env::var("ZKBINFO_ESI_ATTEMPTS")
+                .unwrap_or_default()
+                .parse::<u32>()
+                .unwrap_or(default.attempts),
+            backoff: default.backoff,
+            timeout: env::var("ZKBINFO_ESI_TIMEOUT")
+                .unwrap_or_default()
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(default.timeout),
+            cache_entries: env::var("ZKBINFO_ESI_CACHE_ENTRIES")
+                .unwrap_or_default()
+                .parse::<usize>()
+                .unwrap_or(default.cache_entries),
+            cache_file: env::var("ZKBINFO_ESI_CACHE")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+        }
+    }
+}
+impl Default for EsiConfig {
+    fn default() -> Self {
+        Self {
+            root: String::from(EVE_TECH_ROOT),
+            datasource: String::from(EVE_TECH_DATASOURCE),
+            user_agent: format!("zkbinfo/{}", env!("CARGO_PKG_VERSION")),
+            parallel: DEFAULT_PARALLEL,
+            attempts: DEFAULT_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+            timeout: DEFAULT_TIMEOUT,
+            cache_entries: DEFAULT_CACHE_ENTRIES,
+            cache_file: None,
+        }
+    }
+}
+
+thread_local! {
+    // A pooled connection is driven by the runtime that opened it, so every
+    // runtime thread keeps its own clients, one per user agent and timeout
+    static CLIENTS: RefCell<HashMap<(String, Duration), reqwest::Client>> =
+        RefCell::new(HashMap::new());
+}
+
+/// What ESI answered, read whole.
+struct Answer {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+/// The ESI calls of the process: limited in number, paused before the ESI
+/// error limit runs out, retried when ESI asks for it. Answers are served
+/// from the cache until they expire and revalidated by their ETag after.
+pub struct Esi {
+    config: EsiConfig,
+    permits: Semaphore,
+    /// No calls before this
+    paused_until: Mutex<Option<Instant>>,
+    cache: ResponseCache,
+}
+impl Esi {
+    pub fn new(config: EsiConfig) -> Self {
+        Self {
+            permits: Semaphore::new(config.parallel.max(1)),
+            paused_until: Mutex::new(None),
+            cache: ResponseCache::new(config.cache_entries, config.cache_file.as_deref()),
+            config,
+        }
+    }
+
+    /// The url of an ESI path, e.g. `/characters/{id}/`, on the configured datasource.
+    pub fn url(&self, path: &str) -> String {
+        format!(
+            "{}{path}?datasource={}",
+            self.config.root.trim_end_matches('/'),
+            self.config.datasource
+        )
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, url: &str) -> anyhow::Result<T> {
+        let body = self.cached(url).await?;
+        serde_json::from_str::<T>(&body).map_err(|e| anyhow!(e))
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    async fn cached(&self, url: &str) -> anyhow::Result<String> {
+        if !cacheable(url) {
+            return Ok(self.send(|client| client.get(url)).await?.body);
+        }
+        let cached = self.cache.lookup(url).await;
+        if let Some(entry) = cached.as_ref().filter(|entry| entry.is_fresh(Utc::now())) {
+            self.cache.hit();
+            return Ok(entry.body.clone());
+        }
+        let etag = cached.as_ref().and_then(|entry| entry.etag.clone());
+        let answer = self
+            .send(|client| match &etag {
+                Some(etag) => client.get(url).header(IF_NONE_MATCH, etag),
+                None => client.get(url),
+            })
+            .await?;
+        let expires = expires(&answer.headers);
+        if let (StatusCode::NOT_MODIFIED, Some(mut entry)) = (answer.status, cached) {
+            self.cache.revalidated();
+            entry.expires = expires;
+            self.cache.store(url, entry.clone()).await;
+            return Ok(entry.body);
+        }
+        self.cache.miss();
+        let etag = answer
+            .headers
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let body = answer.body;
+        if etag.is_some() || expires > Utc::now() {
+            let entry = CachedResponse {
+                etag,
+                expires,
+                body: body.clone(),
+            };
+            self.cache.store(url, entry).await;
+        }
+        Ok(body)
+    }
+
+    pub async fn post<B, T>(&self, url: &str, body: &B) -> anyhow::Result<T>
+    where
+        B: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let answer = self.send(|client| client.post(url).json(body)).await?;
+        serde_json::from_str::<T>(&answer.body).map_err(|e| anyhow!(e))
+    }
+
+    fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let key = (self.config.user_agent.clone(), self.config.timeout);
+        CLIENTS.with(|clients| {
+            if let Some(client) = clients.borrow().get(&key) {
+                return Ok(client.clone());
+            }
+            let client = reqwest::Client::builder()
+                .user_agent(&self.config.user_agent)
+                .timeout(self.config.timeout)
+                .connect_timeout(CONNECT_TIMEOUT.min(self.config.timeout))
+                .build()?;
+            clients.borrow_mut().insert(key, client.clone());
+            Ok(client)
+        })
+    }
+
+    /// Timeouts and broken answers are retried like server errors.
+    async fn send<F>(&self, request: F) -> anyhow::Result<Answer>
+    where
+        F: Fn(&reqwest::Client) -> RequestBuilder,
+    {
+        let client = self.client()?;
+        let mut attempt = 1;
+        loop {
+            self.wait().await;
+            let request = request(&client).build()?;
+            let url = request.url().to_string();
+            let result = {
+                let _permit = self.permits.acquire().await?;
+                match client.execute(request).await {
+                    Ok(response) => {
+                        let status = response.status();
+                        let headers = response.headers().clone();
+                        response.text().await.map(|body| Answer {
+                            status,
+                            headers,
+                            body,
+                        })
+                    }
+                    Err(what) => Err(what),
+                }
+            };
+            let (delay, what) = match result {
+                Ok(answer) => {
+                    let status = answer.status;
+                    self.track(status, &answer.headers);
+                    match status {
+                        status if status.is_success() || status == StatusCode::NOT_MODIFIED => {
+                            return Ok(answer)
+                        }
+                        StatusCode::TOO_MANY_REQUESTS => (
+                            retry_after(&answer.headers).unwrap_or(self.backoff(attempt)),
+                            status.to_string(),
+                        ),
+                        status if status.as_u16() == ERROR_LIMITED => {
+                            (Duration::ZERO, String::from("error limited"))
+                        }
+                        status if status.is_server_error() => {
+                            (self.backoff(attempt), status.to_string())
+                        }
+                        status => return Err(anyhow!("{url}: {status}")),
+                    }
+                }
+                Err(what) => (self.backoff(attempt), what.to_string()),
+            };
+            if attempt >= self.config.attempts {
+                return Err(anyhow!("{url}: {what}, gave up after {attempt} attempts"));
+            }
+            warn!(
+                "{url}: {what}, attempt {attempt} of {}",
+                self.config.attempts
+            );
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        (self.config.backoff * 2u32.saturating_pow(attempt - 1)).min(MAX_BACKOFF)
+    }
+
+    /// Pauses every call until the error window resets when it is about to run out.
+    fn track(&self, status: StatusCode, headers: &HeaderMap) {
+        let number = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<i64>().ok())
+        };
+        let remain = number("X-ESI-Error-Limit-Remain");
+        let reset = number("X-ESI-Error-Limit-Reset")
+            .map(|secs| Duration::from_secs(secs.max(0) as u64))
+            .unwrap_or(DEFAULT_ERROR_RESET);
+        let limited = status.as_u16() == ERROR_LIMITED;
+        if !limited && remain.map(|n| n >= ERROR_LIMIT_RESERVE).unwrap_or(true) {
+            return;
+        }
+        warn!("ESI error limit: {remain:?} errors left, calls paused for {reset:?}");
+        if let Ok(mut paused_until) = self.paused_until.lock() {
+            let until = Instant::now() + reset;
+            *paused_until = Some(paused_until.map_or(until, |current| current.max(until)));
+        }
+    }
+
+    async fn wait(&self) {
+        let until = self.paused_until.lock().ok().and_then(|until| *until);
+        if let Some(until) = until {
+            sleep(until.saturating_duration_since(Instant::now())).await;
+        }
+    }
+}
+
+/// Killmails never change and are fetched once, they would only crowd out the rest.
+fn cacheable(url: &str) -> bool {
+    !url.contains("/killmails/")
+}
+
+/// Answers without a valid Expires are stale at once.
+fn expires(headers: &HeaderMap) -> DateTime<Utc> {
+    headers
+        .get(EXPIRES)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .map(|expires| expires.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now)
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get("Retry-After")?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+static ESI: OnceLock<Esi> = OnceLock::new();
+
+/// The ESI client shared by every call of the process.
+pub fn esi() -> &'static Esi {
+    ESI.get_or_init(|| Esi::new(config()))
+}
+
+#[cfg(not(test))]
+fn config() -> EsiConfig {
+    EsiConfig::from_env()
+}
+
+/// The tests of the crate talk to the recorded ESI
+#[cfg(test)]
+fn config() -> EsiConfig {
+    super::mock::config()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Stand-in for ESI, answers the connections in turn and keeps the request heads.
+    async fn serve(
+        answers: Vec<(&'static str, &'static str)>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/latest/", listener.local_addr().unwrap());
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let requests = served.clone();
+        tokio::spawn(async move {
+            for (status, headers) in answers {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut socket = BufReader::new(socket);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    socket.read_line(&mut line).await.unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line.to_lowercase());
+                }
+                if status.is_empty() {
+                    // No answer, the client has to give up on it
+                    requests.lock().unwrap().push(head);
+                    tokio::spawn(async move {
+                        sleep(Duration::from_secs(5)).await;
+                        drop(socket);
+                    });
+                    continue;
+                }
+                let body = if status.starts_with("304") {
+                    ""
+                } else {
+                    r#"{"id":1}"#
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\n{headers}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requests.lock().unwrap().push(head);
+            }
+        });
+        (url, served)
+    }
+
+    fn esi() -> Esi {
+        Esi::new(EsiConfig {
+            backoff: Duration::from_millis(10),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn retries_what_esi_asks_to_retry() {
+        let (url, served) = serve(vec![
+            ("503 Service Unavailable", ""),
+            ("429 Too Many Requests", "Retry-After: 0\r\n"),
+            ("200 OK", ""),
+            ("404 Not Found", ""),
+        ])
+        .await;
+        let value = esi().get::<serde_json::Value>(&url).await.unwrap();
+        assert_eq!(value["id"], 1);
+        assert_eq!(served.lock().unwrap().len(), 3);
+        assert!(esi().get::<serde_json::Value>(&url).await.is_err());
+        assert_eq!(served.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn retries_calls_that_time_out() {
+        let (url, served) = serve(vec![("", ""), ("200 OK", "")]).await;
+        let esi = Esi::new(EsiConfig {
+            backoff: Duration::from_millis(10),
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let value = esi.get::<serde_json::Value>(&url).await.unwrap();
+        assert_eq!(value["id"], 1);
+        assert_eq!(served.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn every_instance_calls_with_its_own_config() {
+        let (url, served) = serve(vec![("200 OK", ""), ("200 OK", "")]).await;
+        for agent in ["first-agent", "second-agent"] {
+            let esi = Esi::new(EsiConfig {
+                user_agent: String::from(agent),
+                ..Default::default()
+            });
+            esi.get::<serde_json::Value>(&url).await.unwrap();
+        }
+        let requests = served.lock().unwrap();
+        assert!(requests[0].contains("user-agent: first-agent"));
+        assert!(requests[1].contains("user-agent: second-agent"));
+    }
+
+    #[tokio::test]
+    async fn pauses_before_the_error_limit_runs_out() {
+        let (url, _) = serve(vec![
+            (
+                "200 OK",
+                "X-ESI-Error-Limit-Remain: 5\r\nX-ESI-Error-Limit-Reset: 1\r\n",
+            ),
+            ("200 OK", ""),
+        ])
+        .await;
+        let esi = esi();
+        esi.get::<serde_json::Value>(&url).await.unwrap();
+        let started = Instant::now();
+        esi.get::<serde_json::Value>(&url).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn serves_fresh_answers_and_revalidates_stale_ones() {
+        let (fresh, served) = serve(vec![(
+            "200 OK",
+            "Expires: Fri, 01 Jan 2100 00:00:00 GMT\r\n",
+        )])
+        .await;
+        let esi = esi();
+        esi.get::<serde_json::Value>(&fresh).await.unwrap();
+        let value = esi.get::<serde_json::Value>(&fresh).await.unwrap();
+        assert_eq!(value["id"], 1);
+        assert_eq!(served.lock().unwrap().len(), 1);
+
+        let (stale, served) = serve(vec![
+            (
+                "200 OK",
+                "ETag: \"abc\"\r\nExpires: Thu, 01 Jan 2015 00:00:00 GMT\r\n",
+            ),
+            ("304 Not Modified", ""),
+        ])
+        .await;
+        esi.get::<serde_json::Value>(&stale).await.unwrap();
+        let value = esi.get::<serde_json::Value>(&stale).await.unwrap();
+        assert_eq!(value["id"], 1);
+        let requests = served.lock().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"abc\""));
+
+        let stats = esi.cache_stats();
+        assert_eq!((stats.hits, stats.revalidated, stats.misses), (1, 1, 2));
+        assert_eq!(stats.entries, 2);
+        assert!(!cacheable(&esi.url("/killmails/1/abc/")));
+        assert!(cacheable(&esi.url("/characters/1/")));
+    }
+}
@@ -19,13 +19,13 @@ pub struct Corporation {
 }
 impl Corporation {
     pub async fn from(id: i32) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/corporations/{id}/?{EVE_TECH_SERVER}");
+        Self::from_esi(esi(), id).await
+    }
+
+    pub async fn from_esi(esi: &Esi, id: i32) -> anyhow::Result<Self> {
+        let url = esi.url(&format!("/corporations/{id}/"));
         info!("{url}");
-        reqwest::get(&url)
-            .await?
-            .json::<Self>()
-            .await
-            .map_err(|e| anyhow!(e))
+        esi.get::<Self>(&url).await
     }
 }
 
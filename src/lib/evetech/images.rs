@@ -9,13 +9,13 @@ pub struct CharacterPortrait {
 }
 impl CharacterPortrait {
     pub async fn from(id: i32) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/characters/{id}/portrait/?{EVE_TECH_SERVER}");
+        Self::from_esi(esi(), id).await
+    }
+
+    pub async fn from_esi(esi: &Esi, id: i32) -> anyhow::Result<Self> {
+        let url = esi.url(&format!("/characters/{id}/portrait/"));
         info!("{url}");
-        reqwest::get(&url)
-            .await?
-            .json::<Self>()
-            .await
-            .map_err(|e| anyhow!(e))
+        esi.get::<Self>(&url).await
     }
 }
 
@@ -27,13 +27,13 @@ pub struct CorporationIcon {
 }
 impl CorporationIcon {
     pub async fn from(id: i32) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/corporations/{id}/icons/?{EVE_TECH_SERVER}");
+        Self::from_esi(esi(), id).await
+    }
+
+    pub async fn from_esi(esi: &Esi, id: i32) -> anyhow::Result<Self> {
+        let url = esi.url(&format!("/corporations/{id}/icons/"));
         info!("{url}");
-        reqwest::get(&url)
-            .await?
-            .json::<Self>()
-            .await
-            .map_err(|e| anyhow!(e))
+        esi.get::<Self>(&url).await
     }
 }
 
@@ -44,13 +44,13 @@ pub struct AllianceIcon {
 }
 impl AllianceIcon {
     pub async fn from(id: i32) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/alliances/{id}/icons/?{EVE_TECH_SERVER}");
+        Self::from_esi(esi(), id).await
+    }
+
+    pub async fn from_esi(esi: &Esi, id: i32) -> anyhow::Result<Self> {
+        let url = esi.url(&format!("/alliances/{id}/icons/"));
         info!("{url}");
-        reqwest::get(&url)
-            .await?
-            .json::<Self>()
-            .await
-            .map_err(|e| anyhow!(e))
+        esi.get::<Self>(&url).await
     }
 }
 
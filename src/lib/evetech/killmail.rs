@@ -10,6 +10,18 @@ pub struct Killmail {
     pub zkb: Option<Zkb>,
 }
 
+impl Killmail {
+    pub async fn from(id: i32, hash: &str) -> anyhow::Result<Self> {
+        Self::from_esi(esi(), id, hash).await
+    }
+
+    pub async fn from_esi(esi: &Esi, id: i32, hash: &str) -> anyhow::Result<Self> {
+        let url = esi.url(&format!("/killmails/{id}/{hash}/"));
+        info!("{url}");
+        esi.get::<Self>(&url).await
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct Attackers {
     pub alliance_id: Option<i32>,
@@ -38,6 +50,19 @@ pub struct Zkb {
 mod tests_killmail {
     use super::*;
 
+    #[tokio::test]
+    async fn from() -> Result<(), String> {
+        let killmail = Killmail::from(97318112, "9377f28e34eabc18162e57e7e85f7a15c9339604")
+            .await
+            .map_err(|e| format!("{e}"))?;
+
+        assert_eq!(killmail.killmail_time, "2021-12-12T15:46:42Z");
+        assert_eq!(killmail.attackers[0].damage_done, 8076);
+        assert_eq!(killmail.victim.ship_type_id, Some(47466));
+        assert!(killmail.zkb.is_none());
+        Ok(())
+    }
+
     #[test]
     fn test_killmail_deserialize() {
         let json = r#"
@@ -108,4 +133,3 @@ mod tests_killmail {
         );
     }
 }
-
@@ -0,0 +1,129 @@
+use super::*;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Duration;
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// A recorded ESI call, the answer is given when method, path and request body match.
+#[derive(Debug, Deserialize)]
+struct Exchange {
+    method: String,
+    path: String,
+    request: Option<Value>,
+    response: Value,
+}
+
+fn fixtures() -> Vec<Exchange> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/esi");
+    fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let json = fs::read_to_string(&path).unwrap();
+            serde_json::from_str::<Exchange>(&json)
+                .unwrap_or_else(|what| panic!("{}: {what}", path.display()))
+        })
+        .collect()
+}
+
+static ROOT: OnceLock<String> = OnceLock::new();
+
+/// Root of the recorded ESI, started on first use. It runs on a thread of
+/// its own, as every test runtime is gone when its test ends.
+pub fn root() -> &'static str {
+    ROOT.get_or_init(|| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let root = format!("http://{}/latest", listener.local_addr().unwrap());
+        let exchanges = Arc::new(fixtures());
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                listener.set_nonblocking(true).unwrap();
+                let listener = TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (socket, _) = listener.accept().await.unwrap();
+                    let exchanges = exchanges.clone();
+                    tokio::spawn(async move { answer(socket, &exchanges).await });
+                }
+            })
+        });
+        root
+    })
+}
+
+pub fn config() -> EsiConfig {
+    EsiConfig {
+        root: root().to_string(),
+        backoff: Duration::from_millis(10),
+        ..Default::default()
+    }
+}
+
+async fn answer(socket: TcpStream, exchanges: &[Exchange]) -> std::io::Result<()> {
+    let mut socket = BufReader::new(socket);
+    let mut request_line = String::new();
+    socket.read_line(&mut request_line).await?;
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        socket.read_line(&mut line).await?;
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    socket.read_exact(&mut body).await?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = path.strip_prefix("/latest").unwrap_or(path);
+    let request = serde_json::from_slice::<Value>(&body).ok();
+    let found = exchanges.iter().find(|exchange| {
+        exchange.method == method && exchange.path == path && exchange.request == request
+    });
+    let (status, body) = match found {
+        Some(exchange) if query.contains("datasource=") => {
+            ("200 OK", exchange.response.to_string())
+        }
+        _ => ("404 Not Found", String::from(r#"{"error":"Not found"}"#)),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    socket.write_all(response.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn injected_client_on_another_datasource() {
+        let esi = Esi::new(EsiConfig {
+            datasource: String::from("singularity"),
+            ..config()
+        });
+        assert_eq!(
+            esi.url("/characters/2114350216/"),
+            format!("{}/characters/2114350216/?datasource=singularity", root())
+        );
+        let character = Character::from_esi(&esi, 2114350216).await.unwrap();
+        assert_eq!(&character.name, "Seb Odessa");
+        assert!(Character::from_esi(&esi, 1).await.is_err());
+    }
+}
@@ -3,9 +3,10 @@ use log::info;
 use serde::{Deserialize, Serialize};
 
 pub const EVE_TECH_ROOT: &str = "https://esi.evetech.net/latest";
-pub const EVE_TECH_SERVER: &str = "datasource=tranquility";
+pub const EVE_TECH_DATASOURCE: &str = "tranquility";
 pub const EVE_TECH_SEARCH: &str = "language=en&strict=true";
 
+mod affiliation;
 mod alliance;
 mod character;
 mod corporation;
@@ -13,15 +14,23 @@ mod killmail;
 mod images;
 mod search;
 mod names;
+mod cache;
+mod client;
+#[cfg(test)]
+mod mock;
 
+pub use affiliation::{Affiliation, AFFILIATION_CHUNK};
 pub use alliance::Alliance;
 pub use character::Character;
 pub use corporation::Corporation;
 pub use killmail::Killmail;
+pub use killmail::{Attackers, Victim, Zkb};
 pub use images::CharacterPortrait;
 pub use images::CorporationIcon;
 pub use images::AllianceIcon;
 
 pub use search::SearchCategory;
-pub use search::SearchResult;
-pub use names::Names;
+pub use search::{SearchResult, IDS_CHUNK};
+pub use names::{Names, NAMES_CHUNK};
+pub use cache::CacheStats;
+pub use client::{esi, Esi, EsiConfig};
@@ -1,6 +1,10 @@
 use super::*;
+use futures::future::try_join_all;
 use std::collections::HashMap;
 
+/// Ids ESI names in one call
+pub const NAMES_CHUNK: usize = 1000;
+
 #[derive(Debug, Deserialize, Serialize)]
 struct RawName {
     category: String,
@@ -13,25 +17,25 @@ pub struct Names {
     pub values: HashMap<SearchCategory, HashMap<i32, String>>,
 }
 impl Names {
-    pub async fn from(ids: &Vec<i32>) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/universe/names/?{EVE_TECH_SERVER}");
+    pub async fn from(ids: &[i32]) -> anyhow::Result<Self> {
+        Self::from_esi(esi(), ids).await
+    }
+
+    pub async fn from_esi(esi: &Esi, ids: &[i32]) -> anyhow::Result<Self> {
+        let url = esi.url("/universe/names/");
         info!("{url}");
 
-        let mut unique = ids.clone();
+        let mut unique = ids.to_vec();
         unique.sort();
         unique.dedup();
 
-        let client = reqwest::Client::new();
-        let raw_names = client
-            .post(&url)
-            .json(&unique)
-            .send()
-            .await?
-            .json::<Vec<RawName>>()
-            .await?;
+        let chunks = unique
+            .chunks(NAMES_CHUNK)
+            .map(|chunk| esi.post::<_, Vec<RawName>>(&url, chunk));
+        let raw_names = try_join_all(chunks).await?;
         let mut values = HashMap::new();
 
-        for raw in raw_names.into_iter() {
+        for raw in raw_names.into_iter().flatten() {
             let category = SearchCategory::from(&raw.category).ok_or(anyhow!("Not a Category"))?;
             values
                 .entry(category)
@@ -50,7 +54,7 @@ impl Names {
         names
             .get(&id)
             .ok_or(anyhow!("Id {id} not found in {:?}", category))
-            .map(|name| name.clone())
+            .cloned()
     }
 }
 
@@ -60,7 +64,7 @@ mod tests {
 
     #[tokio::test]
     async fn from() -> Result<(), String> {
-        let names = Names::from(&vec![2114350216, 3756])
+        let names = Names::from(&[2114350216, 3756])
             .await
             .map_err(|e| format!("{e}"))?;
 
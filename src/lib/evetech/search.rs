@@ -1,4 +1,8 @@
 use super::*;
+use futures::future::try_join_all;
+
+/// Names ESI resolves in one call
+pub const IDS_CHUNK: usize = 500;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Hash)]
 pub enum SearchCategory {
@@ -51,7 +55,7 @@ pub struct EveItem {
     pub name: String
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Default)]
 pub struct SearchResult {
     pub agents: Option<Vec<EveItem>>,
     pub alliances: Option<Vec<EveItem>>,
@@ -66,18 +70,69 @@ pub struct SearchResult {
 }
 impl SearchResult {
     pub async fn from(name: String) -> anyhow::Result<Self> {
-        let url = format!("{EVE_TECH_ROOT}/universe/ids/?{EVE_TECH_SERVER}");
+        Self::from_esi(esi(), name).await
+    }
+
+    pub async fn from_esi(esi: &Esi, name: String) -> anyhow::Result<Self> {
+        let url = esi.url("/universe/ids/");
         info!("{url}");
         let query = vec!(name.clone());
-        reqwest::Client::new()
-            .post(&url)
-            .json(&query)
-            .send()
-            .await?
-            .json::<Self>()
-            .await
-            .map_err(|e| anyhow!(e))
+        esi.post::<_, Self>(&url, &query).await
+    }
+
+    /// Resolves all the names at once, in as few calls as ESI allows.
+    pub async fn from_names(names: &[String]) -> anyhow::Result<Self> {
+        Self::from_names_esi(esi(), names).await
+    }
+
+    pub async fn from_names_esi(esi: &Esi, names: &[String]) -> anyhow::Result<Self> {
+        let url = esi.url("/universe/ids/");
+        let mut unique = names.to_vec();
+        unique.sort();
+        unique.dedup();
+        info!("{url} for {} names", unique.len());
+        let chunks = unique
+            .chunks(IDS_CHUNK)
+            .map(|chunk| esi.post::<_, Self>(&url, chunk));
+        let mut result = Self::default();
+        for chunk in try_join_all(chunks).await? {
+            result.merge(chunk);
+        }
+        Ok(result)
+    }
 
+    pub fn items(&self, category: &SearchCategory) -> &[EveItem] {
+        let items = match category {
+            SearchCategory::Agent => &self.agents,
+            SearchCategory::Alliance => &self.alliances,
+            SearchCategory::Character => &self.characters,
+            SearchCategory::Constellation => &self.constellations,
+            SearchCategory::Corporation => &self.corporations,
+            SearchCategory::Faction => &self.factions,
+            SearchCategory::InventoryType => &self.inventory_types,
+            SearchCategory::Region => &self.regions,
+            SearchCategory::SolarSystem => &self.systems,
+            SearchCategory::Station => &self.stations,
+        };
+        items.as_deref().unwrap_or_default()
+    }
+
+    fn merge(&mut self, other: Self) {
+        fn join(items: &mut Option<Vec<EveItem>>, other: Option<Vec<EveItem>>) {
+            if let Some(other) = other {
+                items.get_or_insert_with(Vec::new).extend(other);
+            }
+        }
+        join(&mut self.agents, other.agents);
+        join(&mut self.alliances, other.alliances);
+        join(&mut self.characters, other.characters);
+        join(&mut self.constellations, other.constellations);
+        join(&mut self.corporations, other.corporations);
+        join(&mut self.factions, other.factions);
+        join(&mut self.inventory_types, other.inventory_types);
+        join(&mut self.regions, other.regions);
+        join(&mut self.systems, other.systems);
+        join(&mut self.stations, other.stations);
     }
 }
 
@@ -117,6 +172,26 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn search_names_at_once() -> Result<(), String> {
+        let names = [
+            String::from("Train Wreck."),
+            String::from("Seb Odessa"),
+            String::from("Seb Odessa"),
+        ];
+        let search = SearchResult::from_names(&names)
+            .await
+            .map_err(|e| format!("{e}"))?;
+        assert_eq!(search.items(&SearchCategory::Character)[0].id, 2114350216);
+        assert_eq!(search.items(&SearchCategory::Alliance)[0].id, 99011258);
+        assert!(search.items(&SearchCategory::Corporation).is_empty());
+        let search = SearchResult::from_names(&[])
+            .await
+            .map_err(|e| format!("{e}"))?;
+        assert_eq!(search, SearchResult::default());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn search_alliance() -> Result<(), String> {
         let name = String::from("Train Wreck.");
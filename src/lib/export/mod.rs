@@ -0,0 +1,461 @@
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use parquet::basic::Compression;
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::database::QuerySubject;
+
+/// Rows of a parquet row group, the memory an export holds at most
+const ROW_GROUP: usize = 65536;
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// Participants joined with their killmails, or the aggregates of the subject.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Dataset {
+    #[default]
+    Killmails,
+    Activity,
+    Relations,
+}
+
+/// What to export, the query string of the API and the arguments of the CLI.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ExportRequest {
+    pub format: Format,
+    #[serde(default)]
+    pub dataset: Dataset,
+    /// First date, inclusive
+    pub from: Option<NaiveDate>,
+    /// Last date, inclusive
+    pub to: Option<NaiveDate>,
+    pub subject: Option<QuerySubject>,
+    /// Only the killmails the subject took part in, or the aggregates of the subject
+    pub id: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Int,
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(Option<i64>),
+    Text(Option<String>),
+}
+impl Value {
+    fn from(value: ValueRef) -> Self {
+        match value {
+            ValueRef::Integer(n) => Value::Int(Some(n)),
+            ValueRef::Text(text) => Value::Text(Some(String::from_utf8_lossy(text).to_string())),
+            ValueRef::Real(x) => Value::Text(Some(x.to_string())),
+            ValueRef::Null | ValueRef::Blob(_) => Value::Int(None),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Int(n) => serde_json::json!(n),
+            Value::Text(text) => serde_json::json!(text),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        match self {
+            Value::Int(n) => n.map(|n| n.to_string()).unwrap_or_default(),
+            Value::Text(text) => text.clone().unwrap_or_default(),
+        }
+    }
+}
+
+struct Query {
+    columns: Vec<(&'static str, Kind)>,
+    sql: String,
+}
+
+fn query(request: &ExportRequest) -> Query {
+    let mut filter = vec![String::from("1 = 1")];
+    if let Some(from) = request.from {
+        filter.push(format!("K.killmail_time >= '{}'", from.format("%Y-%m-%d")));
+    }
+    if let Some(to) = request.to.and_then(|to| to.succ_opt()) {
+        filter.push(format!("K.killmail_time < '{}'", to.format("%Y-%m-%d")));
+    }
+    let subject = request.subject.unwrap_or(QuerySubject::Character);
+    let field = QuerySubject::get_field(&subject);
+    if let Some(id) = request.id {
+        filter.push(match request.dataset {
+            Dataset::Killmails => format!(
+                "K.killmail_id IN (SELECT killmail_id FROM participants WHERE {field} = {id})"
+            ),
+            Dataset::Activity => format!("P.{field} = {id}"),
+            Dataset::Relations => format!("A.{field} = {id}"),
+        });
+    }
+    let filter = filter.join(" AND ");
+    match request.dataset {
+        Dataset::Killmails => Query {
+            columns: vec![
+                ("killmail_id", Kind::Int),
+                ("killmail_time", Kind::Text),
+                ("solar_system_id", Kind::Int),
+                ("hash", Kind::Text),
+                ("character_id", Kind::Int),
+                ("corporation_id", Kind::Int),
+                ("alliance_id", Kind::Int),
+                ("ship_type_id", Kind::Int),
+                ("damage", Kind::Int),
+                ("is_victim", Kind::Int),
+            ],
+            sql: format!(
+                "SELECT K.killmail_id, killmail_time, solar_system_id, hash,
+                        character_id, corporation_id, alliance_id, ship_type_id, damage, is_victim
+                 FROM participants P JOIN killmails K ON K.killmail_id = P.killmail_id
+                 WHERE {filter}
+                 ORDER BY K.killmail_id, is_victim DESC;"
+            ),
+        },
+        Dataset::Activity => Query {
+            columns: vec![
+                ("id", Kind::Int),
+                ("wins", Kind::Int),
+                ("losses", Kind::Int),
+            ],
+            sql: format!(
+                "SELECT P.{field},
+                        COUNT(DISTINCT CASE WHEN is_victim = 0 THEN K.killmail_id END),
+                        COUNT(DISTINCT CASE WHEN is_victim = 1 THEN K.killmail_id END)
+                 FROM participants P JOIN killmails K ON K.killmail_id = P.killmail_id
+                 WHERE {filter} AND P.{field} IS NOT NULL
+                 GROUP BY 1
+                 ORDER BY 1;"
+            ),
+        },
+        Dataset::Relations => Query {
+            columns: vec![
+                ("id", Kind::Int),
+                ("other_id", Kind::Int),
+                ("relation", Kind::Text),
+                ("killmails", Kind::Int),
+            ],
+            // As the API reports them, friends attacked with the subject, enemies killed it
+            sql: format!(
+                "SELECT A.{field}, B.{field},
+                        CASE WHEN A.is_victim = 0 THEN 'friend' ELSE 'enemy' END,
+                        COUNT(DISTINCT K.killmail_id)
+                 FROM participants A
+                 JOIN participants B ON B.killmail_id = A.killmail_id
+                 JOIN killmails K ON K.killmail_id = A.killmail_id
+                 WHERE {filter} AND B.is_victim = 0
+                   AND A.{field} IS NOT NULL AND B.{field} IS NOT NULL AND A.{field} <> B.{field}
+                 GROUP BY 1, 2, 3
+                 ORDER BY 1, 2, 3;"
+            ),
+        },
+    }
+}
+
+trait RowWriter {
+    fn write(&mut self, row: Vec<Value>) -> anyhow::Result<()>;
+    fn finish(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+struct CsvWriter<W: Write> {
+    writer: csv::Writer<W>,
+}
+impl<W: Write> RowWriter for CsvWriter<W> {
+    fn write(&mut self, row: Vec<Value>) -> anyhow::Result<()> {
+        self.writer.write_record(row.iter().map(Value::to_csv))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct NdjsonWriter<W: Write> {
+    out: W,
+    columns: Vec<&'static str>,
+}
+impl<W: Write> RowWriter for NdjsonWriter<W> {
+    fn write(&mut self, row: Vec<Value>) -> anyhow::Result<()> {
+        let object = self
+            .columns
+            .iter()
+            .zip(row.iter())
+            .map(|(name, value)| (name.to_string(), value.to_json()))
+            .collect::<serde_json::Map<String, serde_json::Value>>();
+        serde_json::to_writer(&mut self.out, &object)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps a row group of columns, written once full.
+struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    columns: Vec<Vec<Value>>,
+}
+impl<W: Write + Send> ParquetWriter<W> {
+    fn new(out: W, columns: &[(&'static str, Kind)]) -> anyhow::Result<Self> {
+        let fields = columns
+            .iter()
+            .map(|(name, kind)| match kind {
+                Kind::Int => format!("OPTIONAL INT64 {name};"),
+                Kind::Text => format!("OPTIONAL BYTE_ARRAY {name} (UTF8);"),
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        let schema = Arc::new(parse_message_type(&format!(
+            "message export {{ {fields} }}"
+        ))?);
+        let props = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build(),
+        );
+        Ok(Self {
+            writer: SerializedFileWriter::new(out, schema, props)?,
+            columns: vec![Vec::new(); columns.len()],
+        })
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.columns.first().map(Vec::is_empty).unwrap_or(true) {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group()?;
+        let mut columns = self.columns.iter_mut();
+        while let Some(mut writer) = row_group.next_column()? {
+            let values = columns
+                .next()
+                .ok_or_else(|| anyhow!("Schema and columns differ"))?;
+            let levels = values
+                .iter()
+                .map(|value| match value {
+                    Value::Int(None) | Value::Text(None) => 0,
+                    _ => 1,
+                })
+                .collect::<Vec<i16>>();
+            match writer.untyped() {
+                ColumnWriter::Int64ColumnWriter(column) => {
+                    let data = values
+                        .iter()
+                        .filter_map(|value| match value {
+                            Value::Int(n) => *n,
+                            Value::Text(_) => None,
+                        })
+                        .collect::<Vec<i64>>();
+                    column.write_batch(&data, Some(&levels), None)?;
+                }
+                ColumnWriter::ByteArrayColumnWriter(column) => {
+                    let data = values
+                        .iter()
+                        .filter_map(|value| match value {
+                            Value::Text(text) => text.as_deref().map(ByteArray::from),
+                            Value::Int(_) => None,
+                        })
+                        .collect::<Vec<ByteArray>>();
+                    column.write_batch(&data, Some(&levels), None)?;
+                }
+                _ => return Err(anyhow!("Unexpected parquet column")),
+            }
+            writer.close()?;
+            values.clear();
+        }
+        row_group.close()?;
+        Ok(())
+    }
+}
+impl<W: Write + Send> RowWriter for ParquetWriter<W> {
+    fn write(&mut self, row: Vec<Value>) -> anyhow::Result<()> {
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            column.push(value);
+        }
+        if self.columns[0].len() >= ROW_GROUP {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+/// Streams the requested rows into `out`, returns how many.
+pub fn run<W: Write + Send + 'static>(
+    conn: &Connection,
+    request: &ExportRequest,
+    out: W,
+) -> anyhow::Result<usize> {
+    let query = query(request);
+    let mut writer: Box<dyn RowWriter> = match request.format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(query.columns.iter().map(|(name, _)| name))?;
+            Box::new(CsvWriter { writer })
+        }
+        Format::Ndjson => Box::new(NdjsonWriter {
+            out,
+            columns: query.columns.iter().map(|(name, _)| *name).collect(),
+        }),
+        Format::Parquet => Box::new(ParquetWriter::new(out, &query.columns)?),
+    };
+    let mut stmt = conn.prepare(&query.sql)?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let mut values = Vec::with_capacity(query.columns.len());
+        for (n, (_, kind)) in query.columns.iter().enumerate() {
+            values.push(match (Value::from(row.get_ref(n)?), kind) {
+                (Value::Int(None), Kind::Text) => Value::Text(None),
+                (value, _) => value,
+            });
+        }
+        writer.write(values)?;
+        count += 1;
+    }
+    writer.finish()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+    use crate::testing;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use std::sync::Mutex;
+
+    /// Collects what the export writes, like the response stream does.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn database() -> Connection {
+        let conn = testing::database();
+        for (id, time) in [(1, "2022-07-01T10:00:00Z"), (2, "2022-07-02T10:00:00Z")] {
+            database::insert(&conn, testing::killmail(id, time)).unwrap();
+        }
+        conn
+    }
+
+    fn request(query: &str) -> ExportRequest {
+        serde_urlencoded::from_str(query).unwrap()
+    }
+
+    fn export(conn: &Connection, query: &str) -> (usize, String) {
+        let sink = Sink::default();
+        let count = run(conn, &request(query), sink.clone()).unwrap();
+        let bytes = sink.0.lock().unwrap().clone();
+        (count, String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    #[test]
+    fn killmails_as_csv_within_dates() {
+        let conn = database();
+        let (count, csv) = export(&conn, "format=csv&from=2022-07-02&to=2022-07-02");
+        assert_eq!(count, 2);
+        let lines = csv.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[0], "killmail_id,killmail_time,solar_system_id,hash,character_id,corporation_id,alliance_id,ship_type_id,damage,is_victim");
+        assert_eq!(lines[1], "2,2022-07-02T10:00:00Z,30000142,,1,2,,587,10,1");
+        assert_eq!(lines[2], "2,2022-07-02T10:00:00Z,30000142,,3,4,,,10,0");
+    }
+
+    #[test]
+    fn aggregates_as_ndjson() {
+        let conn = database();
+        let (count, ndjson) = export(&conn, "format=ndjson&dataset=activity&subject=corporation");
+        assert_eq!(count, 2);
+        assert_eq!(
+            ndjson,
+            "{\"id\":2,\"losses\":2,\"wins\":0}\n{\"id\":4,\"losses\":0,\"wins\":2}\n"
+        );
+        let (count, ndjson) = export(&conn, "format=ndjson&dataset=activity&id=3");
+        assert_eq!(count, 1);
+        assert_eq!(ndjson, "{\"id\":3,\"losses\":0,\"wins\":2}\n");
+        let (count, ndjson) = export(&conn, "format=ndjson&dataset=relations&id=1");
+        assert_eq!(count, 1);
+        assert_eq!(
+            ndjson,
+            "{\"id\":1,\"killmails\":2,\"other_id\":3,\"relation\":\"enemy\"}\n"
+        );
+        let (count, ndjson) = export(&conn, "format=ndjson&dataset=relations&id=3");
+        assert_eq!(count, 0);
+        assert!(ndjson.is_empty());
+    }
+
+    #[test]
+    fn parquet_is_readable() {
+        let conn = database();
+        let sink = Sink::default();
+        let count = run(&conn, &request("format=parquet"), sink.clone()).unwrap();
+        assert_eq!(count, 4);
+        let bytes = actix_web::web::Bytes::from(sink.0.lock().unwrap().clone());
+        let reader = SerializedFileReader::new(bytes).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 4);
+        assert_eq!(
+            reader
+                .metadata()
+                .file_metadata()
+                .schema_descr()
+                .num_columns(),
+            10
+        );
+    }
+}
@@ -1,22 +1,26 @@
 use anyhow::anyhow;
 use chrono::NaiveDateTime;
 use futures::future::join_all;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
 mod providers;
 
+use crate::evetech::Affiliation;
 use crate::evetech::Alliance;
 use crate::evetech::AllianceIcon;
 use crate::evetech::Character;
 use crate::evetech::CharacterPortrait;
 use crate::evetech::Corporation;
 use crate::evetech::CorporationIcon;
+use crate::evetech::AFFILIATION_CHUNK;
 
 use crate::evetech::Names;
 use crate::evetech::SearchCategory;
 use crate::evetech::SearchResult;
+use crate::evetech::IDS_CHUNK;
+use crate::evetech::NAMES_CHUNK;
 
 use std::collections::HashMap;
 
@@ -99,7 +103,7 @@ impl CorporationProps {
             corporation_founded: corporation
                 .date_founded
                 .and_then(|founded| parse_date(&founded, "%Y-%m-%dT%H:%M:%SZ").ok())
-                .and_then(|date| Some(date.format("%Y-%m-%d %H:%M:%S").to_string())),
+                .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string()),
             corporation_description: None,
             // corporation.description
             //     .and_then(|desc| serde_json::from_str(&desc).ok()) + unescape unicode
@@ -142,12 +146,12 @@ impl AllianceProps {
             alliance_executor_corporation_id: alliance.executor_corporation_id,
             alliance_founded: parse_date(&alliance.date_founded, "%Y-%m-%dT%H:%M:%SZ")
                 .ok()
-                .and_then(|date| Some(date.format("%Y-%m-%d %H:%M:%S").to_string())),
+                .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string()),
         })
     }
 }
 
-fn query_all_ids(killmails: &Vec<Killmail>) -> Vec<i32> {
+fn query_all_ids(killmails: &[Killmail]) -> Vec<i32> {
     let mut ids = Vec::with_capacity(5 * killmails.len());
     for row in killmails {
         if let Some(character_id) = row.character_id {
@@ -166,7 +170,7 @@ fn query_all_ids(killmails: &Vec<Killmail>) -> Vec<i32> {
     }
     ids.sort();
     ids.dedup();
-    return ids;
+    ids
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
@@ -201,12 +205,12 @@ impl LostProps {
             .await
             .map_err(|e| anyhow!(e))?;
 
-        let names = Names::from(&vec![id, ship_id]).await?;
+        let names = Names::from(&[id, ship_id]).await?;
         let ids = query_all_ids(&killmails);
         Ok(Self {
             name: names.get_name(category, id)?,
             ship_name: names.get_name(SearchCategory::InventoryType, ship_id)?,
-            killmails: killmails,
+            killmails,
             ids: serde_json::to_string(&ids)?,
         })
     }
@@ -258,6 +262,24 @@ pub struct Activity {
 pub struct WhoProps {
     characters: Vec<WhoIsCharacter>,
 }
+/// Looks the items up chunk by chunk, as ESI rejects a whole chunk for one bad id.
+/// A failed chunk is logged and left out, the other chunks are still answered.
+async fn per_chunk<'a, T, R, F, Fut>(items: &'a [T], size: usize, lookup: F) -> Vec<R>
+where
+    F: Fn(&'a [T]) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<R>>,
+{
+    join_all(items.chunks(size).map(lookup))
+        .await
+        .into_iter()
+        .filter_map(|result| {
+            result
+                .map_err(|what| warn!("ESI lookup failed, the chunk is left out: {what}"))
+                .ok()
+        })
+        .collect()
+}
+
 impl WhoProps {
     async fn activity(id: i32) -> anyhow::Result<Activity> {
         let url = format!("http://185.87.51.139:8080/api/character/activity/{id}/");
@@ -270,31 +292,53 @@ impl WhoProps {
     }
 
     pub async fn from(data: WhoFormData) -> anyhow::Result<Self> {
-        let get_ids_tasks = data
+        let mut names = data
             .names
             .split("\r\n")
-            .map(|name| String::from(name))
+            .map(String::from)
             .filter(|name| !name.is_empty())
-            .map(|name| IdProvider::get(name, SearchCategory::Character));
-        let ids_results: Vec<_> = join_all(get_ids_tasks).await;
-        let ids: Vec<i32> = ids_results
+            .collect::<Vec<String>>();
+        // Every name once, so the chunks are as few as they can be
+        names.sort();
+        names.dedup();
+        let char_map = per_chunk(&names, IDS_CHUNK, |chunk| {
+            IdProvider::get_many(chunk, SearchCategory::Character)
+        })
+        .await
+        .into_iter()
+        .flatten()
+        .map(|(name, id)| (id, name))
+        .collect::<HashMap<i32, String>>();
+        let mut ids = char_map.keys().copied().collect::<Vec<i32>>();
+        ids.sort();
+
+        let affiliations = per_chunk(&ids, AFFILIATION_CHUNK, Affiliation::from)
+            .await
             .into_iter()
-            .map(|id| id.unwrap_or_default())
-            .filter(|id| 0 != *id)
-            .collect();
-
-        let get_chars_tasks = join_all(ids.iter().map(|id| Character::from(*id))).await;
+            .flatten()
+            .map(|affiliation| (affiliation.character_id, affiliation))
+            .collect::<HashMap<i32, Affiliation>>();
+        let mut entity_ids = affiliations
+            .values()
+            .flat_map(|affiliation| [Some(affiliation.corporation_id), affiliation.alliance_id])
+            .flatten()
+            .collect::<Vec<i32>>();
+        entity_ids.sort();
+        entity_ids.dedup();
+        let mut entity_names = Names {
+            values: HashMap::new(),
+        };
+        for names in per_chunk(&entity_ids, NAMES_CHUNK, Names::from).await {
+            for (category, values) in names.values {
+                entity_names
+                    .values
+                    .entry(category)
+                    .or_default()
+                    .extend(values);
+            }
+        }
 
         let get_activity_tasks = join_all(ids.iter().map(|id| Self::activity(*id))).await;
-        let char_map = ids
-            .iter()
-            .zip(
-                get_chars_tasks
-                    .into_iter()
-                    .map(|result| result.unwrap_or_default()),
-            )
-            .collect::<HashMap<&i32, Character>>();
-
         let activity_map = ids
             .iter()
             .zip(
@@ -304,56 +348,19 @@ impl WhoProps {
             )
             .collect::<HashMap<&i32, Activity>>();
 
-        let mut corp_ids = char_map
-            .values()
-            .map(|character| character.corporation_id)
-            .collect::<Vec<i32>>();
-        corp_ids.sort();
-        corp_ids.dedup();
-
-        let get_corps_tasks = join_all(corp_ids.iter().map(|id| Corporation::from(*id))).await;
-        let corp_map = corp_ids
-            .into_iter()
-            .zip(
-                get_corps_tasks
-                    .into_iter()
-                    .map(|result| result.unwrap_or_default()),
-            )
-            .collect::<HashMap<i32, Corporation>>();
-
-        let mut alli_ids = corp_map
-            .values()
-            .map(|corporation| corporation.alliance_id.unwrap_or_default())
-            .filter(|id| *id != 0)
-            .collect::<Vec<i32>>();
-        alli_ids.sort();
-        alli_ids.dedup();
-
-        let get_allis_tasks = join_all(alli_ids.iter().map(|id| Alliance::from(*id))).await;
-        let alli_map = alli_ids
-            .into_iter()
-            .zip(
-                get_allis_tasks
-                    .into_iter()
-                    .map(|result| result.unwrap_or_default()),
-            )
-            .collect::<HashMap<i32, Alliance>>();
-
         let mut characters = Vec::new();
-        for (id, character) in char_map {
-            if character.name.is_empty() {
+        for (id, character_name) in char_map {
+            let Some(affiliation) = affiliations.get(&id) else {
                 continue;
-            }
-            let corporation_id = character.corporation_id;
-            let corporation_name: String = corp_map
-                .get(&character.corporation_id)
-                .and_then(|corp| Some(corp.name.clone()))
+            };
+            let corporation_id = affiliation.corporation_id;
+            let corporation_name: String = entity_names
+                .get_name(SearchCategory::Corporation, corporation_id)
                 .unwrap_or_default();
 
-            let alliance_id = character.alliance_id.unwrap_or_default();
-            let alliance_name: String = alli_map
-                .get(&character.alliance_id.unwrap_or_default())
-                .and_then(|corp| Some(corp.name.clone()))
+            let alliance_id = affiliation.alliance_id.unwrap_or_default();
+            let alliance_name: String = entity_names
+                .get_name(SearchCategory::Alliance, alliance_id)
                 .unwrap_or_default();
 
             let activity: Activity = activity_map.get(&id).cloned().unwrap_or_default();
@@ -364,7 +371,7 @@ impl WhoProps {
                     100.0 * activity.wins.total_count as f32 / total_combats
                 )
             } else {
-                format!("")
+                String::new()
             };
             let losses_percent = if total_combats > 0.0 {
                 format!(
@@ -372,7 +379,7 @@ impl WhoProps {
                     100.0 * activity.losses.total_count as f32 / total_combats
                 )
             } else {
-                format!("")
+                String::new()
             };
 
             let total_damage = (activity.wins.total_damage + activity.losses.total_damage) as f32;
@@ -382,7 +389,7 @@ impl WhoProps {
                     100.0 * activity.wins.total_damage as f32 / total_damage
                 )
             } else {
-                format!("")
+                String::new()
             };
             let damage_received_percent = if total_damage > 0.0 {
                 format!(
@@ -390,24 +397,24 @@ impl WhoProps {
                     100.0 * activity.losses.total_damage as f32 / total_damage
                 )
             } else {
-                format!("")
+                String::new()
             };
 
             let character = WhoIsCharacter {
-                character_id: *id,
-                character_name: character.name,
-                corporation_id: corporation_id,
-                corporation_name: corporation_name,
-                alliance_id: alliance_id,
-                alliance_name: alliance_name,
+                character_id: id,
+                character_name,
+                corporation_id,
+                corporation_name,
+                alliance_id,
+                alliance_name,
                 wins_count: activity.wins.total_count,
                 losses_count: activity.losses.total_count,
-                wins_percent: wins_percent,
-                losses_percent: losses_percent,
+                wins_percent,
+                losses_percent,
                 damage_dealt: activity.wins.total_damage,
                 damage_received: activity.losses.total_damage,
-                damage_dealt_percent: damage_dealt_percent,
-                damage_received_percent: damage_received_percent,
+                damage_dealt_percent,
+                damage_received_percent,
             };
             characters.push(character);
         }
@@ -427,3 +434,22 @@ impl WhoProps {
         Ok(Self { characters })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_chunk_is_left_out() {
+        let ids = [1, 2, 3, 4, 5];
+        let found = per_chunk(&ids, 2, |chunk: &[i32]| async move {
+            if chunk.contains(&3) {
+                Err(anyhow!("invalid id"))
+            } else {
+                Ok(chunk.to_vec())
+            }
+        })
+        .await;
+        assert_eq!(found, vec![vec![1, 2], vec![5]]);
+    }
+}
@@ -17,24 +17,24 @@ impl IdProvider {
         match category {
             SearchCategory::Character => {
                 if let Ok(map) = CHARACTERS.lock() {
-                    return map.get(&name).and_then(|id| Some(*id));
+                    return map.get(&name).copied();
                 }
             }
             SearchCategory::Corporation => {
                 if let Ok(map) = CORPORATIONS.lock() {
-                    return map.get(&name).and_then(|id| Some(*id));
+                    return map.get(&name).copied();
                 }
             }
             SearchCategory::Alliance => {
                 if let Ok(map) = ALLIANCES.lock() {
-                    return map.get(&name).and_then(|id| Some(*id));
+                    return map.get(&name).copied();
                 }
             }
             _ => {
                 return None;
             }
         }
-        return None;
+        None
     }
 
     fn update(result: SearchResult) -> anyhow::Result<()> {
@@ -73,6 +73,33 @@ impl IdProvider {
             .ok_or(format!("Can't find id for {name}"))
             .map_err(|e| anyhow!(e))
     }
+
+    /// Names with their ids, the unknown names are resolved by one bulk search.
+    pub async fn get_many(
+        names: &[String],
+        category: SearchCategory,
+    ) -> anyhow::Result<Vec<(String, i32)>> {
+        let mut found = Vec::new();
+        let mut unknown = Vec::new();
+        for name in names {
+            match Self::find_id(name.clone(), category.clone()) {
+                Some(id) => found.push((name.clone(), id)),
+                None => unknown.push(name.clone()),
+            }
+        }
+        if !unknown.is_empty() {
+            let sr = SearchResult::from_names(&unknown).await?;
+            found.extend(
+                sr.items(&category)
+                    .iter()
+                    .map(|item| (item.name.clone(), item.id)),
+            );
+            Self::update(sr)?;
+        }
+        found.sort_by_key(|(_, id)| *id);
+        found.dedup_by_key(|(_, id)| *id);
+        Ok(found)
+    }
 }
 
 #[cfg(test)]
@@ -89,6 +116,17 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn id_provider_get_many_char_ids() -> Result<(), String> {
+        let names = [String::from("Seb Odessa"), String::from("Train Wreck.")];
+        let ids = IdProvider::get_many(&names, SearchCategory::Character)
+            .await
+            .map_err(|e| format!("{e}"))?;
+
+        assert_eq!(ids, vec![(String::from("Seb Odessa"), 2114350216)]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn id_provider_get_corp_id() -> Result<(), String> {
         let id = IdProvider::get(String::from("SO Corporation"), SearchCategory::Corporation)
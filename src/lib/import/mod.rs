@@ -0,0 +1,292 @@
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::database::{self, InsertOutcome};
+use crate::evetech::Killmail;
+
+pub const DEFAULT_BATCH: usize = 1000;
+
+/// What the importer does with a path.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    TarGz,
+    TarBz2,
+    Ndjson,
+    Json,
+    Dir,
+}
+impl Format {
+    pub fn of(path: &Path) -> Option<Self> {
+        if path.is_dir() {
+            return Some(Format::Dir);
+        }
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".tar.bz2") || name.ends_with(".tbz2") {
+            Some(Format::TarBz2)
+        } else if name.ends_with(".ndjson") || name.ends_with(".jsonl") {
+            Some(Format::Ndjson)
+        } else if name.ends_with(".json") {
+            Some(Format::Json)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportStats {
+    pub files: usize,
+    pub parsed: usize,
+    pub imported: usize,
+    pub updated: usize,
+    pub duplicates: usize,
+    pub errors: usize,
+}
+impl fmt::Display for ImportStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files, {} killmails parsed, {} imported, {} updated, {} saved before, {} errors",
+            self.files, self.parsed, self.imported, self.updated, self.duplicates, self.errors
+        )
+    }
+}
+
+/// Saves the parsed killmails in batches, one transaction per batch.
+pub struct Importer<'a> {
+    conn: &'a Connection,
+    batch_size: usize,
+    batch: Vec<Killmail>,
+    stats: ImportStats,
+}
+impl<'a> Importer<'a> {
+    pub fn new(conn: &'a Connection, batch_size: usize) -> Self {
+        Self {
+            conn,
+            batch_size: batch_size.max(1),
+            batch: Vec::new(),
+            stats: ImportStats::default(),
+        }
+    }
+
+    /// Imports a file or a directory of them, whatever format the name says.
+    pub fn import(&mut self, path: &Path) -> anyhow::Result<()> {
+        match Format::of(path) {
+            Some(Format::Dir) => {
+                let mut entries = fs::read_dir(path)?
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                entries.sort();
+                for entry in entries {
+                    if Format::of(&entry).is_some() {
+                        self.import(&entry)?;
+                    }
+                }
+            }
+            Some(Format::TarGz) => self.tar(GzDecoder::new(File::open(path)?))?,
+            Some(Format::TarBz2) => self.tar(BzDecoder::new(File::open(path)?))?,
+            Some(format) => {
+                let name = path.to_string_lossy();
+                self.read(&name, format, File::open(path)?)?;
+            }
+            None => warn!("{path:?} skipped, unknown format"),
+        }
+        Ok(())
+    }
+
+    fn tar(&mut self, reader: impl Read) -> anyhow::Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = entry.path()?.to_string_lossy().to_string();
+            match Format::of(Path::new(&name)) {
+                Some(format @ (Format::Json | Format::Ndjson))
+                    if entry.header().entry_type().is_file() =>
+                {
+                    self.read(&name, format, entry)?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, name: &str, format: Format, reader: impl Read) -> anyhow::Result<()> {
+        self.stats.files += 1;
+        if format == Format::Ndjson {
+            for (n, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    self.parse(&format!("{name}:{}", n + 1), &line)?;
+                }
+            }
+        } else {
+            let mut json = String::new();
+            BufReader::new(reader).read_to_string(&mut json)?;
+            self.parse(name, &json)?;
+        }
+        Ok(())
+    }
+
+    /// A killmail or an array of them.
+    fn parse(&mut self, name: &str, json: &str) -> anyhow::Result<()> {
+        let parsed = if json.trim_start().starts_with('[') {
+            serde_json::from_str::<Vec<Killmail>>(json)
+        } else {
+            serde_json::from_str::<Killmail>(json).map(|killmail| vec![killmail])
+        };
+        match parsed {
+            Ok(killmails) => {
+                for killmail in killmails {
+                    self.push(killmail)?;
+                }
+            }
+            Err(what) => {
+                warn!("{name}: {what}");
+                self.stats.errors += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, killmail: Killmail) -> anyhow::Result<()> {
+        self.stats.parsed += 1;
+        self.batch.push(killmail);
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for killmail in self.batch.drain(..) {
+            // Saved ones still go in, they may bring the hash or participants they missed
+            match database::insert(&tx, killmail)? {
+                InsertOutcome::Inserted => self.stats.imported += 1,
+                InsertOutcome::Updated => self.stats.updated += 1,
+                InsertOutcome::Duplicate => self.stats.duplicates += 1,
+            }
+        }
+        tx.commit()?;
+        info!("{}", self.stats);
+        Ok(())
+    }
+
+    /// Saves what is left in the batch.
+    pub fn finish(mut self) -> anyhow::Result<ImportStats> {
+        if !self.batch.is_empty() {
+            self.flush()?;
+        }
+        Ok(self.stats)
+    }
+}
+
+/// Imports the paths into the database.
+pub fn run(conn: &Connection, paths: &[&Path], batch_size: usize) -> anyhow::Result<ImportStats> {
+    let mut importer = Importer::new(conn, batch_size);
+    for path in paths {
+        info!("Importing {path:?}");
+        importer.import(path)?;
+    }
+    importer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn killmail(id: i32) -> String {
+        let killmail = testing::killmail(id, "2022-07-01T00:00:00Z");
+        serde_json::to_string(&testing::hashed(killmail, &format!("h{id}"))).unwrap()
+    }
+
+    fn tar_gz(path: &Path, files: &[(&str, String)]) {
+        let encoder = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn formats_by_name() {
+        assert_eq!(
+            Format::of(Path::new("k-2022-07-01.tar.bz2")),
+            Some(Format::TarBz2)
+        );
+        assert_eq!(Format::of(Path::new("k.tgz")), Some(Format::TarGz));
+        assert_eq!(Format::of(Path::new("k.ndjson")), Some(Format::Ndjson));
+        assert_eq!(Format::of(Path::new("1.json")), Some(Format::Json));
+        assert_eq!(Format::of(Path::new("notes.txt")), None);
+    }
+
+    #[test]
+    fn imports_archives_and_skips_saved() {
+        let dir = testing::temp_dir("import");
+        tar_gz(
+            &dir.join("killmails.tar.gz"),
+            &[
+                ("killmails/1.json", killmail(1)),
+                ("killmails/2.json", killmail(2)),
+                ("killmails/bad.json", String::from("{")),
+                ("README", String::from("not a killmail")),
+            ],
+        );
+        fs::write(
+            dir.join("day.ndjson"),
+            format!("{}\n\n{}\n", killmail(2), killmail(3)),
+        )
+        .unwrap();
+        fs::write(dir.join("4.json"), format!("[{}]", killmail(4))).unwrap();
+
+        let conn = testing::database();
+        let stats = run(&conn, &[dir.as_path()], 2).unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                files: 5,
+                parsed: 5,
+                imported: 4,
+                updated: 0,
+                duplicates: 1,
+                errors: 1,
+            }
+        );
+        let saved = database::select_by_ids(&conn, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(saved.len(), 4);
+        assert_eq!(saved[0].zkb.as_ref().unwrap().hash, "h1");
+
+        let again = run(&conn, &[dir.join("day.ndjson").as_path()], 10).unwrap();
+        assert_eq!(again.imported, 0);
+        assert_eq!(again.duplicates, 2);
+
+        database::insert(&conn, testing::killmail(5, "2022-07-01T00:00:00Z")).unwrap();
+        fs::write(dir.join("5.json"), killmail(5)).unwrap();
+        let hashed = run(&conn, &[dir.join("5.json").as_path()], 10).unwrap();
+        assert_eq!((hashed.imported, hashed.updated), (0, 1));
+        let saved = database::select_by_ids(&conn, &[5]).unwrap();
+        assert_eq!(saved[0].zkb.as_ref().unwrap().hash, "h5");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
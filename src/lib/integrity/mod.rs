@@ -0,0 +1,240 @@
+use chrono::NaiveDate;
+use log::{info, warn};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::future;
+
+use crate::backfill;
+use crate::database;
+
+/// Killmails of a date whose participants are not what ESI hands out.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct DayReport {
+    pub date: NaiveDate,
+    pub killmails: usize,
+    pub without_victim: Vec<i32>,
+    pub duplicate_victims: Vec<i32>,
+    pub without_attackers: Vec<i32>,
+    /// Killmails zkillboard has for the date, when the coverage was checked
+    pub zkillboard: Option<usize>,
+    /// Of them not saved
+    pub missing: Option<usize>,
+}
+impl DayReport {
+    /// Ids of the killmails to refetch or remove.
+    pub fn affected(&self) -> Vec<i32> {
+        let mut ids = self
+            .without_victim
+            .iter()
+            .chain(&self.duplicate_victims)
+            .chain(&self.without_attackers)
+            .copied()
+            .collect::<Vec<i32>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.affected().is_empty() && self.missing.unwrap_or(0) == 0
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct IntegrityReport {
+    /// What SQLite finds wrong with the file
+    pub file: Vec<String>,
+    /// Killmail ids of the participants without a killmail
+    pub orphaned: Vec<i32>,
+    pub days: Vec<DayReport>,
+}
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.file.is_empty()
+            && self.orphaned.is_empty()
+            && self.days.iter().all(DayReport::is_clean)
+    }
+
+    pub fn affected(&self) -> usize {
+        self.days.iter().map(|day| day.affected().len()).sum()
+    }
+}
+
+/// Checks the file and the killmails of the dates.
+pub fn check(
+    conn: &Connection,
+    first: NaiveDate,
+    last: NaiveDate,
+) -> anyhow::Result<IntegrityReport> {
+    let (first, last) = (first.min(last), first.max(last));
+    let counts = database::count_by_date(conn, &first, &last)?;
+    let mut days = backfill::dates(first, last)
+        .into_iter()
+        .map(|date| {
+            let killmails = counts.get(&date.to_string()).copied().unwrap_or(0);
+            (
+                date,
+                DayReport {
+                    date,
+                    killmails,
+                    ..Default::default()
+                },
+            )
+        })
+        .collect::<HashMap<NaiveDate, DayReport>>();
+    for (id, date, victims, attackers) in database::participant_anomalies(conn, &first, &last)? {
+        let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d")?;
+        if let Some(day) = days.get_mut(&date) {
+            if victims == 0 {
+                day.without_victim.push(id);
+            } else if victims > 1 {
+                day.duplicate_victims.push(id);
+            }
+            if attackers == 0 {
+                day.without_attackers.push(id);
+            }
+        }
+    }
+    let mut days = days.into_values().collect::<Vec<DayReport>>();
+    days.sort_by_key(|day| day.date);
+    Ok(IntegrityReport {
+        file: database::integrity_check(conn)?,
+        orphaned: database::orphaned_participants(conn)?,
+        days,
+    })
+}
+
+/// Compares the saved killmails of every date with the zkillboard history.
+pub async fn coverage(conn: &Connection, report: &mut IntegrityReport) -> anyhow::Result<()> {
+    for day in report.days.iter_mut() {
+        let history = backfill::history(&day.date).await?;
+        let saved = database::select_ids_by_date(conn, &day.date)?;
+        day.zkillboard = Some(history.len());
+        day.missing = Some(backfill::missing(history, &saved).len());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// Fetches the killmails from ESI again and saves them over
+    Refetch,
+    Remove,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RepairSummary {
+    pub orphans_removed: usize,
+    pub refetched: usize,
+    pub removed: usize,
+    pub failed: Vec<i32>,
+    /// Neither saved nor zkillboard has a hash of them
+    pub without_hash: Vec<i32>,
+}
+
+/// Repairs the killmails of the report, the orphaned participants are always removed.
+pub async fn repair(
+    conn: &Connection,
+    report: &IntegrityReport,
+    mode: Repair,
+    concurrency: usize,
+) -> anyhow::Result<RepairSummary> {
+    let mut summary = RepairSummary {
+        orphans_removed: database::delete_orphaned_participants(conn)?,
+        ..Default::default()
+    };
+    for day in report.days.iter() {
+        let affected = day.affected();
+        if affected.is_empty() {
+            continue;
+        }
+        if mode == Repair::Remove {
+            summary.removed += database::delete_killmails(conn, &affected)?;
+            info!("{}: {} killmails removed", day.date, affected.len());
+            continue;
+        }
+        let saved = database::select_hashes_by_date(conn, &day.date)?;
+        let hashes = backfill::hashes(&day.date, saved, &affected).await?;
+        for id in affected.iter().filter(|id| !hashes.contains_key(id)) {
+            warn!("killmail {id} has no hash to refetch it");
+            summary.without_hash.push(*id);
+        }
+        let refetched = backfill::refetch(
+            hashes,
+            concurrency,
+            |killmail| future::ready(database::replace(conn, killmail)),
+            |_, _| {},
+        )
+        .await;
+        info!("{}: {refetched}", day.date);
+        summary.refetched += refetched.saved;
+        summary.failed.extend(refetched.failed);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn database() -> Connection {
+        let conn = testing::database();
+        conn.execute_batch(
+            "INSERT INTO killmails VALUES (1, '2022-07-01T10:00:00Z', 30000142, 'h1');
+             INSERT INTO killmails VALUES (2, '2022-07-01T11:00:00Z', 30000142, 'h2');
+             INSERT INTO killmails VALUES (3, '2022-07-02T10:00:00Z', 30000142, NULL);
+             INSERT INTO killmails VALUES (4, '2022-07-02T11:00:00Z', 30000142, NULL);
+             INSERT INTO participants VALUES (1, 1, 2, NULL, 587, 10, 1);
+             INSERT INTO participants VALUES (1, 3, 4, NULL, 587, 10, 0);
+             INSERT INTO participants VALUES (3, NULL, 2, NULL, 35832, 10, 1);
+             INSERT INTO participants VALUES (3, NULL, 2, NULL, 35832, 10, 1);
+             INSERT INTO participants VALUES (3, 3, 4, NULL, 587, 10, 0);
+             INSERT INTO participants VALUES (4, 1, 2, NULL, 587, 10, 1);
+             INSERT INTO participants VALUES (9, 1, 2, NULL, 587, 10, 1);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2022, 7, day).unwrap()
+    }
+
+    #[test]
+    fn anomalies_by_date() {
+        let conn = database();
+        let report = check(&conn, date(2), date(1)).unwrap();
+        assert!(report.file.is_empty());
+        assert_eq!(report.orphaned, vec![9]);
+        assert_eq!(
+            report.days[0],
+            DayReport {
+                date: date(1),
+                killmails: 2,
+                without_victim: vec![2],
+                without_attackers: vec![2],
+                ..Default::default()
+            }
+        );
+        assert_eq!(report.days[1].duplicate_victims, vec![3]);
+        assert_eq!(report.days[1].without_attackers, vec![4]);
+        assert_eq!(report.days[1].affected(), vec![3, 4]);
+        assert_eq!(report.affected(), 3);
+        assert!(!report.is_clean());
+    }
+
+    #[tokio::test]
+    async fn remove_leaves_a_clean_database() {
+        let conn = database();
+        let report = check(&conn, date(1), date(2)).unwrap();
+        let summary = repair(&conn, &report, Repair::Remove, 1).await.unwrap();
+        assert_eq!(summary.orphans_removed, 1);
+        assert_eq!(summary.removed, 3);
+        let report = check(&conn, date(1), date(2)).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.days[0].killmails, 1);
+    }
+}
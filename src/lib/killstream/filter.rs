@@ -0,0 +1,132 @@
+use anyhow::anyhow;
+
+use std::collections::HashSet;
+
+use crate::evetech::Killmail;
+
+/// Channels zkillboard publishes killmails to besides the killstream.
+pub const CHANNEL_KINDS: [&str; 5] = ["alliance", "corporation", "character", "system", "region"];
+
+/// Channels from a comma separated list like `alliance:99003581,region:10000002`.
+pub fn channels(spec: &str) -> anyhow::Result<Vec<String>> {
+    let mut channels = Vec::new();
+    for channel in spec.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        if channel != super::ZKB_KILLSTREAM {
+            let (kind, _) = entry(channel)?;
+            if !CHANNEL_KINDS.contains(&kind) {
+                return Err(anyhow!("Unknown channel {channel}"));
+            }
+        }
+        channels.push(channel.to_string());
+    }
+    if channels.is_empty() {
+        return Err(anyhow!("No channel in {spec:?}"));
+    }
+    Ok(channels)
+}
+
+fn entry(item: &str) -> anyhow::Result<(&str, i32)> {
+    let (kind, id) = item
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Expected kind:id, got {item}"))?;
+    let id = id.parse::<i32>().map_err(|_| anyhow!("Bad id in {item}"))?;
+    Ok((kind, id))
+}
+
+/// Keeps the killmails that involve any of the listed entities or systems.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    alliances: HashSet<i32>,
+    corporations: HashSet<i32>,
+    characters: HashSet<i32>,
+    systems: HashSet<i32>,
+}
+impl Filter {
+    /// Same syntax as the channels, regions are left to the subscription.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut filter = Self::default();
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (kind, id) = entry(item)?;
+            let set = match kind {
+                "alliance" => &mut filter.alliances,
+                "corporation" => &mut filter.corporations,
+                "character" => &mut filter.characters,
+                "system" => &mut filter.systems,
+                _ => return Err(anyhow!("Unknown filter {item}")),
+            };
+            set.insert(id);
+        }
+        Ok(filter)
+    }
+
+    /// An empty filter keeps everything.
+    pub fn is_empty(&self) -> bool {
+        self.alliances.is_empty()
+            && self.corporations.is_empty()
+            && self.characters.is_empty()
+            && self.systems.is_empty()
+    }
+
+    pub fn matches(&self, killmail: &Killmail) -> bool {
+        if self.is_empty() || self.systems.contains(&killmail.solar_system_id) {
+            return true;
+        }
+        let victim = &killmail.victim;
+        let participants = std::iter::once((
+            victim.alliance_id,
+            victim.corporation_id,
+            victim.character_id,
+        ))
+        .chain(
+            killmail
+                .attackers
+                .iter()
+                .map(|a| (a.alliance_id, a.corporation_id, a.character_id)),
+        );
+        let has = |set: &HashSet<i32>, id: Option<i32>| id.filter(|id| set.contains(id)).is_some();
+        for (alliance, corporation, character) in participants {
+            if has(&self.alliances, alliance)
+                || has(&self.corporations, corporation)
+                || has(&self.characters, character)
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn channels_are_validated() {
+        assert_eq!(
+            channels("alliance:99, region:10000002").unwrap(),
+            vec!["alliance:99", "region:10000002"]
+        );
+        assert_eq!(channels("killstream").unwrap(), vec!["killstream"]);
+        assert!(channels("ship:587").is_err());
+        assert!(channels("alliance:x").is_err());
+        assert!(channels(" , ").is_err());
+    }
+
+    #[test]
+    fn filter_matches_victim_attackers_and_system() {
+        let mut killmail = testing::killmail(1, "2022-07-01T00:00:00Z");
+        killmail.attackers[0].alliance_id = Some(99);
+        assert!(Filter::parse("").unwrap().matches(&killmail));
+        assert!(Filter::parse("alliance:99").unwrap().matches(&killmail));
+        assert!(Filter::parse("corporation:2").unwrap().matches(&killmail));
+        assert!(Filter::parse("character:3,system:1")
+            .unwrap()
+            .matches(&killmail));
+        assert!(Filter::parse("system:30000142").unwrap().matches(&killmail));
+        assert!(!Filter::parse("alliance:1,corporation:3")
+            .unwrap()
+            .matches(&killmail));
+        assert!(Filter::parse("region:10000002").is_err());
+    }
+}
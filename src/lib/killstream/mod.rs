@@ -0,0 +1,447 @@
+use anyhow::anyhow;
+use log::{error, info, warn};
+use rand::Rng;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, Duration, Instant};
+use websockets::{Frame, WebSocket, WebSocketError, WebSocketWriteHalf};
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use crate::evetech::Killmail;
+
+pub mod filter;
+pub mod record;
+pub mod redisq;
+pub mod source;
+pub use filter::Filter;
+pub use record::{Recorder, Replay};
+pub use redisq::RedisQ;
+pub use source::Source;
+
+pub const ZKB_WEBSOCKET: &str = "wss://zkillboard.com/websocket/";
+/// The whole universe
+pub const ZKB_KILLSTREAM: &str = "killstream";
+
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+pub const DEFAULT_BACKOFF_BASE: Duration = Duration::from_secs(1);
+pub const DEFAULT_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// Exponential backoff with equal jitter: half of the delay is random.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn delay(&mut self) -> Duration {
+        let factor = 2_u32.saturating_pow(self.attempt);
+        let cap = self.base.saturating_mul(factor).min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = cap / 2;
+        half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct StreamStats {
+    pub connects: usize,
+    pub polls: usize,
+    pub reconnects: usize,
+    pub killmails: usize,
+    /// Killmails the local filter kept out
+    pub filtered: usize,
+    pub dropped_frames: usize,
+    pub pings: usize,
+    pub idle_timeouts: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub url: String,
+    pub channels: Vec<String>,
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+}
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            url: String::from(ZKB_WEBSOCKET),
+            channels: vec![String::from(ZKB_KILLSTREAM)],
+            ping_interval: DEFAULT_PING_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            backoff_base: DEFAULT_BACKOFF_BASE,
+            backoff_max: DEFAULT_BACKOFF_MAX,
+        }
+    }
+}
+
+/// Where killmails come from: the websocket, the RedisQ long poll or a recording.
+pub trait KillmailSource {
+    /// Hands every killmail to the handler, at least once, live sources never return.
+    fn run<F, Fut>(&mut self, handle: F) -> impl Future<Output = ()>
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>;
+
+    fn stats(&self) -> Arc<Mutex<StreamStats>>;
+
+    /// Keeps the raw frames the source receives from now on.
+    fn record(&mut self, recorder: Recorder);
+}
+
+/// The zkillboard killstream that reconnects whatever goes wrong.
+pub struct KillStream {
+    config: StreamConfig,
+    backoff: Backoff,
+    stats: Arc<Mutex<StreamStats>>,
+    recorder: Option<Recorder>,
+}
+impl KillStream {
+    pub fn new(config: StreamConfig) -> Self {
+        Self {
+            backoff: Backoff::new(config.backoff_base, config.backoff_max),
+            config,
+            stats: Arc::new(Mutex::new(StreamStats::default())),
+            recorder: None,
+        }
+    }
+
+    fn count(&self, update: impl FnOnce(&mut StreamStats)) {
+        count(&self.stats, update);
+    }
+
+    async fn session<F, Fut>(&mut self, handle: &mut F) -> anyhow::Result<()>
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let ws = WebSocket::connect(&self.config.url).await?;
+        self.count(|stats| stats.connects += 1);
+        let (mut read, mut write) = ws.split();
+        for channel in &self.config.channels {
+            write.send_text(subscription(channel)).await?;
+        }
+        info!(
+            "Web Socket {} subscribed to {:?}",
+            self.config.url, self.config.channels
+        );
+
+        // Frames are read in a task of their own, a read is never cancelled halfway
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            loop {
+                let frame = read.receive().await;
+                let failed = frame.is_err();
+                if tx.send(frame).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut parts = Vec::new();
+        let result = self.receive(&mut rx, &mut write, &mut parts, handle).await;
+        reader.abort();
+        let _ = write.shutdown().await;
+        if !parts.is_empty() {
+            self.count(|stats| stats.dropped_frames += 1);
+        }
+        result
+    }
+
+    async fn receive<F, Fut>(
+        &mut self,
+        rx: &mut mpsc::UnboundedReceiver<Result<Frame, WebSocketError>>,
+        write: &mut WebSocketWriteHalf,
+        parts: &mut Vec<String>,
+        handle: &mut F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut heartbeat = interval(self.config.ping_interval);
+        heartbeat.tick().await;
+        let mut last_frame = Instant::now();
+        loop {
+            tokio::select! {
+                frame = rx.recv() => {
+                    let frame = frame.ok_or_else(|| anyhow!("reader stopped"))??;
+                    last_frame = Instant::now();
+                    // Sends the pongs the read half queued
+                    write.flush().await?;
+                    match frame {
+                        Frame::Text { payload, fin, .. } => {
+                            parts.push(payload);
+                            if fin {
+                                let json = parts.concat();
+                                parts.clear();
+                                if let Some(recorder) = &self.recorder {
+                                    recorder.record(&json);
+                                }
+                                match serde_json::from_str::<Killmail>(&json) {
+                                    Ok(killmail) => {
+                                        self.backoff.reset();
+                                        self.count(|stats| stats.killmails += 1);
+                                        info!("killmail_id: {}", killmail.killmail_id);
+                                        handle(killmail).await;
+                                    }
+                                    Err(what) => {
+                                        self.count(|stats| stats.dropped_frames += 1);
+                                        error!("Dropped frame: {what}");
+                                    }
+                                }
+                            }
+                        }
+                        Frame::Binary { .. } => self.count(|stats| stats.dropped_frames += 1),
+                        Frame::Close { payload } => return Err(anyhow!("closed by server {payload:?}")),
+                        Frame::Ping { .. } | Frame::Pong { .. } => {}
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    if last_frame.elapsed() >= self.config.idle_timeout {
+                        self.count(|stats| stats.idle_timeouts += 1);
+                        return Err(anyhow!("idle for {} seconds", last_frame.elapsed().as_secs()));
+                    }
+                    write.send_ping(None).await?;
+                    self.count(|stats| stats.pings += 1);
+                }
+            }
+        }
+    }
+}
+
+impl KillmailSource for KillStream {
+    async fn run<F, Fut>(&mut self, mut handle: F)
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            if let Err(what) = self.session(&mut handle).await {
+                error!("Web Socket {what}");
+            }
+            self.count(|stats| stats.reconnects += 1);
+            if let Ok(stats) = self.stats.lock() {
+                info!("Web Socket {stats:?}");
+            }
+            let delay = self.backoff.delay();
+            warn!("Will reconnect in {} ms", delay.as_millis());
+            sleep(delay).await;
+        }
+    }
+
+    fn stats(&self) -> Arc<Mutex<StreamStats>> {
+        self.stats.clone()
+    }
+
+    fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+}
+
+/// A recorded frame of either live source.
+pub fn decode(frame: &str) -> anyhow::Result<Killmail> {
+    serde_json::from_str::<Killmail>(frame)
+        .or_else(|what| redisq::unpack(frame).map_err(|_| anyhow!(what)))
+}
+
+pub fn subscription(channel: &str) -> String {
+    serde_json::json!({"action": "sub", "channel": channel}).to_string()
+}
+
+fn count(stats: &Mutex<StreamStats>, update: impl FnOnce(&mut StreamStats)) {
+    if let Ok(mut stats) = stats.lock() {
+        update(&mut stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const KILLMAIL: &str = r#"{"killmail_id":1,"killmail_time":"2022-07-01T00:00:00Z","solar_system_id":30000142,"victim":{"alliance_id":null,"character_id":1,"corporation_id":2,"damage_taken":10,"ship_type_id":587},"attackers":[]}"#;
+
+    /// Stand-in for the zkillboard websocket, one script per connection.
+    async fn accept(listener: &TcpListener) -> BufReader<TcpStream> {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+        let mut key = String::new();
+        loop {
+            let mut line = String::new();
+            socket.read_line(&mut line).await.unwrap();
+            if let Some(value) = line.strip_prefix("Sec-WebSocket-Key:") {
+                key = value.trim().to_string();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let accept = actix_http::ws::hash_key(key.as_bytes());
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            String::from_utf8_lossy(&accept)
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        socket
+    }
+
+    /// Reads a masked client frame, returns the opcode and the payload.
+    async fn read_frame(socket: &mut BufReader<TcpStream>) -> (u8, Vec<u8>) {
+        let opcode = socket.read_u8().await.unwrap() & 0x0f;
+        let len = match socket.read_u8().await.unwrap() & 0x7f {
+            126 => socket.read_u16().await.unwrap() as usize,
+            127 => socket.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut mask = [0; 4];
+        socket.read_exact(&mut mask).await.unwrap();
+        let mut payload = vec![0; len];
+        socket.read_exact(&mut payload).await.unwrap();
+        for (n, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[n % 4];
+        }
+        (opcode, payload)
+    }
+
+    async fn send_text(socket: &mut BufReader<TcpStream>, text: &str) {
+        let mut frame = vec![0x81];
+        if text.len() < 126 {
+            frame.push(text.len() as u8);
+        } else {
+            frame.push(126);
+            frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(text.as_bytes());
+        socket.write_all(&frame).await.unwrap();
+    }
+
+    fn config(port: u16) -> StreamConfig {
+        StreamConfig {
+            url: format!("ws://127.0.0.1:{port}/"),
+            ping_interval: Duration::from_millis(50),
+            idle_timeout: Duration::from_millis(200),
+            backoff_base: Duration::from_millis(10),
+            backoff_max: Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    fn start(config: StreamConfig) -> (mpsc::UnboundedReceiver<i32>, Arc<Mutex<StreamStats>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut stream = KillStream::new(config);
+        let stats = stream.stats();
+        tokio::spawn(async move {
+            stream
+                .run(|killmail| {
+                    let _ = tx.send(killmail.killmail_id);
+                    async {}
+                })
+                .await
+        });
+        (rx, stats)
+    }
+
+    #[test]
+    fn backoff_grows_with_jitter_up_to_max() {
+        let base = Duration::from_millis(100);
+        let mut backoff = Backoff::new(base, Duration::from_secs(1));
+        for attempt in 0..10 {
+            let cap = (base * 2_u32.pow(attempt)).min(Duration::from_secs(1));
+            let delay = backoff.delay();
+            assert!(delay >= cap / 2 && delay <= cap, "{attempt}: {delay:?}");
+        }
+        backoff.reset();
+        assert!(backoff.delay() <= base);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_server_drop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut killmails, stats) = start(config(listener.local_addr().unwrap().port()));
+
+        let mut socket = accept(&listener).await;
+        let (opcode, payload) = read_frame(&mut socket).await;
+        assert_eq!(opcode, 1);
+        assert_eq!(payload, subscription(ZKB_KILLSTREAM).as_bytes());
+        send_text(&mut socket, KILLMAIL).await;
+        send_text(&mut socket, "not a killmail").await;
+        assert_eq!(killmails.recv().await, Some(1));
+        drop(socket);
+
+        let mut socket = accept(&listener).await;
+        read_frame(&mut socket).await;
+        send_text(&mut socket, &KILLMAIL.replace(":1,", ":2,")).await;
+        assert_eq!(killmails.recv().await, Some(2));
+
+        let stats = stats.lock().unwrap().clone();
+        assert_eq!(stats.connects, 2);
+        assert_eq!(stats.reconnects, 1);
+        assert_eq!(stats.killmails, 2);
+        assert_eq!(stats.dropped_frames, 1);
+    }
+
+    #[tokio::test]
+    async fn subscribes_to_every_channel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = StreamConfig {
+            channels: vec![String::from("alliance:99"), String::from("region:10000002")],
+            ..config(listener.local_addr().unwrap().port())
+        };
+        let (_killmails, _stats) = start(config);
+        let mut socket = accept(&listener).await;
+        let (_, payload) = read_frame(&mut socket).await;
+        assert_eq!(payload, br#"{"action":"sub","channel":"alliance:99"}"#);
+        let (_, payload) = read_frame(&mut socket).await;
+        assert_eq!(payload, br#"{"action":"sub","channel":"region:10000002"}"#);
+    }
+
+    #[tokio::test]
+    async fn half_open_connection_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (_killmails, stats) = start(config(listener.local_addr().unwrap().port()));
+
+        // Reads the subscription and the pings but never answers
+        let mut socket = accept(&listener).await;
+        let (_, _) = read_frame(&mut socket).await;
+        let (opcode, _) = read_frame(&mut socket).await;
+        assert_eq!(opcode, 9);
+
+        let _socket = accept(&listener).await;
+        let stats = stats.lock().unwrap().clone();
+        assert_eq!(stats.idle_timeouts, 1);
+        assert!(stats.pings >= 1);
+        drop(socket);
+    }
+
+    #[tokio::test]
+    async fn survives_refused_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let (_killmails, stats) = start(config(port));
+        sleep(Duration::from_millis(100)).await;
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let _socket = accept(&listener).await;
+        assert!(stats.lock().unwrap().reconnects >= 1);
+    }
+}
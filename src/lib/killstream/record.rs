@@ -0,0 +1,316 @@
+use chrono::{DateTime, Duration as Age, NaiveDate, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::time::{sleep_until, Instant};
+
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use super::{count, decode, KillmailSource, StreamStats};
+use crate::evetech::Killmail;
+
+pub const DEFAULT_KEEP_DAYS: u32 = 7;
+
+const PREFIX: &str = "frames-";
+const SUFFIX: &str = ".ndjson";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// One received frame, as it came.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    at: DateTime<Utc>,
+    frame: String,
+}
+
+fn date(name: &str) -> Option<NaiveDate> {
+    let stamp = name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+    NaiveDate::parse_from_str(stamp, DATE_FORMAT).ok()
+}
+
+/// Recordings of the directory, oldest first.
+pub fn list(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Some(date) = date(&name) {
+            files.push((date, dir.join(name)));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Appends the raw frames to a file per day, drops the days older than keep_days.
+/// The files are written by a thread of its own, a slow disk never holds up the stream.
+pub struct Recorder {
+    frames: mpsc::Sender<(DateTime<Utc>, String)>,
+}
+impl Recorder {
+    pub fn open(dir: PathBuf, keep_days: u32) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut writer = Writer::new(dir, keep_days);
+        let (frames, received) = mpsc::channel::<(DateTime<Utc>, String)>();
+        thread::Builder::new()
+            .name(String::from("recorder"))
+            .spawn(move || {
+                for (at, frame) in received {
+                    if let Err(what) = writer.record_at(at, &frame) {
+                        error!("Recorder {what}");
+                    }
+                }
+            })?;
+        Ok(Self { frames })
+    }
+
+    /// Failures are logged, recording never stops the stream.
+    pub fn record(&self, frame: &str) {
+        if self.frames.send((Utc::now(), frame.to_string())).is_err() {
+            error!("Recorder is gone, frame not recorded");
+        }
+    }
+}
+
+struct Writer {
+    dir: PathBuf,
+    keep_days: u32,
+    current: Option<(NaiveDate, File)>,
+}
+impl Writer {
+    fn new(dir: PathBuf, keep_days: u32) -> Self {
+        Self {
+            dir,
+            keep_days,
+            current: None,
+        }
+    }
+
+    fn record_at(&mut self, at: DateTime<Utc>, frame: &str) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(&Record {
+            at,
+            frame: frame.to_string(),
+        })?;
+        line.push('\n');
+        let today = at.date_naive();
+        if self
+            .current
+            .as_ref()
+            .filter(|(date, _)| *date == today)
+            .is_none()
+        {
+            let name = format!("{PREFIX}{}{SUFFIX}", today.format(DATE_FORMAT));
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(name))?;
+            self.current = Some((today, file));
+            self.rotate(today)?;
+        }
+        if let Some((_, file)) = self.current.as_mut() {
+            file.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&self, today: NaiveDate) -> anyhow::Result<()> {
+        let oldest = today - Age::days(self.keep_days as i64);
+        for path in list(&self.dir)? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if date(&name).filter(|date| *date < oldest).is_some() {
+                fs::remove_file(&path)?;
+                info!("Recording {name} removed");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hands out the recorded killmails, `speed` times faster than they came, 0 without pauses.
+pub struct Replay {
+    files: Vec<PathBuf>,
+    speed: f64,
+    stats: Arc<Mutex<StreamStats>>,
+}
+impl Replay {
+    /// A directory replays all its recordings in order.
+    pub fn new(path: &Path, speed: f64) -> anyhow::Result<Self> {
+        let files = if path.is_dir() {
+            list(path)?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        Ok(Self {
+            files,
+            speed,
+            stats: Arc::new(Mutex::new(StreamStats::default())),
+        })
+    }
+
+    fn count(&self, update: impl FnOnce(&mut StreamStats)) {
+        count(&self.stats, update);
+    }
+
+    async fn replay<F, Fut>(
+        &mut self,
+        path: &Path,
+        start: &mut Option<(DateTime<Utc>, Instant)>,
+        handle: &mut F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        info!("Replay {path:?}");
+        let mut lines = BufReader::new(tokio::fs::File::open(path).await?).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.is_empty() {
+                continue;
+            }
+            let record = match serde_json::from_str::<Record>(&line) {
+                Ok(record) => record,
+                Err(what) => {
+                    self.count(|stats| stats.dropped_frames += 1);
+                    error!("Bad record: {what}");
+                    continue;
+                }
+            };
+            let (first, began) = *start.get_or_insert((record.at, Instant::now()));
+            if self.speed > 0.0 {
+                let elapsed = (record.at - first).to_std().unwrap_or_default();
+                sleep_until(began + elapsed.div_f64(self.speed)).await;
+            }
+            match decode(&record.frame) {
+                Ok(killmail) => {
+                    self.count(|stats| stats.killmails += 1);
+                    handle(killmail).await;
+                }
+                Err(what) => {
+                    self.count(|stats| stats.dropped_frames += 1);
+                    error!("Dropped frame: {what}");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KillmailSource for Replay {
+    /// Returns once the recordings are over.
+    async fn run<F, Fut>(&mut self, mut handle: F)
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut start = None;
+        for path in self.files.clone() {
+            if let Err(what) = self.replay(&path, &mut start, &mut handle).await {
+                error!("Replay {path:?}: {what}");
+            }
+        }
+        if let Ok(stats) = self.stats.lock() {
+            info!("Replay {stats:?}");
+        }
+    }
+
+    fn stats(&self) -> Arc<Mutex<StreamStats>> {
+        self.stats.clone()
+    }
+
+    /// Replays are not recorded again.
+    fn record(&mut self, _recorder: Recorder) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::TimeZone;
+    use tokio::time::Duration;
+
+    const KILLMAIL: &str = r#"{"killmail_id":1,"killmail_time":"2022-07-01T00:00:00Z","solar_system_id":30000142,"victim":{"alliance_id":null,"character_id":1,"corporation_id":2,"damage_taken":10,"ship_type_id":587},"attackers":[]}"#;
+
+    fn at(day: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2022, 7, day, 0, 0, second).unwrap()
+    }
+
+    #[test]
+    fn recordings_rotate_by_day() {
+        let dir = testing::temp_dir("record-rotate");
+        let mut recorder = Writer::new(dir.clone(), 1);
+        recorder.record_at(at(1, 0), KILLMAIL).unwrap();
+        recorder.record_at(at(2, 0), KILLMAIL).unwrap();
+        recorder.record_at(at(2, 1), "not a killmail").unwrap();
+        let names = |dir: &Path| -> Vec<String> {
+            list(dir)
+                .unwrap()
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(&dir),
+            vec!["frames-2022-07-01.ndjson", "frames-2022-07-02.ndjson"]
+        );
+        recorder.record_at(at(3, 0), KILLMAIL).unwrap();
+        assert_eq!(
+            names(&dir),
+            vec!["frames-2022-07-02.ndjson", "frames-2022-07-03.ndjson"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_in_order_at_speed() {
+        let dir = testing::temp_dir("record-replay");
+        let mut recorder = Writer::new(dir.clone(), 7);
+        recorder.record_at(at(1, 0), KILLMAIL).unwrap();
+        recorder.record_at(at(1, 1), "not a killmail").unwrap();
+        let second = KILLMAIL.replace(":1,", ":2,");
+        recorder.record_at(at(1, 2), &second).unwrap();
+        let package = format!(
+            r#"{{"killID":3,"killmail":{},"zkb":{{"hash":"h"}}}}"#,
+            KILLMAIL.replace(":1,", ":3,")
+        );
+        recorder.record_at(at(2, 0), &package).unwrap();
+
+        // A day and 2 seconds of recordings in about 100 ms
+        let speed = Age::days(1).num_seconds() as f64 * 10.0;
+        let mut replay = Replay::new(&dir, speed).unwrap();
+        let mut ids = Vec::new();
+        let began = Instant::now();
+        replay
+            .run(|killmail| {
+                ids.push(killmail.killmail_id);
+                async {}
+            })
+            .await;
+        assert!(began.elapsed() >= Duration::from_millis(90));
+        assert_eq!(ids, vec![1, 2, 3]);
+        let stats = replay.stats().lock().unwrap().clone();
+        assert_eq!(stats.killmails, 3);
+        assert_eq!(stats.dropped_frames, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn frames_are_written_by_the_recorder_thread() {
+        let dir = testing::temp_path("record-thread");
+        let recorder = Recorder::open(dir.clone(), 7).unwrap();
+        recorder.record(KILLMAIL);
+        let began = Instant::now();
+        let mut text = String::new();
+        while !text.ends_with('\n') && began.elapsed() < Duration::from_secs(5) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if let Some(path) = list(&dir).unwrap().first() {
+                text = fs::read_to_string(path).unwrap();
+            }
+        }
+        let record = serde_json::from_str::<Record>(text.trim()).unwrap();
+        assert_eq!(record.frame, KILLMAIL);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
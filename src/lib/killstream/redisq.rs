@@ -0,0 +1,230 @@
+use anyhow::anyhow;
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::time::{sleep, Duration};
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use super::{count, Backoff, KillmailSource, Recorder, StreamStats};
+use super::{DEFAULT_BACKOFF_BASE, DEFAULT_BACKOFF_MAX};
+use crate::evetech::{Killmail, Zkb};
+
+pub const ZKB_REDISQ: &str = "https://zkillredisq.stream/listen.php";
+pub const DEFAULT_QUEUE_ID: &str = "zkbinfo";
+/// How long RedisQ holds a poll open when there is nothing to hand out
+pub const DEFAULT_TTW: Duration = Duration::from_secs(10);
+/// Extra time for the answer of a held poll to arrive
+const POLL_SLACK: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone)]
+pub struct RedisQConfig {
+    pub url: String,
+    pub queue_id: String,
+    pub ttw: Duration,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+}
+impl Default for RedisQConfig {
+    fn default() -> Self {
+        Self {
+            url: String::from(ZKB_REDISQ),
+            queue_id: String::from(DEFAULT_QUEUE_ID),
+            ttw: DEFAULT_TTW,
+            backoff_base: DEFAULT_BACKOFF_BASE,
+            backoff_max: DEFAULT_BACKOFF_MAX,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Answer {
+    package: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Package {
+    killmail: Killmail,
+    zkb: Option<Zkb>,
+}
+
+/// Long polls the zkillboard RedisQ, one killmail per answer.
+pub struct RedisQ {
+    config: RedisQConfig,
+    client: reqwest::Client,
+    backoff: Backoff,
+    stats: Arc<Mutex<StreamStats>>,
+    recorder: Option<Recorder>,
+}
+impl RedisQ {
+    pub fn new(config: RedisQConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.ttw + POLL_SLACK)
+            .build()?;
+        Ok(Self {
+            backoff: Backoff::new(config.backoff_base, config.backoff_max),
+            client,
+            config,
+            stats: Arc::new(Mutex::new(StreamStats::default())),
+            recorder: None,
+        })
+    }
+
+    fn count(&self, update: impl FnOnce(&mut StreamStats)) {
+        count(&self.stats, update);
+    }
+
+    /// Some package when there was a killmail in the queue.
+    async fn poll(&self) -> anyhow::Result<Option<serde_json::Value>> {
+        let response = self
+            .client
+            .get(&self.config.url)
+            .query(&[
+                ("queueID", self.config.queue_id.clone()),
+                ("ttw", self.config.ttw.as_secs().to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+        self.count(|stats| stats.polls += 1);
+        let answer = response
+            .json::<Answer>()
+            .await
+            .map_err(|e| anyhow!("bad answer {e}"))?;
+        Ok(answer.package)
+    }
+}
+
+impl KillmailSource for RedisQ {
+    async fn run<F, Fut>(&mut self, mut handle: F)
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        info!("RedisQ {} queue {}", self.config.url, self.config.queue_id);
+        loop {
+            match self.poll().await {
+                Ok(Some(package)) => {
+                    self.backoff.reset();
+                    let package = package.to_string();
+                    if let Some(recorder) = &self.recorder {
+                        recorder.record(&package);
+                    }
+                    match unpack(&package) {
+                        Ok(killmail) => {
+                            self.count(|stats| stats.killmails += 1);
+                            info!("killmail_id: {}", killmail.killmail_id);
+                            handle(killmail).await;
+                        }
+                        Err(what) => {
+                            self.count(|stats| stats.dropped_frames += 1);
+                            error!("Dropped package: {what}");
+                        }
+                    }
+                }
+                Ok(None) => self.backoff.reset(),
+                Err(what) => {
+                    error!("RedisQ {what}");
+                    self.count(|stats| stats.reconnects += 1);
+                    if let Ok(stats) = self.stats.lock() {
+                        info!("RedisQ {stats:?}");
+                    }
+                    let delay = self.backoff.delay();
+                    warn!("Will poll again in {} ms", delay.as_millis());
+                    sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    fn stats(&self) -> Arc<Mutex<StreamStats>> {
+        self.stats.clone()
+    }
+
+    fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+}
+
+/// The killmail of a RedisQ package, with its zkb part.
+pub fn unpack(package: &str) -> anyhow::Result<Killmail> {
+    let Package { mut killmail, zkb } = serde_json::from_str::<Package>(package)?;
+    killmail.zkb = zkb.or(killmail.zkb);
+    Ok(killmail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    const KILLMAIL: &str = r#"{"killmail_id":1,"killmail_time":"2022-07-01T00:00:00Z","solar_system_id":30000142,"victim":{"alliance_id":null,"character_id":1,"corporation_id":2,"damage_taken":10,"ship_type_id":587},"attackers":[]}"#;
+
+    /// Stand-in for RedisQ, answers one poll per connection.
+    async fn answer(listener: &TcpListener, status: &str, body: &str) -> String {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+        let mut request = String::new();
+        socket.read_line(&mut request).await.unwrap();
+        loop {
+            let mut line = String::new();
+            socket.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        request
+    }
+
+    fn package(id: i32) -> String {
+        let killmail = KILLMAIL.replace(":1,", &format!(":{id},"));
+        format!(r#"{{"package":{{"killID":{id},"killmail":{killmail},"zkb":{{"hash":"h{id}"}}}}}}"#)
+    }
+
+    #[tokio::test]
+    async fn polls_through_empty_and_failed_answers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = RedisQConfig {
+            url: format!("http://{}/listen.php", listener.local_addr().unwrap()),
+            queue_id: String::from("test"),
+            ttw: Duration::from_secs(1),
+            backoff_base: Duration::from_millis(10),
+            backoff_max: Duration::from_millis(50),
+        };
+        let mut redisq = RedisQ::new(config).unwrap();
+        let stats = redisq.stats();
+        let (tx, mut killmails) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            redisq
+                .run(|killmail| {
+                    let _ = tx.send(killmail);
+                    async {}
+                })
+                .await
+        });
+
+        let request = answer(&listener, "200 OK", &package(1)).await;
+        assert!(request.starts_with("GET /listen.php?queueID=test&ttw=1 "));
+        answer(&listener, "200 OK", r#"{"package":null}"#).await;
+        answer(&listener, "200 OK", r#"{"package":{"killID":3}}"#).await;
+        answer(&listener, "500 Internal Server Error", "").await;
+        answer(&listener, "200 OK", &package(2)).await;
+
+        let killmail = killmails.recv().await.unwrap();
+        assert_eq!(killmail.killmail_id, 1);
+        assert_eq!(killmail.zkb.unwrap().hash, "h1");
+        assert_eq!(killmails.recv().await.unwrap().killmail_id, 2);
+        let stats = stats.lock().unwrap().clone();
+        assert_eq!(stats.polls, 4);
+        assert_eq!(stats.killmails, 2);
+        assert_eq!(stats.dropped_frames, 1);
+        assert_eq!(stats.reconnects, 1);
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::anyhow;
+use log::info;
+use tokio::time::Duration;
+
+use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use super::record::{self, Recorder};
+use super::redisq::{RedisQ, RedisQConfig};
+use super::{filter, KillStream, KillmailSource, StreamConfig, StreamStats};
+use crate::evetech::Killmail;
+
+/// The live source `ZKBINFO_SOURCE` selects.
+pub enum Source {
+    WebSocket(KillStream),
+    RedisQ(RedisQ),
+}
+impl Source {
+    /// Configured by the `ZKBINFO_` variables, recording when `ZKBINFO_RECORD` is set.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut source = match env::var("ZKBINFO_SOURCE").unwrap_or_default().as_str() {
+            "redisq" => {
+                let mut config = RedisQConfig::default();
+                if let Ok(url) = env::var("ZKBINFO_REDISQ") {
+                    config.url = url;
+                }
+                if let Ok(queue_id) = env::var("ZKBINFO_REDISQ_QUEUE") {
+                    config.queue_id = queue_id;
+                }
+                info!("RedisQ {config:?}");
+                Source::RedisQ(RedisQ::new(config)?)
+            }
+            "" | "websocket" => {
+                let mut config = StreamConfig::default();
+                if let Ok(url) = env::var("ZKBINFO_WEBSOCKET") {
+                    config.url = url;
+                }
+                if let Ok(secs) = env::var("ZKBINFO_WS_PING")
+                    .unwrap_or_default()
+                    .parse::<u64>()
+                {
+                    config.ping_interval = Duration::from_secs(secs);
+                }
+                if let Ok(secs) = env::var("ZKBINFO_WS_IDLE")
+                    .unwrap_or_default()
+                    .parse::<u64>()
+                {
+                    config.idle_timeout = Duration::from_secs(secs);
+                }
+                if let Ok(spec) = env::var("ZKBINFO_CHANNELS") {
+                    config.channels = filter::channels(&spec)?;
+                }
+                info!("Web Socket {config:?}");
+                Source::WebSocket(KillStream::new(config))
+            }
+            other => return Err(anyhow!("Unknown ZKBINFO_SOURCE {other}")),
+        };
+        if let Ok(dir) = env::var("ZKBINFO_RECORD") {
+            let keep_days = env::var("ZKBINFO_RECORD_KEEP_DAYS")
+                .unwrap_or_default()
+                .parse::<u32>()
+                .unwrap_or(record::DEFAULT_KEEP_DAYS);
+            info!("Recording frames to {dir}, kept for {keep_days} days");
+            source.record(Recorder::open(dir.into(), keep_days)?);
+        }
+        Ok(source)
+    }
+}
+
+impl KillmailSource for Source {
+    async fn run<F, Fut>(&mut self, handle: F)
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        match self {
+            Source::WebSocket(stream) => stream.run(handle).await,
+            Source::RedisQ(redisq) => redisq.run(handle).await,
+        }
+    }
+
+    fn stats(&self) -> Arc<Mutex<StreamStats>> {
+        match self {
+            Source::WebSocket(stream) => stream.stats(),
+            Source::RedisQ(redisq) => redisq.stats(),
+        }
+    }
+
+    fn record(&mut self, recorder: Recorder) {
+        match self {
+            Source::WebSocket(stream) => stream.record(recorder),
+            Source::RedisQ(redisq) => redisq.record(recorder),
+        }
+    }
+}
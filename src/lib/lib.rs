@@ -1,8 +1,17 @@
-
 pub mod api;
+pub mod archive;
+pub mod backfill;
+pub mod backup;
+pub mod cache;
+pub mod ctl;
 pub mod evetech;
 pub mod database;
+pub mod export;
 pub mod gui;
-
-
-
+pub mod import;
+pub mod integrity;
+pub mod killstream;
+pub mod replication;
+pub mod spool;
+#[cfg(test)]
+mod testing;
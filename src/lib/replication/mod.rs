@@ -0,0 +1,71 @@
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use log::info;
+
+use std::collections::HashSet;
+
+use crate::evetech;
+
+/// Most killmails a single export request returns.
+pub const EXPORT_LIMIT: usize = 500;
+
+/// Ids of the killmails the peer saved for the date.
+pub async fn peer_ids(
+    client: &reqwest::Client,
+    peer: &str,
+    date: &NaiveDate,
+) -> anyhow::Result<Vec<i32>> {
+    let url = format!("{peer}/api/killmail/ids/{}/", date.format("%Y-%m-%d"));
+    info!("{url}");
+    client
+        .get(&url)
+        .send()
+        .await?
+        .json::<Vec<i32>>()
+        .await
+        .map_err(|e| anyhow!(e))
+}
+
+/// Full killmails from the peer, in chunks of EXPORT_LIMIT.
+pub async fn export(
+    client: &reqwest::Client,
+    peer: &str,
+    ids: &[i32],
+) -> anyhow::Result<Vec<evetech::Killmail>> {
+    let url = format!("{peer}/api/killmail/export/");
+    let mut killmails = Vec::with_capacity(ids.len());
+    for chunk in ids.chunks(EXPORT_LIMIT) {
+        let mut received = client
+            .post(&url)
+            .json(chunk)
+            .send()
+            .await?
+            .json::<Vec<evetech::Killmail>>()
+            .await?;
+        killmails.append(&mut received);
+    }
+    Ok(killmails)
+}
+
+/// Ids the peer has and the local instance has not, in ascending order.
+pub fn missing(peer: Vec<i32>, saved: &[i32]) -> Vec<i32> {
+    let saved = saved.iter().collect::<HashSet<_>>();
+    let mut missing = peer
+        .into_iter()
+        .filter(|id| !saved.contains(id))
+        .collect::<Vec<i32>>();
+    missing.sort_unstable();
+    missing.dedup();
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_ids() {
+        assert_eq!(missing(vec![5, 1, 3, 3, 2], &[2, 4]), vec![1, 3, 5]);
+        assert!(missing(vec![1, 2], &[1, 2]).is_empty());
+    }
+}
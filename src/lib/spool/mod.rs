@@ -0,0 +1,300 @@
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::evetech::Killmail;
+
+pub const DEFAULT_SPOOL_PATH: &str = "spool.ndjson";
+/// A replay spools apart from the live client, the two may run at once
+pub const DEFAULT_REPLAY_SPOOL_PATH: &str = "replay-spool.ndjson";
+pub const DEFAULT_SPOOL_MAX_MB: u64 = 100;
+pub const REPLAY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SpoolStats {
+    pub pending: usize,
+    pub bytes: u64,
+    pub spooled: usize,
+    pub replayed: usize,
+    pub overflowed: usize,
+    /// Moved to the dead-letter file
+    pub rejected: usize,
+}
+
+/// zkbinfo refused the killmail itself, delivering it again does not help.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rejected: {}", self.0)
+    }
+}
+impl std::error::Error for Rejected {}
+
+/// Append-only NDJSON queue of the killmails zkbinfo did not accept yet.
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+    // Guards the file, appends wait while the delivered head is cut off
+    stats: Mutex<SpoolStats>,
+}
+impl Spool {
+    /// Killmails left by the previous run are pending again.
+    pub fn open(path: PathBuf, max_bytes: u64) -> anyhow::Result<Self> {
+        let mut stats = SpoolStats::default();
+        if path.exists() {
+            stats.pending = read_lines(&path)?.len();
+            stats.bytes = fs::metadata(&path)?.len();
+        }
+        Ok(Self {
+            path,
+            max_bytes,
+            stats: Mutex::new(stats),
+        })
+    }
+
+    pub async fn stats(&self) -> SpoolStats {
+        self.stats.lock().await.clone()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.stats.lock().await.pending == 0
+    }
+
+    /// Returns false when the spool is full and the killmail is dropped.
+    pub async fn push(&self, killmail: &Killmail) -> anyhow::Result<bool> {
+        let mut line = serde_json::to_string(killmail)?;
+        line.push('\n');
+        let mut stats = self.stats.lock().await;
+        if stats.bytes + line.len() as u64 > self.max_bytes {
+            stats.overflowed += 1;
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        stats.pending += 1;
+        stats.bytes += line.len() as u64;
+        stats.spooled += 1;
+        Ok(true)
+    }
+
+    /// Keeps a rejected killmail in the dead-letter file next to the spool.
+    pub async fn reject(&self, killmail: &Killmail) -> anyhow::Result<()> {
+        let line = serde_json::to_string(killmail)?;
+        let mut stats = self.stats.lock().await;
+        self.dead_letter(&line)?;
+        stats.rejected += 1;
+        Ok(())
+    }
+
+    fn dead_letter(&self, line: &str) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dead_letter_path())?;
+        writeln!(file, "{line}")?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn dead_letter_path(&self) -> PathBuf {
+        self.path.with_extension("rejected.ndjson")
+    }
+
+    /// Delivers the pending killmails in order until the first failure,
+    /// the rejected ones are moved to the dead-letter file.
+    pub async fn replay<F, Fut>(&self, mut deliver: F) -> anyhow::Result<usize>
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let lines = {
+            let _stats = self.stats.lock().await;
+            read_lines(&self.path)?
+        };
+        let (mut delivered, mut rejected) = (0, 0);
+        for line in &lines {
+            match serde_json::from_str::<Killmail>(line) {
+                Ok(killmail) => match deliver(killmail).await {
+                    Ok(()) => {}
+                    Err(what) if what.is::<Rejected>() => {
+                        warn!("Spooled killmail {what}, moved to the dead letters");
+                        self.dead_letter(line)?;
+                        rejected += 1;
+                    }
+                    Err(what) => {
+                        warn!("Spool replay stopped: {what}");
+                        break;
+                    }
+                },
+                Err(what) => error!("Spooled line skipped: {what}"),
+            }
+            delivered += 1;
+        }
+        if delivered > 0 {
+            self.cut(delivered, rejected).await?;
+        }
+        Ok(delivered - rejected)
+    }
+
+    /// Drops the delivered head, the lines appended meanwhile stay.
+    async fn cut(&self, delivered: usize, rejected: usize) -> anyhow::Result<()> {
+        let mut stats = self.stats.lock().await;
+        let rest = read_lines(&self.path)?.split_off(delivered);
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for line in &rest {
+            writeln!(file, "{line}")?;
+        }
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+        stats.pending = rest.len();
+        stats.bytes = fs::metadata(&self.path)?.len();
+        stats.replayed += delivered - rejected;
+        stats.rejected += rejected;
+        Ok(())
+    }
+
+    /// Replays whenever there is something pending, never returns.
+    pub async fn drain<F, Fut>(self: Arc<Self>, mut deliver: F)
+    where
+        F: FnMut(Killmail) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        loop {
+            if !self.is_empty().await {
+                match self.replay(&mut deliver).await {
+                    Ok(delivered) if delivered > 0 => {
+                        info!("Spool {:?}", self.stats().await)
+                    }
+                    Ok(_) => {}
+                    Err(what) => error!("Spool {what}"),
+                }
+            }
+            sleep(REPLAY_INTERVAL).await;
+        }
+    }
+}
+
+fn read_lines(path: &Path) -> anyhow::Result<Vec<String>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut lines = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn killmail(id: i32) -> Killmail {
+        testing::killmail(id, "2022-07-01T00:00:00Z")
+    }
+
+    #[tokio::test]
+    async fn replays_in_order_up_to_first_failure() {
+        let path = testing::temp_path("spool-order.ndjson");
+        let spool = Spool::open(path.clone(), 1 << 20).unwrap();
+        for id in 1..=4 {
+            assert!(spool.push(&killmail(id)).await.unwrap());
+        }
+        let mut seen = Vec::new();
+        let delivered = spool
+            .replay(|killmail| {
+                let id = killmail.killmail_id;
+                seen.push(id);
+                async move {
+                    match id {
+                        3 => Err(anyhow::anyhow!("zkbinfo is down")),
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(delivered, 2);
+        assert_eq!(seen, vec![1, 2, 3]);
+
+        // Survives a restart
+        let spool = Spool::open(path.clone(), 1 << 20).unwrap();
+        assert_eq!(spool.stats().await.pending, 2);
+        let mut seen = Vec::new();
+        spool
+            .replay(|killmail| {
+                seen.push(killmail.killmail_id);
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(seen, vec![3, 4]);
+        assert!(spool.is_empty().await);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn size_cap_drops_new_killmails() {
+        let path = testing::temp_path("spool-cap.ndjson");
+        let line = serde_json::to_string(&killmail(1)).unwrap().len() as u64 + 1;
+        let spool = Spool::open(path.clone(), line * 2).unwrap();
+        assert!(spool.push(&killmail(1)).await.unwrap());
+        assert!(spool.push(&killmail(2)).await.unwrap());
+        assert!(!spool.push(&killmail(3)).await.unwrap());
+        let stats = spool.stats().await;
+        assert_eq!(stats.pending, 2);
+        assert_eq!(stats.bytes, line * 2);
+        assert_eq!(stats.overflowed, 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_head_is_moved_aside() {
+        let path = testing::temp_path("spool-rejected.ndjson");
+        let spool = Spool::open(path.clone(), 1 << 20).unwrap();
+        let _ = fs::remove_file(spool.dead_letter_path());
+        for id in 1..=3 {
+            assert!(spool.push(&killmail(id)).await.unwrap());
+        }
+        let mut seen = Vec::new();
+        let delivered = spool
+            .replay(|killmail| {
+                let id = killmail.killmail_id;
+                seen.push(id);
+                async move {
+                    match id {
+                        1 => Err(Rejected(String::from("400 Bad Request")).into()),
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(delivered, 2);
+        assert_eq!(seen, vec![1, 2, 3]);
+        let stats = spool.stats().await;
+        assert_eq!((stats.pending, stats.replayed, stats.rejected), (0, 2, 1));
+        let dead = read_lines(&spool.dead_letter_path()).unwrap();
+        assert_eq!(dead, vec![serde_json::to_string(&killmail(1)).unwrap()]);
+        fs::remove_file(spool.dead_letter_path()).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+//! Fixtures shared by the tests of the crate.
+use crate::database;
+use crate::evetech::{Attackers, Killmail, Victim, Zkb};
+
+use rusqlite::Connection;
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Character 3 of corporation 4 kills character 1 of corporation 2 in Jita.
+pub fn killmail(id: i32, time: &str) -> Killmail {
+    Killmail {
+        killmail_id: id,
+        killmail_time: String::from(time),
+        solar_system_id: 30000142,
+        victim: Victim {
+            alliance_id: None,
+            character_id: Some(1),
+            corporation_id: Some(2),
+            damage_taken: 10,
+            ship_type_id: Some(587),
+        },
+        attackers: vec![Attackers {
+            alliance_id: None,
+            character_id: Some(3),
+            corporation_id: Some(4),
+            damage_done: 10,
+            ship_type_id: None,
+            weapon_type_id: None,
+        }],
+        zkb: None,
+    }
+}
+
+/// The killmail as zkillboard shares it, with its hash.
+pub fn hashed(killmail: Killmail, hash: &str) -> Killmail {
+    Killmail {
+        zkb: Some(Zkb {
+            hash: hash.to_string(),
+        }),
+        ..killmail
+    }
+}
+
+/// An empty database in memory.
+pub fn database() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    database::create_tables(&conn, "main").unwrap();
+    conn
+}
+
+/// A database in a temp file, as the server opens it.
+pub fn pool(name: &str) -> database::SqlitePool {
+    database::create_pool(temp_path(name).to_str().unwrap()).unwrap()
+}
+
+/// A path in the temp dir of this process, whatever was there is removed.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("zkbinfo-{}-{name}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&path);
+    path
+}
+
+/// An empty dir in the temp dir of this process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = temp_path(name);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -1,16 +1,97 @@
-use env_logger;
-use log::{error, info};
+use log::{error, info, warn};
+use reqwest::StatusCode;
 use tokio::time::{sleep, Duration};
-use websockets::{Frame, WebSocket, WebSocketError};
 
 use std::env;
+use std::path::Path;
+use std::sync::Arc;
 
+use lib::api::keys;
 use lib::evetech::Killmail;
+use lib::killstream::{Filter, KillmailSource, Replay, Source};
+use lib::spool::{self, Rejected, Spool};
+
+/// Refused killmails are `Rejected`, the rest of the failures are worth a retry.
+async fn deliver(client: reqwest::Client, api: String, killmail: Killmail) -> anyhow::Result<()> {
+    let response = client.post(&api).json(&killmail).send().await?;
+    let status = response.status();
+    let busy = [StatusCode::REQUEST_TIMEOUT, StatusCode::TOO_MANY_REQUESTS];
+    if status.is_client_error() && !busy.contains(&status) {
+        return Err(Rejected(status.to_string()).into());
+    }
+    response.error_for_status()?;
+    Ok(())
+}
+
+/// Saves what the source hands out, spooling what zkbinfo does not accept.
+async fn ingest<S: KillmailSource>(
+    mut source: S,
+    filter: Filter,
+    client: reqwest::Client,
+    api: String,
+    spool: Arc<Spool>,
+) {
+    let stats = source.stats();
+    source
+        .run(|killmail| {
+            let keep = filter.matches(&killmail);
+            if !keep {
+                if let Ok(mut stats) = stats.lock() {
+                    stats.filtered += 1;
+                }
+            }
+            let client = client.clone();
+            let api = api.clone();
+            let spool = spool.clone();
+            async move {
+                if !keep {
+                    return;
+                }
+                // Behind the spooled ones, so zkbinfo gets them in order
+                if spool.is_empty().await {
+                    if deliver(client.clone(), api.clone(), killmail.clone())
+                        .await
+                        .is_ok()
+                    {
+                        return;
+                    }
+                    sleep(Duration::from_secs(1)).await;
+                    match deliver(client, api, killmail.clone()).await {
+                        Ok(()) => return,
+                        Err(what) if what.is::<Rejected>() => {
+                            warn!("killmail {} {what}", killmail.killmail_id);
+                            if let Err(what) = spool.reject(&killmail).await {
+                                error!("killmail {} is lost: {what}", killmail.killmail_id);
+                            }
+                            return;
+                        }
+                        Err(what) => warn!("killmail {} spooled: {what}", killmail.killmail_id),
+                    }
+                }
+                match spool.push(&killmail).await {
+                    Ok(true) => {}
+                    Ok(false) => error!("Spool is full, killmail {} is lost", killmail.killmail_id),
+                    Err(what) => error!("killmail {} is lost: {what}", killmail.killmail_id),
+                }
+            }
+        })
+        .await;
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    let args = env::args().collect::<Vec<String>>();
+    match args.get(1).map(String::as_str) {
+        None => {}
+        Some("replay") if args.len() == 3 => {}
+        _ => {
+            println!("Usage:\n\t{} [replay <recording or directory>]", args[0]);
+            return Ok(());
+        }
+    }
+
     let host = env::var("ZKBINFO_HOST").unwrap_or(String::from("localhost"));
     let port = env::var("ZKBINFO_PORT")
         .unwrap_or_default()
@@ -19,55 +100,55 @@ async fn main() -> anyhow::Result<()> {
     let api = format!("http://{host}:{port}/killmail/save");
     info!("zkbinfo API url: {api}");
 
-    let wss = "wss://zkillboard.com/websocket/";
-    let enable = r#"{"action":"sub","channel":"killstream"}"#;
+    let filter = Filter::parse(&env::var("ZKBINFO_FILTER").unwrap_or_default())?;
+    info!("Filter {filter:?}");
+
+    let live_spool = env::var("ZKBINFO_SPOOL").unwrap_or(String::from(spool::DEFAULT_SPOOL_PATH));
+    let spool_path = if args.get(2).is_some() {
+        let path = env::var("ZKBINFO_REPLAY_SPOOL")
+            .unwrap_or(String::from(spool::DEFAULT_REPLAY_SPOOL_PATH));
+        // Both would drain the one file, each taking the killmails of the other
+        if Path::new(&path) == Path::new(&live_spool) {
+            return Err(anyhow::anyhow!(
+                "ZKBINFO_REPLAY_SPOOL must differ from the live spool {live_spool}"
+            ));
+        }
+        path
+    } else {
+        live_spool
+    };
+    let spool_mb = env::var("ZKBINFO_SPOOL_MAX_MB")
+        .unwrap_or_default()
+        .parse::<u64>()
+        .unwrap_or(spool::DEFAULT_SPOOL_MAX_MB);
+    let spool = Arc::new(Spool::open(spool_path.into(), spool_mb << 20)?);
+    info!("Spool {:?}", spool.stats().await);
 
-    let client = reqwest::Client::new();
+    let client = keys::http_client()?;
     info!("Reqwest client created");
-    let mut ws = WebSocket::connect(wss).await?;
-    info!("Web Socket {:?} created", ws);
-    ws.send_text(enable.to_string()).await?;
-    info!("Web Socket request sent");
 
-    let mut parts = Vec::new();
-    loop {
-        let maybe_response = ws.receive().await;
-        match maybe_response {
-            Ok(response) => {
-                if let Frame::Text {
-                    payload,
-                    continuation,
-                    fin,
-                } = response
-                {
-                    parts.push(payload);
-                    if !continuation || fin {
-                        let json = parts.join("");
-                        parts.clear();
-                        match serde_json::from_str::<Killmail>(&json) {
-                            Ok(killmail) => {
-                                info!("killmail_id: {}", killmail.killmail_id);
-                                let res = client.post(&api).json(&killmail).send().await;
-                                if res.is_err() {
-                                    sleep(Duration::from_secs(10)).await;
-                                    client.post(&api).json(&killmail).send().await?;
-                                }
-                            }
-                            Err(what) => {
-                                error!(": {what}");
-                            }
-                        }
-                    }
-                }
-            }
-            Err(what) => {
-                error!("Web Socket {what}");
-                if let WebSocketError::ReadError(_) = what {
-                    ws.close(None).await?;
-                    ws = WebSocket::connect(wss).await?;
-                    ws.send_text(enable.to_string()).await?;
-                }
-            }
+    let (replay_client, replay_api) = (client.clone(), api.clone());
+    tokio::spawn(
+        spool
+            .clone()
+            .drain(move |killmail| deliver(replay_client.clone(), replay_api.clone(), killmail)),
+    );
+
+    if let Some(path) = args.get(2) {
+        let speed = env::var("ZKBINFO_REPLAY_SPEED")
+            .unwrap_or_default()
+            .parse::<f64>()
+            .unwrap_or(1.0);
+        let replay = Replay::new(Path::new(path), speed)?;
+        ingest(replay, filter, client, api, spool.clone()).await;
+        while !spool.is_empty().await {
+            sleep(spool::REPLAY_INTERVAL).await;
         }
+        return Ok(());
     }
+
+    // Spooled and replayed killmails are late, only these tell zkbinfo the stream is alive
+    let live_api = format!("{api}?live=true");
+    ingest(Source::from_env()?, filter, client, live_api, spool).await;
+    Ok(())
 }
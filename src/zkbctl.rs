@@ -0,0 +1,30 @@
+use std::env;
+use std::io;
+
+use lib::ctl::{self, Options};
+
+#[actix_web::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
+
+    let args = env::args().collect::<Vec<String>>();
+    let options = match Options::parse(&args[1..]) {
+        Ok(options) => options,
+        Err(what) => {
+            eprintln!("{what}");
+            println!("Usage:\n\t{} {}", args[0], ctl::USAGE);
+            return Ok(());
+        }
+    };
+    if let Some(output) = ctl::run(&options, io::stdout()).await? {
+        if options.json {
+            println!("{}", serde_json::to_string_pretty(&output.json)?);
+        } else {
+            print!("{}", output.table);
+            for note in output.notes {
+                println!("{note}");
+            }
+        }
+    }
+    Ok(())
+}
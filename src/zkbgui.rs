@@ -7,6 +7,7 @@ use handlebars::Handlebars;
 use log::{error, info};
 use serde::{Deserialize, Serialize};
 
+use lib::evetech;
 use lib::evetech::SearchCategory;
 use lib::gui::AllianceProps;
 use lib::gui::CharacterProps;
@@ -45,6 +46,7 @@ async fn main() -> anyhow::Result<()> {
             .service(report)
             .service(report_by_id)
             .service(lost_ships)
+            .service(statistic)
     })
     .workers(6)
     .bind((host.as_str(), port))?
@@ -58,6 +60,11 @@ async fn favicon() -> impl Responder {
     NamedFile::open_async("./public/favicon.ico").await
 }
 
+#[get("/gui/statistic/")]
+async fn statistic() -> HttpResponse {
+    HttpResponse::Ok().json(evetech::esi().cache_stats())
+}
+
 #[get("/gui/who/")]
 async fn who(ctx: Context<'_>) -> HttpResponse {
     let body = wrapper(ctx, "who", &{});
@@ -90,7 +97,7 @@ async fn report(ctx: Context<'_>, path: web::Path<(String, String)>) -> HttpResp
             Ok(prop) => wrapper(ctx, "alliance", &prop),
             Err(err) => wrapper(ctx, "error", &Error::from(format!("{err}"))),
         },
-        _ => wrapper(ctx, "error", &Error::from(format!("Unknown Target"))),
+        _ => wrapper(ctx, "error", &Error::from("Unknown Target".to_string())),
     };
     HttpResponse::Ok().body(body)
 }
@@ -111,7 +118,7 @@ async fn report_by_id(ctx: Context<'_>, path: web::Path<(String, i32)>) -> HttpR
             Ok(prop) => wrapper(ctx, "alliance", &prop),
             Err(err) => wrapper(ctx, "error", &Error::from(format!("{err}"))),
         },
-        _ => wrapper(ctx, "error", &Error::from(format!("Unknown Target"))),
+        _ => wrapper(ctx, "error", &Error::from("Unknown Target".to_string())),
     };
     HttpResponse::Ok().body(body)
 }
@@ -132,7 +139,7 @@ async fn lost_ships(ctx: Context<'_>, path: web::Path<(String, i32, i32)>) -> Ht
             Ok(prop) => wrapper(ctx, "losts", &prop),
             Err(err) => wrapper(ctx, "error", &Error::from(format!("{err}"))),
         },
-        _ => wrapper(ctx, "error", &Error::from(format!("Unknown Target"))),
+        _ => wrapper(ctx, "error", &Error::from("Unknown Target".to_string())),
     };
 
     HttpResponse::Ok().body(body)
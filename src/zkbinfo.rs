@@ -1,16 +1,22 @@
 use actix_cors::Cors;
-use actix_rt;
 use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpServer};
 use anyhow::anyhow;
-use env_logger;
-use log::{error, info};
+use log::info;
 use tokio::time::Duration;
 
 use std::env;
 
 use lib::api;
+use lib::api::admin;
+use lib::api::keys;
+use lib::api::limiter;
+use lib::archive;
+use lib::backfill;
+use lib::backup;
+use lib::cache;
 use lib::database;
+use lib::killstream::{Filter, Source};
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
@@ -23,26 +29,134 @@ async fn main() -> anyhow::Result<()> {
         .unwrap_or(8080);
 
     let url = "killmail.db";
+    let backup_policy = backup::BackupPolicy {
+        dir: env::var("ZKBINFO_BACKUP_DIR")
+            .unwrap_or(String::from(backup::DEFAULT_BACKUP_DIR))
+            .into(),
+        keep_days: env::var("ZKBINFO_BACKUP_KEEP_DAYS")
+            .unwrap_or_default()
+            .parse::<u32>()
+            .unwrap_or(backup::DEFAULT_KEEP_DAYS),
+        publish: env::var("ZKBINFO_BACKUP_PUBLISH")
+            .map(|value| value != "0" && value != "false")
+            .unwrap_or(false),
+    };
+    let args = env::args().collect::<Vec<String>>();
+    match args.get(1).map(String::as_str) {
+        None => {}
+        Some("backup") => {
+            let conn = rusqlite::Connection::open(url)?;
+            info!("{}", backup::run(&conn, &backup_policy)?);
+            return Ok(());
+        }
+        Some(_) => {
+            println!("Usage:\n\t{} [backup]", args[0]);
+            return Ok(());
+        }
+    }
+
     info!("The Database path: {url}");
-    let pool = database::create_pool(&url)?;
+    let pool = database::create_pool(url)?;
     info!("Connection to the {url} complete.");
-    let cleanup = pool.clone();
-    let state = api::AppState::new(pool);
+    let cache_size = env::var("ZKBINFO_CACHE_SIZE")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap_or(cache::DEFAULT_CAPACITY);
+    let cache_ttl = env::var("ZKBINFO_CACHE_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(cache::DEFAULT_TTL);
+    info!(
+        "Report cache: {cache_size} entries, {} seconds TTL",
+        cache_ttl.as_secs()
+    );
+    let cache = cache::ReportCache::new(cache_size, cache_ttl);
+
+    let lookup_rate = env::var("ZKBINFO_RATE_LOOKUP")
+        .unwrap_or_default()
+        .parse::<u32>()
+        .unwrap_or(limiter::DEFAULT_LOOKUP_RATE);
+    let analytics_rate = env::var("ZKBINFO_RATE_ANALYTICS")
+        .unwrap_or_default()
+        .parse::<u32>()
+        .unwrap_or(limiter::DEFAULT_ANALYTICS_RATE);
+    info!("Rate limits per minute: lookup {lookup_rate}, analytics {analytics_rate}");
+    let limiter = limiter::RateLimiter::new(
+        limiter::Rate::per_minute(lookup_rate),
+        limiter::Rate::per_minute(analytics_rate),
+    );
+
+    let anonymous_write = env::var("ZKBINFO_ANONYMOUS_WRITE")
+        .map(|value| value != "0" && value != "false")
+        .unwrap_or(true);
+    if let Ok(key) = env::var("ZKBINFO_ADMIN_KEY") {
+        let admin = database::ApiKey {
+            key,
+            name: String::from("admin"),
+            quota: 0,
+            scopes: vec![database::Scope::Admin],
+        };
+        let conn = pool.get()?;
+        database::replace_api_key(&conn, &admin)?;
+    }
+    let mut keys = keys::KeyRing::new(anonymous_write);
+    let count = keys.load(&pool)?;
+    info!("Loaded {count} API keys, anonymous write is allowed: {anonymous_write}");
+
+    let cleanup_hours = env::var("ZKBINFO_CLEANUP_HOURS")
+        .unwrap_or_default()
+        .parse::<u64>()
+        .unwrap_or(48);
+    info!("Cleanup interval: {cleanup_hours} hours");
+    let backup_hours = env::var("ZKBINFO_BACKUP_HOURS")
+        .unwrap_or_default()
+        .parse::<u64>()
+        .unwrap_or(0);
+    info!("Backup interval: {backup_hours} hours, policy: {backup_policy:?}");
+
+    let retention = archive::RetentionPolicy::from_env()?;
+    info!("Retention policy: {retention:?}");
+
+    let gap_minutes = env::var("ZKBINFO_GAP_MINUTES")
+        .unwrap_or_default()
+        .parse::<i64>()
+        .unwrap_or(backfill::DEFAULT_GAP_MINUTES);
+    let last_arrival = {
+        let conn = pool.get()?;
+        database::last_killmail_time(&conn)?
+    };
+    info!("Stream outage threshold: {gap_minutes} minutes, last killmail at {last_arrival:?}");
+    let gaps = backfill::GapMonitor::new(
+        Some(chrono::Duration::minutes(gap_minutes)).filter(|_| gap_minutes > 0),
+        last_arrival,
+    );
+
+    let filter = Filter::parse(&env::var("ZKBINFO_FILTER").unwrap_or_default())?;
+    info!("Filter {filter:?}");
+
+    let state = api::AppState::new(pool, cache, limiter, keys);
+    if let Ok(mut jobs) = state.jobs.lock() {
+        jobs.set_interval(admin::JobKind::Cleanup, admin::hours(cleanup_hours));
+        jobs.set_interval(admin::JobKind::Backup, admin::hours(backup_hours));
+        jobs.set_retention(retention);
+        jobs.set_backup(backup_policy);
+        jobs.set_peer(env::var("ZKBINFO_PEER").ok());
+        jobs.set_gap_monitor(gaps);
+        jobs.set_filter(filter.clone());
+    }
     let context = web::Data::new(state);
 
-    actix_rt::spawn(async move {
-        let mut interval = actix_rt::time::interval(Duration::from_secs(60 * 60 * 48));
-        loop {
-            interval.tick().await;
-            if let Ok(conn) = cleanup.get() {
-                if let Err(what) = database::cleanup(&conn) {
-                    error!("{what}");
-                } else {
-                    info!("Cleanup performed");
-                }
-            }
-        }
-    });
+    actix_rt::spawn(admin::scheduler(context.clone()));
+
+    let listen = env::var("ZKBINFO_LISTEN")
+        .map(|value| value != "0" && value != "false")
+        .unwrap_or(false);
+    if listen {
+        info!("Listening to the killstream in process");
+        let source = Source::from_env()?;
+        actix_rt::spawn(api::listener::listen(context.clone(), source, filter));
+    }
 
     info!("Launching server at {host}:{port}");
     HttpServer::new(move || {
@@ -53,8 +167,19 @@ async fn main() -> anyhow::Result<()> {
             .app_data(context.clone())
             .service(
                 web::scope("/api")
+                    .wrap(limiter::RateLimit)
                     .route("/statistic", web::get().to(api::statistic))
                     .route("/killmail/ids/{date}/", web::get().to(api::saved_ids))
+                    .route("/killmail/hashes/{date}/", web::get().to(api::saved_hashes))
+                    .route("/killmail/export/", web::post().to(api::export))
+                    .route("/export", web::get().to(api::stream::export))
+                    .route("/archive/", web::get().to(api::archive::months))
+                    .route(
+                        "/archive/{month}/{subject}/activity/{id}/",
+                        web::get().to(api::archive::activity),
+                    )
+                    .route("/snapshots/", web::get().to(api::snapshot::list))
+                    .route("/snapshots/{name}", web::get().to(api::snapshot::download))
                     .route(
                         "/character/{id}/lost/{ship}/",
                         web::get().to(api::character::lost_ship),
@@ -164,7 +289,22 @@ async fn main() -> anyhow::Result<()> {
                         web::get().to(api::alliance::enemies_alli),
                     ),
             )
-            .service(web::scope("/killmail").route("/save", web::post().to(api::save)))
+            .service(
+                web::scope("/admin")
+                    .wrap(limiter::RateLimit)
+                    .route("/keys/", web::get().to(keys::list))
+                    .route("/keys/", web::post().to(keys::create))
+                    .route("/keys/{name}/", web::delete().to(keys::revoke))
+                    .route("/jobs/", web::get().to(admin::jobs))
+                    .route("/jobs/{kind}/{date}/", web::post().to(admin::run_dated))
+                    .route("/jobs/{kind}/", web::post().to(admin::run))
+                    .route("/schedule/", web::post().to(admin::schedule)),
+            )
+            .service(
+                web::scope("/killmail")
+                    .wrap(limiter::RateLimit)
+                    .route("/save", web::post().to(api::save)),
+            )
             .wrap(Logger::default())
     })
     .workers(6)